@@ -0,0 +1,81 @@
+use lina::lexer::DefaultLexer;
+use lina::parser::Parser;
+use lina::vm::Value;
+use lina::{compiler, regcompiler, regvm, vm};
+use std::time::{Duration, Instant};
+
+//栈虚拟机和寄存器虚拟机跑同样的程序，比较耗时。cargo bench --bench vm
+
+const SOURCE: &str = "
+fn int sum(int n) {
+    var int i, s;
+    while i < n {
+        s = s + i * i % 7;
+        i = i + 1;
+    }
+    return s;
+}
+fn int fib(int n) {
+    if n < 2 {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+fn float leibniz(int n) {
+    var int i;
+    var float pi, sign;
+    sign = 1.0;
+    while i < n {
+        pi = pi + sign / (2 * i + 1);
+        sign = -sign;
+        i = i + 1;
+    }
+    return 4 * pi;
+}
+";
+
+const ROUNDS: u32 = 5;
+
+fn best<F: FnMut() -> Value>(mut f: F) -> (Duration, Value) {
+    let mut best = Duration::MAX;
+    let mut value = Value::Int(0);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        value = f();
+        best = best.min(start.elapsed());
+    }
+    (best, value)
+}
+
+fn main() {
+    let ast = Parser::new(DefaultLexer::new(SOURCE.as_bytes()))
+        .parse()
+        .unwrap();
+    let stack = compiler::compile(&ast).unwrap();
+    let reg = regcompiler::compile(&ast).unwrap();
+    let cases = [
+        ("sum", Value::Int(3_000_000)),
+        ("fib", Value::Int(27)),
+        ("leibniz", Value::Int(3_000_000)),
+    ];
+    println!(
+        "{:<10} {:>12} {:>12} {:>8}",
+        "bench", "stack", "register", "speedup"
+    );
+    for (name, arg) in cases.iter() {
+        let mut s = vm::VM::new(&stack);
+        let f = stack.func(name).unwrap();
+        let (t1, v1) = best(|| s.call(f, &[*arg]).unwrap());
+        let mut r = regvm::VM::new(&reg);
+        let f = reg.func(name).unwrap();
+        let (t2, v2) = best(|| r.call(f, &[*arg]).unwrap());
+        assert_eq!(v1, v2, "{} gives different results", name);
+        println!(
+            "{:<10} {:>12.2?} {:>12.2?} {:>7.2}x",
+            name,
+            t1,
+            t2,
+            t1.as_secs_f64() / t2.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,652 @@
+use crate::compiler::type_name;
+use crate::lexer::KeyWord;
+use crate::natives::{native, Signature, NATIVES};
+use crate::vm::{Function, Global, Instruction, Local, Program};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+//vm 字节码的文本形式。反汇编的结果可以原样汇编回去，也可以手写来测试虚拟机：
+//
+//    .global int count
+//    .host int twice(int)
+//        0  Exit
+//    .fn int sum(int)
+//    .line 2
+//        1  Ent 1
+//    L12:
+//       12  Lea 2 ...
+//    .end
+//
+//行首的数字是指令在 text 中的下标，只是给人看的，汇编时忽略。`;` 之后是注释。
+//跳转的目标写成标签，Call、Glo、Native、Host 写名字，Const 写浮点数，Str 写字符串，
+//其余指令的操作数是整数。任何操作数都可以写成 `#n` 表示原样的一个字，
+//不是合法指令的字写成 `.word n`。`.line n` 表示之后的指令属于源码第 n 行。
+//汇编时常量池和字符串按编译器的方式重新生成：相同的常量只放一次，每个 Str 追加一个字符串。
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize, //从 1 开始
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+//整个程序的清单：全局变量、宿主函数，然后是 text 中的全部指令
+pub fn disassemble(p: &Program) -> String {
+    let mut out = String::new();
+    for g in p.globals.iter() {
+        out.push_str(&format!(".global {} {}\n", type_name(g.typ), g.name));
+    }
+    for h in p.hosts.iter() {
+        out.push_str(&format!(".host {}\n", signature(&h.name, &h.params, h.ret)));
+    }
+    let targets = targets(p);
+    walk(p, 0, p.text.len(), &targets, true, &mut out);
+    out
+}
+
+//第 f 个函数的清单
+pub fn disassemble_fn(p: &Program, f: usize) -> String {
+    let func = &p.funcs[f];
+    let mut out = format!(".fn {}\n", signature(&func.name, &func.params, func.ret));
+    locals(func, &mut out);
+    let end = func.end.min(p.text.len());
+    walk(p, func.entry.min(end), end, &targets(p), false, &mut out);
+    out.push_str(".end\n");
+    out
+}
+
+fn signature(name: &str, params: &[KeyWord], ret: KeyWord) -> String {
+    let params: Vec<&str> = params.iter().map(|t| type_name(*t)).collect();
+    format!("{} {}({})", type_name(ret), name, params.join(", "))
+}
+
+//调试信息：`.local int name slot start end`
+fn locals(f: &Function, out: &mut String) {
+    for l in f.locals.iter() {
+        out.push_str(&format!(
+            ".local {} {} {} {} {}\n",
+            type_name(l.typ),
+            l.name,
+            l.slot,
+            l.start,
+            l.end
+        ));
+    }
+}
+
+//跳转指令的目标，反汇编时在这些位置放标签
+fn targets(p: &Program) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    let mut i = 0;
+    while i < p.text.len() {
+        match Instruction::decode(p.text[i]) {
+            Some(op) if i + op.operands() < p.text.len() => {
+                if is_jump(op) {
+                    targets.insert(p.text[i + 1] as usize);
+                }
+                i += 1 + op.operands();
+            }
+            _ => i += 1,
+        }
+    }
+    targets
+}
+
+fn is_jump(op: Instruction) -> bool {
+    op == Instruction::Jmp || op == Instruction::Jz || op == Instruction::Jnz
+}
+
+//列出 text[start..end]，funcs 为 true 时在函数的入口和结尾加上 .fn 和 .end
+fn walk(
+    p: &Program,
+    start: usize,
+    end: usize,
+    targets: &HashSet<usize>,
+    funcs: bool,
+    out: &mut String,
+) {
+    let mut i = start;
+    while i < end {
+        if funcs {
+            for _ in p.funcs.iter().filter(|f| f.end == i && f.entry < i) {
+                out.push_str(".end\n");
+            }
+            for f in p.funcs.iter().filter(|f| f.entry == i) {
+                out.push_str(&format!(".fn {}\n", signature(&f.name, &f.params, f.ret)));
+                locals(f, out);
+                if f.end == i {
+                    out.push_str(".end\n");
+                }
+            }
+        }
+        for (_, line) in p.lines.iter().filter(|(pc, _)| *pc == i) {
+            out.push_str(&format!(".line {}\n", line));
+        }
+        if targets.contains(&i) {
+            out.push_str(&format!("L{}:\n", i));
+        }
+        match Instruction::decode(p.text[i]) {
+            Some(op) if i + op.operands() < end => {
+                out.push_str(&format!("{:5}  {:?}", i, op));
+                if op.operands() == 1 {
+                    out.push(' ');
+                    out.push_str(&operand(p, op, p.text[i + 1], targets));
+                }
+                out.push('\n');
+                i += 1 + op.operands();
+            }
+            _ => {
+                out.push_str(&format!("{:5}  .word {}\n", i, p.text[i]));
+                i += 1;
+            }
+        }
+    }
+    if funcs {
+        for _ in p.funcs.iter().filter(|f| f.end == end && f.entry < end) {
+            out.push_str(".end\n");
+        }
+    }
+}
+
+fn operand(p: &Program, op: Instruction, n: u64, targets: &HashSet<usize>) -> String {
+    let i = n as usize;
+    let name = match op {
+        _ if is_jump(op) && targets.contains(&i) => Some(format!("L{}", i)),
+        Instruction::Call => p.funcs.get(i).map(|f| f.name.clone()),
+        Instruction::Glo => p.globals.get(i).map(|g| g.name.clone()),
+        Instruction::Native => NATIVES.get(i).map(|n| n.name.to_owned()),
+        Instruction::Host => p.hosts.get(i).map(|h| h.name.clone()),
+        Instruction::Const => p.consts.get(i).map(|c| format!("{:?}", f64::from_bits(*c))),
+        Instruction::Str if i < p.data.len() => {
+            let s = &p.data[i..];
+            let end = s.iter().position(|b| *b == 0).unwrap_or(s.len());
+            Some(quote(&s[..end]))
+        }
+        Instruction::Lea | Instruction::Imm | Instruction::Ent | Instruction::Adj => {
+            Some((n as i64).to_string())
+        }
+        _ => None,
+    };
+    name.unwrap_or(format!("#{}", n))
+}
+
+//字符串写成带引号的形式，控制字符和不是 UTF-8 的字节用 \x 转义
+fn quote(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    let escape = |s: &mut String, c: char, raw: &[u8]| match c {
+        '"' => s.push_str("\\\""),
+        '\\' => s.push_str("\\\\"),
+        '\n' => s.push_str("\\n"),
+        '\t' => s.push_str("\\t"),
+        '\r' => s.push_str("\\r"),
+        c if !c.is_control() => s.push(c),
+        _ => {
+            for b in raw {
+                s.push_str(&format!("\\x{:02x}", b));
+            }
+        }
+    };
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            let mut buf = [0; 4];
+            for c in text.chars() {
+                escape(&mut s, c, c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+        Err(_) => {
+            for b in bytes {
+                let c = if b.is_ascii() { *b as char } else { '\0' };
+                escape(&mut s, c, &[*b]);
+            }
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn unquote(s: &str) -> Result<Vec<u8>, String> {
+    let bad = || format!("bad string literal {}", s);
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(bad());
+    }
+    let mut out = Vec::new();
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => out.push(b'"'),
+                Some('\\') => out.push(b'\\'),
+                Some('n') => out.push(b'\n'),
+                Some('t') => out.push(b'\t'),
+                Some('r') => out.push(b'\r'),
+                Some('0') => out.push(0),
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    out.push(u8::from_str_radix(&hex, 16).map_err(|_| bad())?);
+                }
+                _ => return Err(bad()),
+            },
+            '"' => return Err(bad()),
+            c => {
+                let mut buf = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+//去掉 `;` 开始的注释，字符串里的 `;` 不算
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_type(s: &str) -> Result<KeyWord, String> {
+    match s {
+        "int" => Ok(KeyWord::Int),
+        "float" => Ok(KeyWord::Float),
+        _ => Err(format!("unknown type `{}`", s)),
+    }
+}
+
+//`int name(int, float)`
+fn parse_signature(s: &str) -> Result<Signature, String> {
+    let bad = || format!("expected `type name(types)` but found `{}`", s);
+    let (ret, rest) = s.split_once(' ').ok_or_else(bad)?;
+    let (name, params) = rest.trim().split_once('(').ok_or_else(bad)?;
+    let params = params.trim_end().strip_suffix(')').ok_or_else(bad)?;
+    let params = params
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(parse_type)
+        .collect::<Result<Vec<KeyWord>, String>>()?;
+    Ok(Signature {
+        name: name.trim().to_owned(),
+        params,
+        ret: parse_type(ret)?,
+    })
+}
+
+fn parse_int(s: &str) -> Result<u64, String> {
+    match s.strip_prefix('#') {
+        Some(raw) => raw.parse::<u64>(),
+        None => s.parse::<i64>().map(|n| n as u64),
+    }
+    .map_err(|_| format!("expected a number but found `{}`", s))
+}
+
+//汇编时每一行的内容
+enum Item<'a> {
+    Global(Global),
+    Host(Signature),
+    Fn(Signature),
+    Local(Local),
+    End,
+    Line(u32),
+    Word(u64),
+    Label(&'a str),
+    Op(Instruction, &'a str), //指令和还没解析的操作数
+}
+
+fn parse_line(line: &str) -> Result<Option<Item<'_>>, String> {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if let Some(d) = line.strip_prefix('.') {
+        let (name, rest) = d.split_once(' ').unwrap_or((d, ""));
+        let rest = rest.trim();
+        return match name {
+            "global" => {
+                let (typ, name) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| "expected `.global type name`".to_owned())?;
+                Ok(Some(Item::Global(Global {
+                    name: name.trim().to_owned(),
+                    typ: parse_type(typ)?,
+                })))
+            }
+            "host" => Ok(Some(Item::Host(parse_signature(rest)?))),
+            "fn" => Ok(Some(Item::Fn(parse_signature(rest)?))),
+            "local" => match rest.split_whitespace().collect::<Vec<&str>>()[..] {
+                [typ, name, slot, start, end] => Ok(Some(Item::Local(Local {
+                    name: name.to_owned(),
+                    typ: parse_type(typ)?,
+                    slot: parse_int(slot)? as i64,
+                    start: parse_int(start)? as usize,
+                    end: parse_int(end)? as usize,
+                }))),
+                _ => Err("expected `.local type name slot start end`".to_owned()),
+            },
+            "end" => Ok(Some(Item::End)),
+            "line" => match rest.parse() {
+                Ok(n) => Ok(Some(Item::Line(n))),
+                Err(_) => Err(format!("expected a line number but found `{}`", rest)),
+            },
+            "word" => Ok(Some(Item::Word(parse_int(rest)?))),
+            _ => Err(format!("unknown directive `.{}`", name)),
+        };
+    }
+    if let Some(label) = line.strip_suffix(':') {
+        return Ok(Some(Item::Label(label.trim())));
+    }
+    //行首的下标忽略
+    let mut rest = line;
+    if rest.starts_with(|c: char| c.is_ascii_digit()) {
+        rest = rest
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .trim_start();
+    }
+    if let Some(w) = rest.strip_prefix(".word") {
+        return Ok(Some(Item::Word(parse_int(w.trim())?)));
+    }
+    let (name, operand) = rest.split_once(' ').unwrap_or((rest, ""));
+    match Instruction::from_name(name) {
+        Some(op) => Ok(Some(Item::Op(op, operand.trim()))),
+        None => Err(format!("unknown instruction `{}`", name)),
+    }
+}
+
+pub fn assemble(src: &str) -> AsmResult<Program> {
+    let mut items = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(item)) => items.push((i + 1, item)),
+            Ok(None) => (),
+            Err(msg) => return Err(AsmError { line: i + 1, msg }),
+        }
+    }
+    let mut p = Program::default();
+    //先登记全部名字，后面定义的函数也可以调用
+    let mut funcs: HashMap<String, usize> = HashMap::new();
+    for (line, item) in items.iter() {
+        let error = |msg: String| AsmError { line: *line, msg };
+        match item {
+            Item::Global(g) if p.global(&g.name).is_some() => {
+                return Err(error(format!("global `{}` is defined twice", g.name)))
+            }
+            Item::Global(g) => p.globals.push(Global {
+                name: g.name.clone(),
+                typ: g.typ,
+            }),
+            Item::Host(h) => p.hosts.push(h.clone()),
+            Item::Fn(f) if funcs.contains_key(&f.name) => {
+                return Err(error(format!("function `{}` is defined twice", f.name)))
+            }
+            Item::Fn(f) => {
+                funcs.insert(f.name.clone(), p.funcs.len());
+                p.funcs.push(Function {
+                    name: f.name.clone(),
+                    entry: 0,
+                    end: 0,
+                    params: f.params.clone(),
+                    ret: f.ret,
+                    locals: Vec::new(),
+                });
+            }
+            Item::Local(l) => match p.funcs.last_mut() {
+                Some(f) => f.locals.push(l.clone()),
+                None => return Err(error("`.local` outside of a function".to_owned())),
+            },
+            _ => (),
+        }
+    }
+
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut fixups: Vec<(usize, usize, &str)> = Vec::new(); //行号，text 下标，标签
+    let mut open: Vec<usize> = Vec::new(); //还没有 .end 的函数
+    let mut next_fn = 0;
+    for (line, item) in items.iter() {
+        let line = *line;
+        let error = |msg: String| AsmError { line, msg };
+        match item {
+            Item::Global(_) | Item::Host(_) | Item::Local(_) => (),
+            Item::Fn(_) => {
+                p.funcs[next_fn].entry = p.text.len();
+                open.push(next_fn);
+                next_fn += 1;
+            }
+            Item::End => match open.pop() {
+                Some(f) => p.funcs[f].end = p.text.len(),
+                None => return Err(error("`.end` without `.fn`".to_owned())),
+            },
+            Item::Line(n) => p.lines.push((p.text.len(), *n)),
+            Item::Word(w) => p.text.push(*w),
+            Item::Label(name) => {
+                if labels.insert(name, p.text.len()).is_some() {
+                    return Err(error(format!("label `{}` is defined twice", name)));
+                }
+            }
+            Item::Op(op, operand) => {
+                p.text.push(*op as u64);
+                if op.operands() == 0 {
+                    if !operand.is_empty() {
+                        return Err(error(format!("`{:?}` takes no operand", op)));
+                    }
+                    continue;
+                }
+                if operand.is_empty() {
+                    return Err(error(format!("`{:?}` needs an operand", op)));
+                }
+                if operand.starts_with('#') {
+                    p.text.push(parse_int(operand).map_err(error)?);
+                    continue;
+                }
+                let n = match op {
+                    _ if is_jump(*op) => {
+                        fixups.push((line, p.text.len(), operand));
+                        0
+                    }
+                    Instruction::Call => match funcs.get(*operand) {
+                        Some(f) => *f,
+                        None => return Err(error(format!("undefined function `{}`", operand))),
+                    },
+                    Instruction::Glo => match p.global(operand) {
+                        Some(g) => g,
+                        None => return Err(error(format!("undefined global `{}`", operand))),
+                    },
+                    Instruction::Native => match native(operand) {
+                        Some(n) => n,
+                        None => {
+                            return Err(error(format!("unknown native function `{}`", operand)))
+                        }
+                    },
+                    Instruction::Host => match p.hosts.iter().position(|h| h.name == *operand) {
+                        Some(h) => h,
+                        None => {
+                            return Err(error(format!("undeclared host function `{}`", operand)))
+                        }
+                    },
+                    Instruction::Const => match operand.parse::<f64>() {
+                        Ok(v) => match p.consts.iter().position(|c| *c == v.to_bits()) {
+                            Some(i) => i,
+                            None => {
+                                p.consts.push(v.to_bits());
+                                p.consts.len() - 1
+                            }
+                        },
+                        Err(_) => {
+                            return Err(error(format!("expected a float but found `{}`", operand)))
+                        }
+                    },
+                    Instruction::Str => {
+                        let bytes = unquote(operand).map_err(error)?;
+                        let offset = p.data.len();
+                        p.data.extend_from_slice(&bytes);
+                        p.data.push(0);
+                        offset
+                    }
+                    _ => {
+                        p.text.push(parse_int(operand).map_err(error)?);
+                        continue;
+                    }
+                };
+                p.text.push(n as u64);
+            }
+        }
+    }
+    if let Some(f) = open.pop() {
+        return Err(AsmError {
+            line: src.lines().count(),
+            msg: format!("function `{}` has no `.end`", p.funcs[f].name),
+        });
+    }
+    for (line, at, label) in fixups {
+        match labels.get(label) {
+            Some(target) => p.text[at] = *target as u64,
+            None => {
+                return Err(AsmError {
+                    line,
+                    msg: format!("undefined label `{}`", label),
+                })
+            }
+        }
+    }
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_with;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use crate::vm::{ErrorKind, Value, VM};
+
+    #[test]
+    fn test_roundtrip() {
+        let s = r#"var int count;
+var float scale;
+
+fn float area(int w, float h) {
+    return w * h * scale;
+}
+
+fn int main() {
+    var int i;
+    scale = 0.5;
+    while i < 10 {
+        if i % 2 { count = count + 1; } else { count = count + twice(i); }
+        i = i + 1;
+    }
+    printf("%s;\t\"%d\"\n", "数", count);
+    return area(3, 4.0) + count;
+}"#;
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let hosts = vec![Signature {
+            name: "twice".to_owned(),
+            params: vec![KeyWord::Int],
+            ret: KeyWord::Int,
+        }];
+        let p = compile_with(&ast, &hosts).unwrap();
+        let text = disassemble(&p);
+        assert!(text.contains(".fn float area(int, float)\n"));
+        assert!(text.contains("Host twice\n"));
+        assert!(text.contains("Glo scale\n"));
+        assert!(text.contains("Const 0.5\n"));
+        assert!(text.contains(r#"Str "%s;\t\"%d\"\n""#));
+        assert!(text.contains(".line 12\n"));
+        let q = assemble(&text).unwrap();
+        assert_eq!(q.text, p.text);
+        assert_eq!(q.consts, p.consts);
+        assert_eq!(q.lines, p.lines);
+        assert_eq!(q.data, p.data);
+        assert_eq!(q.hosts, p.hosts);
+        assert_eq!(disassemble(&q), text);
+        for (f, g) in p.funcs.iter().zip(q.funcs.iter()) {
+            assert_eq!((&f.name, f.entry, f.end), (&g.name, g.entry, g.end));
+        }
+        assert!(p.dump(0).starts_with(".fn float area(int, float)\n"));
+    }
+
+    #[test]
+    fn test_assemble() {
+        //手写的循环：1 + 2 + ... + n
+        let s = "
+    0  Exit
+.fn int sum(int)
+    Ent 1
+    Lea -1
+    Push
+    Imm 0
+    Si          ; s = 0
+loop:
+    Lea 2
+    Li
+    Jz done
+    Lea -1
+    Push
+    Lea -1
+    Li
+    Push
+    Lea 2
+    Li
+    Add
+    Si          ; s = s + n
+    Lea 2
+    Push
+    Lea 2
+    Li
+    Push
+    Imm 1
+    Sub
+    Si          ; n = n - 1
+    Jmp loop
+done:
+    Lea -1
+    Li
+    Lev
+.end
+";
+        let p = assemble(s).unwrap();
+        let mut vm = VM::new(&p);
+        assert_eq!(vm.call(0, &[Value::Int(100)]), Ok(Value::Int(5050)));
+        let p = assemble(".fn int f()\nImm 1\nTrap\n.end\n").unwrap();
+        assert_eq!(
+            VM::new(&p).call(0, &[]).unwrap_err().kind,
+            ErrorKind::Trap(1)
+        );
+
+        let err = |s: &str| assemble(s).unwrap_err();
+        assert_eq!(
+            err(".fn int f()\nImm\n.end"),
+            AsmError {
+                line: 2,
+                msg: "`Imm` needs an operand".to_owned()
+            }
+        );
+        assert_eq!(err("Jmp nowhere").msg, "undefined label `nowhere`");
+        assert_eq!(err("Call f").msg, "undefined function `f`");
+        assert_eq!(err("\n\nFoo 1").line, 3);
+        assert_eq!(
+            err(".fn int f(\n").msg,
+            "expected `type name(types)` but found `int f(`"
+        );
+        assert_eq!(err(".fn int f()\nLev").msg, "function `f` has no `.end`");
+        assert_eq!(
+            assemble("Imm #18446744073709551615").unwrap().text,
+            vec![2, u64::MAX]
+        );
+    }
+}
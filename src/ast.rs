@@ -3,32 +3,35 @@ use crate::lexer::Token;
 use std::fmt;
 use std::fmt::Debug;
 
+#[derive(Debug)]
 pub struct AST {
-    
+    pub global: GlobalDecl,
+    pub funcs: Vec<FuncDecl<StmtNode>>,
 }
 
+#[derive(Debug)]
 pub struct GlobalDecl {
     pub list: Vec<ValueSepc>,
 }
 
 #[derive(Debug)]
 pub struct FuncDecl<T: Stmt + Debug> {
+    pub doc: Option<String>, // `///` 文档注释
     pub typ: KeyWord,
     pub fn_name: Ident,
+    pub line: u32, //`fn` 所在的行
     pub params: Vec<Param>,
     pub body: FuncBody<T>,
 }
 
 pub struct FuncBody<T: Stmt + Debug> {
     pub list: Vec<T>,
+    pub lines: Vec<u32>, //每条语句开始的行号
 }
 
 impl<T: Stmt + Debug> Debug for FuncBody<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for x in self.list.iter() {
-            x.fmt(f)?;
-        }
-        Ok(())
+        f.debug_list().entries(self.list.iter()).finish()
     }
 }
 
@@ -64,6 +67,35 @@ impl Debug for Stmt {
 pub enum StmtNode {
     ValueSepc(ValueSepc),
     AssignStmt(AssignStmt),
+    ExprStmt(ExprNode),
+    ReturnStmt(ReturnStmt),
+    BlockStmt(BlockStmt),
+    IfStmt(IfStmt),
+    WhileStmt(WhileStmt),
+}
+
+#[derive(Debug)]
+pub struct ReturnStmt {
+    pub x: Option<ExprNode>,
+}
+
+#[derive(Debug)]
+pub struct BlockStmt {
+    pub list: Vec<StmtNode>,
+    pub lines: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct IfStmt {
+    pub cond: ExprNode,
+    pub body: BlockStmt,
+    pub els: Option<Box<StmtNode>>, // else 后面是 BlockStmt 或者 IfStmt
+}
+
+#[derive(Debug)]
+pub struct WhileStmt {
+    pub cond: ExprNode,
+    pub body: BlockStmt,
 }
 
 impl Stmt for StmtNode {
@@ -80,6 +112,8 @@ pub enum ExprNode {
     UnaryExpr(UnaryExpr),
     BinaryExpr(BinaryExpr),
     ParenExpr(ParenExpr),
+    BasicLit(BasicLit),
+    CallExpr(CallExpr),
 }
 
 impl Expr for ExprNode {}
@@ -102,8 +136,20 @@ pub struct ParenExpr {
     pub x: Box<ExprNode>,
 }
 
+#[derive(Debug)]
+pub struct BasicLit {
+    pub value: Token,
+}
+
+#[derive(Debug)]
+pub struct CallExpr {
+    pub fun: Box<ExprNode>,
+    pub args: Vec<ExprNode>,
+}
+
 #[derive(Debug)]
 pub struct ValueSepc {
+    pub doc: Option<String>,
     pub names: Vec<Ident>,
     pub typ: KeyWord,
 }
@@ -0,0 +1,8 @@
+use std::process;
+
+fn main() {
+    if let Err(e) = lina::lsp::run() {
+        eprintln!("tars-lsp: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,404 @@
+use crate::lexer::KeyWord;
+use crate::natives::Signature;
+use crate::verify::{verify, VerifyError};
+use crate::vm::{Function, Global, Instruction, Local, Program};
+use std::fmt;
+
+//编译好的程序的文件格式 .tbc，用来发布预编译的脚本或者缓存编译结果。
+//整数都是小端序。依次是：
+//  魔数 MAGIC，版本号 u32
+//  text：个数 u64，每个字 u64
+//  consts：个数 u64，每个常量 u64
+//  funcs：个数 u64，每个是 名字、entry u64、end u64、参数个数 u64 和每个参数的类型、返回类型、
+//         局部变量个数 u64 和每个局部变量的 名字、类型、slot i64、start u64、end u64
+//  globals：个数 u64，每个是 名字、类型
+//  lines：个数 u64，每项是 text 下标 u64、行号 u32
+//  data：字节数 u64，字节
+//  hosts：个数 u64，每个是 名字、参数个数 u64 和每个参数的类型、返回类型
+//  校验和：前面全部字节的 FNV-1a 64 位散列，u64
+//字符串是字节数 u64 加 UTF-8 字节，类型是一个字节，0 是 int，1 是 float。
+//读取时检查魔数、版本、校验和以及函数入口和行号表是否落在 text 里，
+//最后用 verify 检查指令本身。
+
+pub const MAGIC: &[u8; 4] = b"\x7fTBC";
+pub const VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    BadMagic,
+    Version(u32), //不支持的版本
+    Truncated,    //文件在中途结束
+    Checksum,
+    TrailingData,    //读完各部分之后、校验和之前还有多余的字节
+    BadType(u8),     //不认识的类型编码
+    BadString,       //名字不是 UTF-8
+    Invalid(String), //各部分之间不一致，比如函数入口超出 text
+    Verify(VerifyError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "not a tars bytecode file"),
+            LoadError::Version(v) => write!(
+                f,
+                "unsupported bytecode version {} (expected {})",
+                v, VERSION
+            ),
+            LoadError::Truncated => write!(f, "unexpected end of bytecode file"),
+            LoadError::Checksum => write!(f, "checksum mismatch, the file is corrupted"),
+            LoadError::TrailingData => write!(f, "unexpected data after the last section"),
+            LoadError::BadType(t) => write!(f, "bad type code {}", t),
+            LoadError::BadString => write!(f, "name is not valid UTF-8"),
+            LoadError::Invalid(s) => write!(f, "invalid bytecode: {}", s),
+            LoadError::Verify(e) => write!(f, "invalid bytecode: {}", e),
+        }
+    }
+}
+
+pub type LoadResult<T> = Result<T, LoadError>;
+
+//FNV-1a，不需要抗篡改，只用来发现损坏和截断
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100_0000_01b3);
+    }
+    h
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, n: u32) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn typ(&mut self, typ: KeyWord) {
+        self.out.push(match typ {
+            KeyWord::Float => 1,
+            _ => 0,
+        });
+    }
+
+    fn types(&mut self, types: &[KeyWord]) {
+        self.u64(types.len() as u64);
+        for t in types {
+            self.typ(*t);
+        }
+    }
+}
+
+pub fn encode(p: &Program) -> Vec<u8> {
+    let mut w = Writer { out: Vec::new() };
+    w.out.extend_from_slice(MAGIC);
+    w.u32(VERSION);
+    w.u64(p.text.len() as u64);
+    for n in p.text.iter() {
+        w.u64(*n);
+    }
+    w.u64(p.consts.len() as u64);
+    for n in p.consts.iter() {
+        w.u64(*n);
+    }
+    w.u64(p.funcs.len() as u64);
+    for f in p.funcs.iter() {
+        w.str(&f.name);
+        w.u64(f.entry as u64);
+        w.u64(f.end as u64);
+        w.types(&f.params);
+        w.typ(f.ret);
+        w.u64(f.locals.len() as u64);
+        for l in f.locals.iter() {
+            w.str(&l.name);
+            w.typ(l.typ);
+            w.u64(l.slot as u64);
+            w.u64(l.start as u64);
+            w.u64(l.end as u64);
+        }
+    }
+    w.u64(p.globals.len() as u64);
+    for g in p.globals.iter() {
+        w.str(&g.name);
+        w.typ(g.typ);
+    }
+    w.u64(p.lines.len() as u64);
+    for (pc, line) in p.lines.iter() {
+        w.u64(*pc as u64);
+        w.u32(*line);
+    }
+    w.u64(p.data.len() as u64);
+    w.out.extend_from_slice(&p.data);
+    w.u64(p.hosts.len() as u64);
+    for h in p.hosts.iter() {
+        w.str(&h.name);
+        w.types(&h.params);
+        w.typ(h.ret);
+    }
+    let sum = checksum(&w.out);
+    w.u64(sum);
+    w.out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> LoadResult<&'a [u8]> {
+        if n > self.bytes.len() - self.pos {
+            return Err(LoadError::Truncated);
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> LoadResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> LoadResult<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> LoadResult<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    //个数，每一项至少占 size 个字节。超过剩下的字节时提前报告截断，不去分配大块内存
+    fn count(&mut self, size: usize) -> LoadResult<usize> {
+        let n = self.u64()?;
+        if n > ((self.bytes.len() - self.pos) / size) as u64 {
+            return Err(LoadError::Truncated);
+        }
+        Ok(n as usize)
+    }
+
+    fn str(&mut self) -> LoadResult<String> {
+        let n = self.count(1)?;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|_| LoadError::BadString)
+    }
+
+    fn typ(&mut self) -> LoadResult<KeyWord> {
+        match self.u8()? {
+            0 => Ok(KeyWord::Int),
+            1 => Ok(KeyWord::Float),
+            t => Err(LoadError::BadType(t)),
+        }
+    }
+
+    fn types(&mut self) -> LoadResult<Vec<KeyWord>> {
+        let n = self.count(1)?;
+        (0..n).map(|_| self.typ()).collect()
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> LoadResult<Program> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    let mut r = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    //先核对校验和，后面读到的内容至少没有损坏
+    if bytes.len() < r.pos + 8 {
+        return Err(LoadError::Truncated);
+    }
+    let body = &bytes[..bytes.len() - 8];
+    let mut sum = [0; 8];
+    sum.copy_from_slice(&bytes[bytes.len() - 8..]);
+    if checksum(body) != u64::from_le_bytes(sum) {
+        return Err(LoadError::Checksum);
+    }
+    r.bytes = body;
+
+    let mut p = Program::default();
+    let n = r.count(8)?;
+    for _ in 0..n {
+        p.text.push(r.u64()?);
+    }
+    let n = r.count(8)?;
+    for _ in 0..n {
+        p.consts.push(r.u64()?);
+    }
+    let n = r.count(41)?;
+    for _ in 0..n {
+        let mut f = Function {
+            name: r.str()?,
+            entry: r.u64()? as usize,
+            end: r.u64()? as usize,
+            params: r.types()?,
+            ret: r.typ()?,
+            locals: Vec::new(),
+        };
+        let n = r.count(33)?;
+        for _ in 0..n {
+            f.locals.push(Local {
+                name: r.str()?,
+                typ: r.typ()?,
+                slot: r.u64()? as i64,
+                start: r.u64()? as usize,
+                end: r.u64()? as usize,
+            });
+        }
+        p.funcs.push(f);
+    }
+    let n = r.count(9)?;
+    for _ in 0..n {
+        p.globals.push(Global {
+            name: r.str()?,
+            typ: r.typ()?,
+        });
+    }
+    let n = r.count(12)?;
+    for _ in 0..n {
+        p.lines.push((r.u64()? as usize, r.u32()?));
+    }
+    let n = r.count(1)?;
+    p.data = r.take(n)?.to_vec();
+    let n = r.count(17)?;
+    for _ in 0..n {
+        p.hosts.push(Signature {
+            name: r.str()?,
+            params: r.types()?,
+            ret: r.typ()?,
+        });
+    }
+    if r.pos != body.len() {
+        return Err(LoadError::TrailingData);
+    }
+    validate(&p)?;
+    Ok(p)
+}
+
+//各部分引用的位置都要在范围内
+fn validate(p: &Program) -> LoadResult<()> {
+    let invalid = |s: String| Err(LoadError::Invalid(s));
+    if p.text.first() != Some(&(Instruction::Exit as u64)) {
+        return invalid("text must start with Exit".to_owned());
+    }
+    for f in p.funcs.iter() {
+        if f.entry == 0 || f.entry > f.end || f.end > p.text.len() {
+            return invalid(format!(
+                "function `{}` spans {}..{} outside the text of {} words",
+                f.name,
+                f.entry,
+                f.end,
+                p.text.len()
+            ));
+        }
+        for l in f.locals.iter() {
+            if l.start > l.end || l.end > p.text.len() {
+                return invalid(format!(
+                    "local `{}` of `{}` spans {}..{} outside the text",
+                    l.name, f.name, l.start, l.end
+                ));
+            }
+        }
+    }
+    let mut last = 0;
+    for (pc, _) in p.lines.iter() {
+        if *pc < last || *pc >= p.text.len() {
+            return invalid(format!("line table entry at {} is out of order", pc));
+        }
+        last = *pc;
+    }
+    if p.data.last().is_some_and(|b| *b != 0) {
+        return invalid("string data is not NUL-terminated".to_owned());
+    }
+    verify(p).map_err(LoadError::Verify)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_with;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use crate::vm::{Value, VM};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_bytecode() {
+        let s = r#"var float scale;
+
+fn float area(int w, float h) {
+    return w * h * scale;
+}
+
+fn int main() {
+    scale = 0.5;
+    printf("%s\n", "ok");
+    return area(3, 4.0) + twice(1);
+}"#;
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let hosts = vec![Signature {
+            name: "twice".to_owned(),
+            params: vec![KeyWord::Int],
+            ret: KeyWord::Int,
+        }];
+        let p = compile_with(&ast, &hosts).unwrap();
+        let bytes = encode(&p);
+        assert_eq!(&bytes[..4], MAGIC);
+        let q = decode(&bytes).unwrap();
+        assert_eq!(q.text, p.text);
+        assert_eq!(q.consts, p.consts);
+        assert_eq!(q.lines, p.lines);
+        assert_eq!(q.data, p.data);
+        assert_eq!(q.hosts, p.hosts);
+        assert_eq!(q.dump(1), p.dump(1));
+        assert_eq!(q.globals[0].typ, KeyWord::Float);
+        assert_eq!(encode(&q), bytes);
+        let mut vm = VM::new(&q);
+        vm.io().stdout = Box::new(std::io::sink());
+        vm.bind(
+            "twice",
+            Rc::new(|args: &[Value]| Ok(Value::Int(args[0].bits() as i64 * 2))),
+        );
+        assert_eq!(vm.call(q.func("main").unwrap(), &[]), Ok(Value::Int(8)));
+
+        //每个字节都被校验和覆盖
+        for i in 8..bytes.len() {
+            let mut bad = bytes.clone();
+            bad[i] ^= 0x40;
+            assert!(decode(&bad).is_err(), "flipped byte {}", i);
+        }
+        assert_eq!(decode(b"#!/bin/tars").err(), Some(LoadError::BadMagic));
+        let mut old = bytes.clone();
+        old[4] = 9;
+        assert_eq!(decode(&old).err(), Some(LoadError::Version(9)));
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]).err(),
+            Some(LoadError::Checksum)
+        );
+        assert_eq!(decode(&bytes[..6]).err(), Some(LoadError::Truncated));
+        //校验和正确但是内容不一致
+        let mut p = p;
+        p.funcs[0].end = p.text.len() + 1;
+        assert!(matches!(decode(&encode(&p)), Err(LoadError::Invalid(_))));
+    }
+}
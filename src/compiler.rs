@@ -0,0 +1,966 @@
+use crate::ast::{CallExpr, ExprNode, FuncDecl, StmtNode, AST};
+use crate::lexer::{KeyWord, Operator, Token};
+use crate::natives::{native, Signature, NATIVES};
+use crate::vm::{Function, Global, Instruction, Local, Program};
+use std::collections::HashMap;
+use std::fmt;
+
+//把 AST 编译成 vm 的字节码。表达式的值放在 ax 里，二元运算先把左边的值压栈。
+//函数开头用 Ent 分配局部变量，参数和局部变量都按 bp 的偏移访问：
+//n 个参数中的第 i 个在 bp + (n + 1 - i)，第 j 个局部变量在 bp - 1 - j。
+//局部变量的槽位在块结束后回收，Ent 分配的是同时存活的最大个数。
+//float 按 f64 的位模式存放，表达式的类型在编译时确定：int 和 float
+//一起运算时先把 int 转成 float，赋值、传参和返回时转成目标的类型。
+//每条语句和函数开头在 Program::lines 中记下行号，运行时错误据此给出源码位置。
+//没有同名定义时 BUILTINS 和 natives::NATIVES 中的名字是内置函数。
+//字符串字面量放在 Program::data 里，值是它的地址，类型是 int。
+//compile_with 额外传入的宿主函数优先于内置函数，调用编译成 Host，签名记在 Program::hosts 里。
+
+//内置函数直接编译成一条指令：前面的参数依次压栈，最后一个参数留在 ax 里
+pub struct Builtin {
+    pub name: &'static str,
+    pub params: &'static [KeyWord],
+    pub ret: KeyWord,
+    pub op: Instruction,
+}
+
+//名字解析时当作已经定义。数组的元素是整数，数组本身是引用，放在 int 变量里
+pub const BUILTINS: &[Builtin] = &[
+    //以错误码 code 中止执行
+    Builtin {
+        name: "trap",
+        params: &[KeyWord::Int],
+        ret: KeyWord::Int,
+        op: Instruction::Trap,
+    },
+    //array(n) 在堆上分配 n 个元素的数组
+    Builtin {
+        name: "array",
+        params: &[KeyWord::Int],
+        ret: KeyWord::Int,
+        op: Instruction::New,
+    },
+    //get(a, i) 读 a[i]
+    Builtin {
+        name: "get",
+        params: &[KeyWord::Int, KeyWord::Int],
+        ret: KeyWord::Int,
+        op: Instruction::HGet,
+    },
+    //set(a, i, v) 写 a[i]，返回 v
+    Builtin {
+        name: "set",
+        params: &[KeyWord::Int, KeyWord::Int, KeyWord::Int],
+        ret: KeyWord::Int,
+        op: Instruction::HSet,
+    },
+    Builtin {
+        name: "len",
+        params: &[KeyWord::Int],
+        ret: KeyWord::Int,
+        op: Instruction::HLen,
+    },
+];
+
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+//名字是内置函数或者本地函数
+pub fn is_builtin(name: &str) -> bool {
+    builtin(name).is_some() || native(name).is_some()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    Undefined(String),
+    NotFunction(String),
+    NotVariable(String),
+    Arity(String, usize, usize), //函数名，参数个数，实际传入的个数
+    NotAssignable,
+    Unsupported(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Undefined(s) => write!(f, "undefined name `{}`", s),
+            CompileError::NotFunction(s) => write!(f, "`{}` is not a function", s),
+            CompileError::NotVariable(s) => write!(f, "`{}` is a function, not a variable", s),
+            CompileError::Arity(s, want, got) => write!(
+                f,
+                "`{}` takes {} argument(s) but {} were given",
+                s, want, got
+            ),
+            CompileError::NotAssignable => write!(f, "left side of `=` must be a variable"),
+            CompileError::Unsupported(s) => write!(f, "{} is not supported by the VM", s),
+        }
+    }
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    Global(usize),
+    Local(i64), //相对 bp 的偏移
+}
+
+struct Compiler<'a> {
+    p: Program,
+    funcs: HashMap<&'a str, usize>,
+    globals: HashMap<&'a str, usize>,
+    scopes: Vec<HashMap<&'a str, (Var, KeyWord)>>,
+    next_local: usize,
+    max_local: usize,
+    ret: KeyWord,       //当前函数的返回类型
+    locals: Vec<Local>, //当前函数的参数和局部变量，作用域结束时填上 end
+    hosts: &'a [Signature],
+}
+
+pub fn compile(ast: &AST) -> CompileResult<Program> {
+    compile_with(ast, &[])
+}
+
+//hosts 是宿主程序提供的函数
+pub fn compile_with<'a>(ast: &'a AST, hosts: &'a [Signature]) -> CompileResult<Program> {
+    let mut c = Compiler {
+        p: Program::default(),
+        funcs: HashMap::new(),
+        globals: HashMap::new(),
+        scopes: Vec::new(),
+        next_local: 0,
+        max_local: 0,
+        ret: KeyWord::Int,
+        locals: Vec::new(),
+        hosts,
+    };
+    c.p.hosts = hosts.to_vec();
+    c.emit(Instruction::Exit);
+    for spec in ast.global.list.iter() {
+        for ident in spec.names.iter() {
+            c.globals.insert(&ident.name, c.p.globals.len());
+            c.p.globals.push(Global {
+                name: ident.name.clone(),
+                typ: spec.typ,
+            });
+        }
+    }
+    //先登记全部函数，函数体里可以调用后面定义的函数
+    for f in ast.funcs.iter() {
+        c.funcs.insert(&f.fn_name.name, c.p.funcs.len());
+        c.p.funcs.push(Function {
+            name: f.fn_name.name.clone(),
+            entry: 0,
+            end: 0,
+            params: f.params.iter().map(|p| p.typ).collect(),
+            ret: f.typ,
+            locals: Vec::new(),
+        });
+    }
+    for (i, f) in ast.funcs.iter().enumerate() {
+        c.compile_fn(i, f)?;
+    }
+    Ok(c.p)
+}
+
+pub fn binary_op(op: &Token, typ: KeyWord) -> CompileResult<Instruction> {
+    let float = typ == KeyWord::Float;
+    let op = match op {
+        Token::Oper(op) => op,
+        op => return Err(CompileError::Unsupported(format!("operator {:?}", op))),
+    };
+    Ok(match op {
+        Operator::Add if float => Instruction::FAdd,
+        Operator::Sub if float => Instruction::FSub,
+        Operator::Star if float => Instruction::FMul,
+        Operator::Div if float => Instruction::FDiv,
+        Operator::Mod if float => Instruction::FMod,
+        Operator::Equal if float => Instruction::FEq,
+        Operator::NotEqual if float => Instruction::FNe,
+        Operator::Less if float => Instruction::FLt,
+        Operator::Greate if float => Instruction::FGt,
+        Operator::LessEqual if float => Instruction::FLe,
+        Operator::GreateEqual if float => Instruction::FGe,
+        Operator::Add => Instruction::Add,
+        Operator::Sub => Instruction::Sub,
+        Operator::Star => Instruction::Mul,
+        Operator::Div => Instruction::Div,
+        Operator::Mod => Instruction::Mod,
+        Operator::Equal => Instruction::Eq,
+        Operator::NotEqual => Instruction::Ne,
+        Operator::Less => Instruction::Lt,
+        Operator::Greate => Instruction::Gt,
+        Operator::LessEqual => Instruction::Le,
+        Operator::GreateEqual => Instruction::Ge,
+        //位运算只能用在 int 上
+        Operator::BitAnd if !float => Instruction::And,
+        Operator::BitOr if !float => Instruction::Or,
+        Operator::BitXor if !float => Instruction::Xor,
+        Operator::BitShiftLeft if !float => Instruction::Shl,
+        Operator::BitShiftRight if !float => Instruction::Shr,
+        op => {
+            return Err(CompileError::Unsupported(format!(
+                "operator {:?} on {}",
+                op,
+                type_name(typ)
+            )))
+        }
+    })
+}
+
+//比较的结果是 int，其他运算的结果和操作数同类型
+pub fn result_type(op: Instruction, typ: KeyWord) -> KeyWord {
+    match op {
+        Instruction::Eq
+        | Instruction::Ne
+        | Instruction::Lt
+        | Instruction::Gt
+        | Instruction::Le
+        | Instruction::Ge
+        | Instruction::FEq
+        | Instruction::FNe
+        | Instruction::FLt
+        | Instruction::FGt
+        | Instruction::FLe
+        | Instruction::FGe => KeyWord::Int,
+        _ => typ,
+    }
+}
+
+pub fn type_name(typ: KeyWord) -> &'static str {
+    match typ {
+        KeyWord::Float => "float",
+        _ => "int",
+    }
+}
+
+//两边的类型不同时按 float 运算
+pub fn join(x: KeyWord, y: KeyWord) -> KeyWord {
+    if x == KeyWord::Float || y == KeyWord::Float {
+        KeyWord::Float
+    } else {
+        KeyWord::Int
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn compile_fn(&mut self, index: usize, f: &'a FuncDecl<StmtNode>) -> CompileResult<()> {
+        self.ret = f.typ;
+        self.p.funcs[index].entry = self.p.text.len();
+        let n = f.params.len() as i64;
+        let mut params = HashMap::new();
+        self.locals.clear();
+        for (i, p) in f.params.iter().enumerate() {
+            let var = Var::Local(n + 1 - i as i64);
+            params.insert(p.ident.name.as_str(), (var, p.typ));
+            self.locals.push(Local {
+                name: p.ident.name.clone(),
+                typ: p.typ,
+                slot: n + 1 - i as i64,
+                start: self.p.text.len(),
+                end: 0,
+            });
+        }
+        self.scopes = vec![params];
+        self.next_local = 0;
+        self.max_local = 0;
+        self.line(f.line);
+        let ent = self.emit_with(Instruction::Ent, 0);
+        self.compile_block(&f.body.list, &f.body.lines)?;
+        //没有 return 时返回 0
+        self.zero(self.ret);
+        self.emit(Instruction::Lev);
+        self.p.text[ent] = self.max_local as u64;
+        self.p.funcs[index].end = self.p.text.len();
+        for local in self.locals.iter_mut().filter(|l| l.end == 0) {
+            local.end = self.p.text.len();
+        }
+        self.p.funcs[index].locals = std::mem::take(&mut self.locals);
+        Ok(())
+    }
+
+    fn compile_block(&mut self, list: &'a [StmtNode], lines: &[u32]) -> CompileResult<()> {
+        self.scopes.push(HashMap::new());
+        let next_local = self.next_local;
+        let locals = self.locals.len();
+        for (stmt, line) in list.iter().zip(lines.iter()) {
+            self.line(*line);
+            self.compile_stmt(stmt)?;
+        }
+        self.next_local = next_local;
+        self.scopes.pop();
+        let end = self.p.text.len();
+        for local in self.locals[locals..].iter_mut().filter(|l| l.end == 0) {
+            local.end = end;
+        }
+        Ok(())
+    }
+
+    fn compile_stmt(&mut self, stmt: &'a StmtNode) -> CompileResult<()> {
+        match stmt {
+            StmtNode::ValueSepc(spec) => {
+                for ident in spec.names.iter() {
+                    let var = Var::Local(-1 - self.next_local as i64);
+                    self.locals.push(Local {
+                        name: ident.name.clone(),
+                        typ: spec.typ,
+                        slot: -1 - self.next_local as i64,
+                        start: self.p.text.len(),
+                        end: 0,
+                    });
+                    self.next_local += 1;
+                    self.max_local = self.max_local.max(self.next_local);
+                    self.scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert(ident.name.as_str(), (var, spec.typ));
+                    //栈上的槽位可能是上次调用留下的值，清零
+                    self.address(var);
+                    self.emit(Instruction::Push);
+                    self.zero(spec.typ);
+                    self.emit(Instruction::Si);
+                }
+            }
+            StmtNode::AssignStmt(assign) => {
+                let (var, typ) = match &assign.x {
+                    ExprNode::IdentExpr(ident) => self.lookup(&ident.name)?,
+                    _ => return Err(CompileError::NotAssignable),
+                };
+                self.address(var);
+                self.emit(Instruction::Push);
+                self.compile_expr_as(&assign.y, typ)?;
+                self.emit(Instruction::Si);
+            }
+            StmtNode::ExprStmt(x) => {
+                self.compile_expr(x)?;
+            }
+            StmtNode::ReturnStmt(ret) => {
+                match &ret.x {
+                    Some(x) => self.compile_expr_as(x, self.ret)?,
+                    None => self.zero(self.ret),
+                }
+                self.emit(Instruction::Lev);
+            }
+            StmtNode::BlockStmt(block) => self.compile_block(&block.list, &block.lines)?,
+            StmtNode::IfStmt(s) => {
+                self.compile_cond(&s.cond)?;
+                let jz = self.emit_with(Instruction::Jz, 0);
+                self.compile_block(&s.body.list, &s.body.lines)?;
+                match &s.els {
+                    Some(els) => {
+                        let jmp = self.emit_with(Instruction::Jmp, 0);
+                        self.patch(jz);
+                        self.compile_stmt(els)?;
+                        self.patch(jmp);
+                    }
+                    None => self.patch(jz),
+                }
+            }
+            StmtNode::WhileStmt(s) => {
+                let start = self.p.text.len();
+                self.compile_cond(&s.cond)?;
+                let jz = self.emit_with(Instruction::Jz, 0);
+                self.compile_block(&s.body.list, &s.body.lines)?;
+                self.emit_with(Instruction::Jmp, start as u64);
+                self.patch(jz);
+            }
+        }
+        Ok(())
+    }
+
+    //编译表达式，返回值的类型
+    fn compile_expr(&mut self, x: &'a ExprNode) -> CompileResult<KeyWord> {
+        match x {
+            ExprNode::IdentExpr(ident) => {
+                let (var, typ) = self.lookup(&ident.name)?;
+                self.address(var);
+                self.emit(Instruction::Li);
+                Ok(typ)
+            }
+            ExprNode::BasicLit(lit) => match &lit.value {
+                Token::Number(n) => {
+                    self.emit_with(Instruction::Imm, *n as i64 as u64);
+                    Ok(KeyWord::Int)
+                }
+                Token::Float(n) => {
+                    self.float(*n);
+                    Ok(KeyWord::Float)
+                }
+                Token::Str(s) => {
+                    let offset = self.p.data.len();
+                    self.p.data.extend_from_slice(s.as_bytes());
+                    self.p.data.push(0);
+                    self.emit_with(Instruction::Str, offset as u64);
+                    Ok(KeyWord::Int)
+                }
+                t => Err(CompileError::Unsupported(format!("literal {:?}", t))),
+            },
+            ExprNode::ParenExpr(p) => self.compile_expr(&p.x),
+            ExprNode::UnaryExpr(u) => {
+                let typ = self.type_of(&u.x)?;
+                //写成 init op x，init 和 x 同一类型
+                let (init, op) = match (&u.op, typ) {
+                    (Token::Oper(Operator::Add), _) => return self.compile_expr(&u.x),
+                    (Token::Oper(Operator::Sub), KeyWord::Float) => (0, Instruction::FSub),
+                    (Token::Oper(Operator::Sub), _) => (0, Instruction::Sub),
+                    (Token::Oper(Operator::LogicNot), KeyWord::Float) => (0, Instruction::FEq),
+                    (Token::Oper(Operator::LogicNot), _) => (0, Instruction::Eq),
+                    (Token::Oper(Operator::BitNot), KeyWord::Int) => (-1, Instruction::Xor),
+                    (op, _) => {
+                        return Err(CompileError::Unsupported(format!(
+                            "operator {:?} on {}",
+                            op,
+                            type_name(typ)
+                        )))
+                    }
+                };
+                match typ {
+                    KeyWord::Float => self.float(init as f64),
+                    _ => {
+                        self.emit_with(Instruction::Imm, init as u64);
+                    }
+                }
+                self.emit(Instruction::Push);
+                self.compile_expr(&u.x)?;
+                self.emit(op);
+                Ok(result_type(op, typ))
+            }
+            ExprNode::BinaryExpr(b) => {
+                let typ = join(self.type_of(&b.x)?, self.type_of(&b.y)?);
+                let op = binary_op(&b.op, typ)?;
+                self.compile_expr_as(&b.x, typ)?;
+                self.emit(Instruction::Push);
+                self.compile_expr_as(&b.y, typ)?;
+                self.emit(op);
+                Ok(result_type(op, typ))
+            }
+            ExprNode::CallExpr(call) => {
+                if let Some(n) = self.host(call) {
+                    return self.compile_host(n, call);
+                }
+                if let Some(b) = self.builtin(call) {
+                    if call.args.len() != b.params.len() {
+                        return Err(CompileError::Arity(
+                            b.name.to_owned(),
+                            b.params.len(),
+                            call.args.len(),
+                        ));
+                    }
+                    for (i, (arg, typ)) in call.args.iter().zip(b.params.iter()).enumerate() {
+                        self.compile_expr_as(arg, *typ)?;
+                        if i + 1 < b.params.len() {
+                            self.emit(Instruction::Push);
+                        }
+                    }
+                    self.emit(b.op);
+                    return Ok(b.ret);
+                }
+                if let Some(n) = self.native(call) {
+                    return self.compile_native(n, call);
+                }
+                let f = self.callee(call)?;
+                let params = self.p.funcs[f].params.clone();
+                for (arg, typ) in call.args.iter().zip(params.iter()) {
+                    self.compile_expr_as(arg, *typ)?;
+                    self.emit(Instruction::Push);
+                }
+                self.emit_with(Instruction::Call, f as u64);
+                if !params.is_empty() {
+                    self.emit_with(Instruction::Adj, params.len() as u64);
+                }
+                Ok(self.p.funcs[f].ret)
+            }
+        }
+    }
+
+    //编译表达式并把值转成 typ
+    fn compile_expr_as(&mut self, x: &'a ExprNode, typ: KeyWord) -> CompileResult<()> {
+        let from = self.compile_expr(x)?;
+        match (from, typ) {
+            (KeyWord::Int, KeyWord::Float) => self.emit(Instruction::Itof),
+            (KeyWord::Float, KeyWord::Int) => self.emit(Instruction::Ftoi),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    //条件的值不等于 0 时为真。-0.0 的位模式不是 0，float 要比较一次
+    fn compile_cond(&mut self, x: &'a ExprNode) -> CompileResult<()> {
+        if self.compile_expr(x)? == KeyWord::Float {
+            self.emit(Instruction::Push);
+            self.float(0.0);
+            self.emit(Instruction::FNe);
+        }
+        Ok(())
+    }
+
+    //不生成代码，只推导表达式的类型
+    fn type_of(&self, x: &ExprNode) -> CompileResult<KeyWord> {
+        match x {
+            ExprNode::IdentExpr(ident) => Ok(self.lookup(&ident.name)?.1),
+            ExprNode::BasicLit(lit) => match &lit.value {
+                Token::Float(_) => Ok(KeyWord::Float),
+                _ => Ok(KeyWord::Int),
+            },
+            ExprNode::ParenExpr(p) => self.type_of(&p.x),
+            ExprNode::UnaryExpr(u) => match &u.op {
+                Token::Oper(Operator::LogicNot) => Ok(KeyWord::Int),
+                _ => self.type_of(&u.x),
+            },
+            ExprNode::BinaryExpr(b) => {
+                let typ = join(self.type_of(&b.x)?, self.type_of(&b.y)?);
+                Ok(result_type(binary_op(&b.op, typ)?, typ))
+            }
+            ExprNode::CallExpr(call) => {
+                match (self.host(call), self.builtin(call), self.native(call)) {
+                    (Some(n), _, _) => Ok(self.hosts[n].ret),
+                    (_, Some(b), _) => Ok(b.ret),
+                    (_, _, Some(n)) => Ok(NATIVES[n].ret),
+                    _ => Ok(self.p.funcs[self.callee(call)?].ret),
+                }
+            }
+        }
+    }
+
+    //没有同名定义时调用的是内置函数
+    fn builtin(&self, call: &CallExpr) -> Option<&'static Builtin> {
+        match &*call.fun {
+            ExprNode::IdentExpr(ident) => builtin(&ident.name)
+                .filter(|_| matches!(self.lookup(&ident.name), Err(CompileError::Undefined(_)))),
+            _ => None,
+        }
+    }
+
+    fn native(&self, call: &CallExpr) -> Option<usize> {
+        match &*call.fun {
+            ExprNode::IdentExpr(ident) => native(&ident.name)
+                .filter(|_| matches!(self.lookup(&ident.name), Err(CompileError::Undefined(_)))),
+            _ => None,
+        }
+    }
+
+    fn host(&self, call: &CallExpr) -> Option<usize> {
+        match &*call.fun {
+            ExprNode::IdentExpr(ident) => self
+                .hosts
+                .iter()
+                .position(|h| h.name == ident.name)
+                .filter(|_| matches!(self.lookup(&ident.name), Err(CompileError::Undefined(_)))),
+            _ => None,
+        }
+    }
+
+    //参数依次压栈，然后是参数个数。可变参数不做转换，按各自的类型传入
+    fn compile_native(&mut self, n: usize, call: &'a CallExpr) -> CompileResult<KeyWord> {
+        let native = &NATIVES[n];
+        let argc = call.args.len();
+        if argc < native.params.len() || (argc > native.params.len() && !native.variadic) {
+            return Err(CompileError::Arity(
+                native.name.to_owned(),
+                native.params.len(),
+                argc,
+            ));
+        }
+        for (i, arg) in call.args.iter().enumerate() {
+            match native.params.get(i) {
+                Some(typ) => self.compile_expr_as(arg, *typ)?,
+                None => {
+                    self.compile_expr(arg)?;
+                }
+            }
+            self.emit(Instruction::Push);
+        }
+        self.emit_with(Instruction::Imm, argc as u64);
+        self.emit_with(Instruction::Native, n as u64);
+        if argc > 0 {
+            self.emit_with(Instruction::Adj, argc as u64);
+        }
+        Ok(native.ret)
+    }
+
+    //参数转成声明的类型后依次压栈
+    fn compile_host(&mut self, n: usize, call: &'a CallExpr) -> CompileResult<KeyWord> {
+        let host = &self.hosts[n];
+        if call.args.len() != host.params.len() {
+            return Err(CompileError::Arity(
+                host.name.clone(),
+                host.params.len(),
+                call.args.len(),
+            ));
+        }
+        for (arg, typ) in call.args.iter().zip(host.params.iter()) {
+            self.compile_expr_as(arg, *typ)?;
+            self.emit(Instruction::Push);
+        }
+        self.emit_with(Instruction::Host, n as u64);
+        if !host.params.is_empty() {
+            self.emit_with(Instruction::Adj, host.params.len() as u64);
+        }
+        Ok(host.ret)
+    }
+
+    //被调用的函数，检查参数个数
+    fn callee(&self, call: &CallExpr) -> CompileResult<usize> {
+        let name = match &*call.fun {
+            ExprNode::IdentExpr(ident) => ident.name.as_str(),
+            _ => {
+                return Err(CompileError::Unsupported(
+                    "calling an expression".to_owned(),
+                ))
+            }
+        };
+        if self.scopes.iter().any(|s| s.contains_key(name)) || self.globals.contains_key(name) {
+            return Err(CompileError::NotFunction(name.to_owned()));
+        }
+        let f = match self.funcs.get(name) {
+            Some(f) => *f,
+            None => return Err(CompileError::Undefined(name.to_owned())),
+        };
+        let params = self.p.funcs[f].params.len();
+        if params != call.args.len() {
+            return Err(CompileError::Arity(
+                name.to_owned(),
+                params,
+                call.args.len(),
+            ));
+        }
+        Ok(f)
+    }
+
+    //内层作用域的变量遮住外层的，最后是全局变量
+    fn lookup(&self, name: &str) -> CompileResult<(Var, KeyWord)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(v) = scope.get(name) {
+                return Ok(*v);
+            }
+        }
+        if let Some(g) = self.globals.get(name) {
+            return Ok((Var::Global(*g), self.p.globals[*g].typ));
+        }
+        if self.funcs.contains_key(name) {
+            return Err(CompileError::NotVariable(name.to_owned()));
+        }
+        Err(CompileError::Undefined(name.to_owned()))
+    }
+
+    //变量的地址放进 ax
+    fn address(&mut self, var: Var) {
+        match var {
+            Var::Global(i) => self.emit_with(Instruction::Glo, i as u64),
+            Var::Local(n) => self.emit_with(Instruction::Lea, n as u64),
+        };
+    }
+
+    //之后生成的指令属于源码的第 line 行
+    fn line(&mut self, line: u32) {
+        let at = self.p.text.len();
+        match self.p.lines.last_mut() {
+            Some(last) if last.0 == at => last.1 = line,
+            _ => self.p.lines.push((at, line)),
+        }
+    }
+
+    //typ 类型的 0
+    fn zero(&mut self, typ: KeyWord) {
+        match typ {
+            KeyWord::Float => self.float(0.0),
+            _ => {
+                self.emit_with(Instruction::Imm, 0);
+            }
+        }
+    }
+
+    //浮点数放在常量池里，整数直接用 Imm
+    fn float(&mut self, n: f64) {
+        let i = self.constant(n.to_bits());
+        self.emit_with(Instruction::Const, i as u64);
+    }
+
+    fn constant(&mut self, v: u64) -> usize {
+        match self.p.consts.iter().position(|c| *c == v) {
+            Some(i) => i,
+            None => {
+                self.p.consts.push(v);
+                self.p.consts.len() - 1
+            }
+        }
+    }
+
+    fn emit(&mut self, op: Instruction) {
+        self.p.text.push(op as u64);
+    }
+
+    //返回操作数的位置，跳转目标之后用 patch 回填
+    fn emit_with(&mut self, op: Instruction, n: u64) -> usize {
+        self.p.text.push(op as u64);
+        self.p.text.push(n);
+        self.p.text.len() - 1
+    }
+
+    //让 at 处的跳转指向当前位置
+    fn patch(&mut self, at: usize) {
+        self.p.text[at] = self.p.text.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use crate::vm::{ErrorKind, Frame, Value, VmResult, VM};
+    use Value::{Float, Int};
+
+    fn try_run(s: &str, f: &str, args: &[Value]) -> VmResult<Value> {
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        VM::new(&p).call(p.func(f).unwrap(), args)
+    }
+
+    fn run(s: &str, f: &str, args: &[Value]) -> Value {
+        try_run(s, f, args).unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        let s = "
+        var int count;
+        fn int fib(int n) {
+            count = count + 1;
+            if n < 2 {
+                return n;
+            }
+            return fib(n - 1) + fib(n - 2);
+        }
+        fn int sum(int a, int b) {
+            var int i, s;
+            i = a;
+            while i <= b {
+                s = s + i;
+                i = i + 1;
+            }
+            return s;
+        }
+        fn int sign(int x) {
+            if x > 0 { return 1; } else if x == 0 { return 0; } else { return -1; }
+        }
+        fn int shadow(int x) {
+            var int y;
+            y = x;
+            { var int x; x = 7; y = y * x; }
+            return y - x % 4;
+        }
+        ";
+        assert_eq!(run(s, "fib", &[Int(10)]), Int(55));
+        assert_eq!(run(s, "sum", &[Int(1), Int(100)]), Int(5050));
+        assert_eq!(run(s, "sign", &[Int(-5)]), Int(-1));
+        assert_eq!(run(s, "sign", &[Int(0)]), Int(0));
+        assert_eq!(run(s, "shadow", &[Int(6)]), Int(40));
+
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        vm.call(p.func("fib").unwrap(), &[Int(5)]).unwrap();
+        assert_eq!(vm.global(p.global("count").unwrap()), 15);
+        assert!(p.dump(p.func("fib").unwrap()).contains("Call fib"));
+
+        let err = |s: &str| {
+            let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+                .parse()
+                .unwrap();
+            compile(&ast).unwrap_err()
+        };
+        assert_eq!(
+            err("fn int f(int a) { return f(); }"),
+            CompileError::Arity("f".to_owned(), 1, 0)
+        );
+        assert_eq!(
+            err("fn int f() { return g(); }"),
+            CompileError::Undefined("g".to_owned())
+        );
+        assert_eq!(
+            err("fn int f(int g) { return g(); }"),
+            CompileError::NotFunction("g".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_compile_float() {
+        let s = "
+        var float scale;
+        fn float area(float r) {
+            return 3.5 * r * r;
+        }
+        fn int trunc(float x) {
+            return x;
+        }
+        fn int bits(int x) {
+            return (x & 12 | 1) ^ 2 << 1 >> 1;
+        }
+        fn int misc() {
+            scale = 2;
+            if !(scale > 1.5) { return 0; }
+            return 10 - 4 - 3 + ~0 + trunc(area(scale) / 2.0);
+        }
+        ";
+        assert_eq!(run(s, "area", &[Int(2)]), Float(14.0));
+        assert_eq!(run(s, "trunc", &[Float(-2.75)]), Int(-2));
+        assert_eq!(run(s, "bits", &[Int(13)]), Int(15));
+        assert_eq!(run(s, "misc", &[]), Int(9));
+
+        let ast = Parser::new(DefaultLexer::new(
+            "fn int f() { return 1.5 & 1; }".as_bytes(),
+        ))
+        .parse()
+        .unwrap();
+        assert_eq!(
+            compile(&ast).unwrap_err().to_string(),
+            "operator BitAnd on float is not supported by the VM"
+        );
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let s = "fn int div(int a, int b) {
+            return a / b;
+        }
+        fn int check(int n) {
+            if n < 0 {
+                trap(n);
+            }
+            return div(100, n);
+        }";
+        let frame = |f: &str, line: u32| Frame {
+            func: f.to_owned(),
+            line: Some(line),
+        };
+        let err = try_run(s, "check", &[Int(0)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivByZero);
+        assert_eq!(err.backtrace, vec![frame("div", 2), frame("check", 8)]);
+        let err = try_run(s, "check", &[Int(-3)]).unwrap_err();
+        assert_eq!(err.to_string(), "trap -3\n    at check (line 6)");
+        assert_eq!(run(s, "check", &[Int(4)]), Int(25));
+        let err = try_run(s, "div", &[Int(1)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Arity(2, 1));
+    }
+
+    #[test]
+    fn test_recursion() {
+        let s = "fn int fib(int n) {
+            if n < 2 { return n; }
+            return fib(n - 1) + fib(n - 2);
+        }
+        fn int ack(int m, int n) {
+            if m == 0 { return n + 1; }
+            if n == 0 { return ack(m - 1, 1); }
+            return ack(m - 1, ack(m, n - 1));
+        }
+        fn int even(int n) {
+            if n == 0 { return 1; }
+            return odd(n - 1);
+        }
+        fn int odd(int n) {
+            if n == 0 { return 0; }
+            return even(n - 1);
+        }
+        fn float power(float x, int n) {
+            var float half;
+            if n == 0 { return 1; }
+            half = power(x, n / 2);
+            if n % 2 { return half * half * x; }
+            return half * half;
+        }";
+        assert_eq!(run(s, "fib", &[Int(20)]), Int(6765));
+        assert_eq!(run(s, "ack", &[Int(2), Int(3)]), Int(9));
+        assert_eq!(run(s, "ack", &[Int(3), Int(3)]), Int(61));
+        assert_eq!(run(s, "even", &[Int(1001)]), Int(0));
+        assert_eq!(run(s, "power", &[Float(1.5), Int(5)]), Float(7.59375));
+
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        vm.set_max_depth(100);
+        let even = p.func("even").unwrap();
+        assert_eq!(vm.call(even, &[Int(99)]), Ok(Int(0)));
+        let err = vm.call(even, &[Int(100)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CallDepth(100));
+        assert_eq!(err.backtrace.len(), crate::vm::MAX_BACKTRACE);
+        //出错之后还能继续调用
+        assert_eq!(vm.call(p.func("fib").unwrap(), &[Int(10)]), Ok(Int(55)));
+    }
+
+    #[test]
+    fn test_heap() {
+        let s = "var int keep;
+        fn int list(int n) {
+            var int head, cell, i, s;
+            while i < n {
+                cell = array(2);
+                set(cell, 0, i);
+                set(cell, 1, head);
+                head = cell;
+                array(3);
+                i = i + 1;
+            }
+            keep = head;
+            while head {
+                s = s + get(head, 0);
+                head = get(head, 1);
+            }
+            return s;
+        }
+        fn int nested() {
+            var int a;
+            a = array(2);
+            set(a, 0, array(5));
+            array(1);
+            return len(get(a, 0)) + len(a);
+        }
+        fn int oob(int i) {
+            return get(array(2), i);
+        }";
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        //每次分配都回收，栈上和全局变量中的引用都不能丢
+        vm.set_gc_stress(true);
+        assert_eq!(vm.call(p.func("list").unwrap(), &[Int(50)]), Ok(Int(1225)));
+        assert_eq!(vm.heap_stats().allocations, 100);
+        assert_eq!(vm.heap_stats().collections, 100);
+        vm.collect();
+        assert_eq!(vm.heap_stats().live, 50);
+        assert_eq!(vm.heap_stats().words, 100);
+        vm.set_global(p.global("keep").unwrap(), 0);
+        vm.collect();
+        assert_eq!(vm.heap_stats().live, 0);
+        assert_eq!(vm.call(p.func("nested").unwrap(), &[]), Ok(Int(7)));
+
+        let oob = p.func("oob").unwrap();
+        let err = vm.call(oob, &[Int(2)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfBounds(2, 2));
+        assert_eq!(
+            err.to_string(),
+            "index 2 out of bounds for length 2\n    at oob (line 27)"
+        );
+        let err = try_run("fn int f() { return array(-1); }", "f", &[]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NegativeSize(-1));
+        let ast = Parser::new(DefaultLexer::new(
+            "fn int f() { return get(1); }".as_bytes(),
+        ))
+        .parse()
+        .unwrap();
+        assert_eq!(
+            compile(&ast).unwrap_err(),
+            CompileError::Arity("get".to_owned(), 2, 1)
+        );
+    }
+}
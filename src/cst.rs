@@ -0,0 +1,472 @@
+use crate::lexer::Lexeme;
+use crate::lexer::Token;
+use crate::lexer::Trivia;
+use crate::lexer::TriviaKind;
+use std::fmt;
+use std::rc::Rc;
+
+//无损具体语法树（CST）。源码中的每个字符，包括空白、注释和出错的 token，
+//都按顺序保存在叶子 token 里，把所有叶子拼起来就是原始输入。
+//节点里不记录绝对位置，只记录长度，所以子树可以在增量解析时直接复用。
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyntaxKind {
+    // token
+    Whitespace,
+    LineComment,
+    BlockComment,
+    DocComment,
+    Keyword,
+    Ident,
+    Number,
+    Str,
+    Char,
+    Punct,
+    ErrorToken,
+    // node
+    Root,
+    VarDecl,
+    FnDecl,
+    ParamList,
+    Param,
+    Block,
+    AssignStmt,
+    ExprStmt,
+    ReturnStmt,
+    IfStmt,
+    WhileStmt,
+    BinaryExpr,
+    UnaryExpr,
+    ParenExpr,
+    IdentExpr,
+    Literal,
+    CallExpr,
+    ArgList,
+    Error,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::Whitespace
+                | SyntaxKind::LineComment
+                | SyntaxKind::BlockComment
+                | SyntaxKind::DocComment
+        )
+    }
+
+    fn from_token(tok: &Token) -> SyntaxKind {
+        match tok {
+            Token::KeyWord(_) => SyntaxKind::Keyword,
+            Token::Ident(_) => SyntaxKind::Ident,
+            Token::Number(_) | Token::Float(_) => SyntaxKind::Number,
+            Token::Str(_) => SyntaxKind::Str,
+            Token::Char(_) => SyntaxKind::Char,
+            Token::Oper(_) | Token::Aide(_) => SyntaxKind::Punct,
+            Token::Unknown | Token::Eof => SyntaxKind::ErrorToken,
+        }
+    }
+
+    fn from_trivia(kind: TriviaKind) -> SyntaxKind {
+        match kind {
+            TriviaKind::Whitespace => SyntaxKind::Whitespace,
+            TriviaKind::LineComment => SyntaxKind::LineComment,
+            TriviaKind::BlockComment => SyntaxKind::BlockComment,
+            TriviaKind::DocComment => SyntaxKind::DocComment,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(Rc<SyntaxNode>),
+    Token(SyntaxToken),
+}
+
+impl SyntaxElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(n) => n.kind,
+            SyntaxElement::Token(t) => t.kind,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SyntaxElement::Node(n) => n.len,
+            SyntaxElement::Token(t) => t.text.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub len: usize, //子树覆盖的源码字节数
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    pub fn new(kind: SyntaxKind, children: Vec<SyntaxElement>) -> SyntaxNode {
+        SyntaxNode {
+            kind,
+            len: children.iter().map(|c| c.len()).sum(),
+            children,
+        }
+    }
+
+    //还原这棵子树对应的源码
+    pub fn text(&self) -> String {
+        let mut s = String::with_capacity(self.len);
+        self.write_text(&mut s);
+        s
+    }
+
+    fn write_text(&self, s: &mut String) {
+        for c in self.children.iter() {
+            match c {
+                SyntaxElement::Node(n) => n.write_text(s),
+                SyntaxElement::Token(t) => s.push_str(&t.text),
+            }
+        }
+    }
+
+    pub fn child_nodes(&self) -> impl Iterator<Item = &Rc<SyntaxNode>> {
+        self.children.iter().filter_map(|c| match c {
+            SyntaxElement::Node(n) => Some(n),
+            _ => None,
+        })
+    }
+
+    //非 trivia 的直接子 token
+    pub fn child_tokens(&self) -> impl Iterator<Item = &SyntaxToken> {
+        self.children.iter().filter_map(|c| match c {
+            SyntaxElement::Token(t) if !t.kind.is_trivia() => Some(t),
+            _ => None,
+        })
+    }
+
+    //直接子元素及其起始偏移，start 是本节点的起始偏移
+    pub fn children_with_offset(&self, start: usize) -> Vec<(usize, &SyntaxElement)> {
+        let mut offset = start;
+        let mut list = Vec::with_capacity(self.children.len());
+        for c in self.children.iter() {
+            list.push((offset, c));
+            offset += c.len();
+        }
+        list
+    }
+
+    pub fn child_node(&self, kind: SyntaxKind) -> Option<&Rc<SyntaxNode>> {
+        self.child_nodes().find(|n| n.kind == kind)
+    }
+
+    pub fn child_token(&self, kind: SyntaxKind) -> Option<&SyntaxToken> {
+        self.child_tokens().find(|t| t.kind == kind)
+    }
+
+    //子树中第一个 token 之前的 `///` 注释
+    pub fn doc_comment(&self) -> Option<String> {
+        let mut lines: Vec<String> = Vec::new();
+        for c in self.children.iter() {
+            match c {
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::DocComment => {
+                    let trivia = Trivia {
+                        kind: TriviaKind::DocComment,
+                        text: t.text.clone(),
+                    };
+                    lines.push(trivia.doc_text().to_owned());
+                }
+                SyntaxElement::Token(t) if t.kind.is_trivia() => {
+                    if t.kind != SyntaxKind::Whitespace || t.text.matches('\n').count() > 1 {
+                        lines.clear();
+                    }
+                }
+                _ => break,
+            }
+        }
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+impl fmt::Debug for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.dump(f, 0)
+    }
+}
+
+impl fmt::Debug for SyntaxElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyntaxElement::Node(n) => n.fmt(f),
+            SyntaxElement::Token(t) => write!(f, "{:?} {:?}", t.kind, t.text),
+        }
+    }
+}
+
+impl SyntaxNode {
+    fn dump(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}{:?}@{}",
+            "",
+            self.kind,
+            self.len,
+            indent = indent
+        )?;
+        for c in self.children.iter() {
+            match c {
+                SyntaxElement::Node(n) => n.dump(f, indent + 2)?,
+                SyntaxElement::Token(t) => writeln!(
+                    f,
+                    "{:indent$}{:?} {:?}",
+                    "",
+                    t.kind,
+                    t.text,
+                    indent = indent + 2
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//自底向上构建 CST，解析器每消耗一个 token 就把它连同 trivia 交给 Builder
+pub struct Builder {
+    stack: Vec<(SyntaxKind, Vec<SyntaxElement>)>,
+    offset: usize, //已经放进树里的字节数
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            stack: vec![(SyntaxKind::Root, Vec::new())],
+            offset: 0,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn start_node(&mut self, kind: SyntaxKind) {
+        self.stack.push((kind, Vec::new()));
+    }
+
+    pub fn finish_node(&mut self) {
+        let (kind, children) = self.stack.pop().unwrap();
+        self.push(SyntaxElement::Node(Rc::new(SyntaxNode::new(
+            kind, children,
+        ))));
+    }
+
+    //记住当前位置，之后可以用 start_node_at 把这之后的元素包进一个新节点
+    pub fn checkpoint(&self) -> usize {
+        self.stack.last().unwrap().1.len()
+    }
+
+    pub fn start_node_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        let children = self.stack.last_mut().unwrap().1.split_off(checkpoint);
+        self.stack.push((kind, children));
+    }
+
+    pub fn token(&mut self, kind: SyntaxKind, text: String) {
+        self.offset += text.len();
+        self.push(SyntaxElement::Token(SyntaxToken { kind, text }));
+    }
+
+    pub fn node(&mut self, node: Rc<SyntaxNode>) {
+        self.offset += node.len;
+        self.push(SyntaxElement::Node(node));
+    }
+
+    pub fn trivia(&mut self, list: Vec<Trivia>) {
+        for t in list.into_iter() {
+            self.token(SyntaxKind::from_trivia(t.kind), t.text);
+        }
+    }
+
+    pub fn lexeme(&mut self, l: Lexeme) {
+        self.trivia(l.leading);
+        if l.tok != Token::Eof {
+            self.token(SyntaxKind::from_token(&l.tok), l.text);
+        }
+        self.trivia(l.trailing);
+    }
+
+    pub fn error_token(&mut self, l: Lexeme) {
+        self.trivia(l.leading);
+        self.token(SyntaxKind::ErrorToken, l.text);
+        self.trivia(l.trailing);
+    }
+
+    pub fn finish(mut self) -> Rc<SyntaxNode> {
+        while self.stack.len() > 1 {
+            self.finish_node();
+        }
+        let (kind, children) = self.stack.pop().unwrap();
+        Rc::new(SyntaxNode::new(kind, children))
+    }
+
+    fn push(&mut self, e: SyntaxElement) {
+        self.stack.last_mut().unwrap().1.push(e);
+    }
+}
+
+//在 CST 上的类型化视图，只是对节点的包装，按需取出字段
+pub trait AstNode<'a>: Sized {
+    fn cast(node: &'a SyntaxNode) -> Option<Self>;
+    fn syntax(&self) -> &'a SyntaxNode;
+}
+
+macro_rules! ast_node {
+    ($name:ident, $kind:ident) => {
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name<'a>(&'a SyntaxNode);
+
+        impl<'a> AstNode<'a> for $name<'a> {
+            fn cast(node: &'a SyntaxNode) -> Option<Self> {
+                if node.kind == SyntaxKind::$kind {
+                    Some($name(node))
+                } else {
+                    None
+                }
+            }
+
+            fn syntax(&self) -> &'a SyntaxNode {
+                self.0
+            }
+        }
+    };
+}
+
+ast_node!(SourceFile, Root);
+ast_node!(VarDecl, VarDecl);
+ast_node!(FnDecl, FnDecl);
+ast_node!(Param, Param);
+ast_node!(Block, Block);
+ast_node!(AssignStmt, AssignStmt);
+
+fn cast_children<'a, N: AstNode<'a>>(node: &'a SyntaxNode) -> Vec<N> {
+    node.child_nodes().filter_map(|n| N::cast(n)).collect()
+}
+
+fn type_name(node: &SyntaxNode) -> Option<&str> {
+    node.child_tokens()
+        .find(|t| t.kind == SyntaxKind::Keyword && (t.text == "int" || t.text == "float"))
+        .map(|t| t.text.as_str())
+}
+
+impl<'a> SourceFile<'a> {
+    pub fn globals(&self) -> Vec<VarDecl<'a>> {
+        cast_children(self.0)
+    }
+
+    pub fn funcs(&self) -> Vec<FnDecl<'a>> {
+        cast_children(self.0)
+    }
+}
+
+impl<'a> VarDecl<'a> {
+    pub fn doc(&self) -> Option<String> {
+        self.0.doc_comment()
+    }
+
+    pub fn type_name(&self) -> Option<&'a str> {
+        type_name(self.0)
+    }
+
+    pub fn names(&self) -> Vec<&'a str> {
+        self.0
+            .child_tokens()
+            .filter(|t| t.kind == SyntaxKind::Ident)
+            .map(|t| t.text.as_str())
+            .collect()
+    }
+}
+
+impl<'a> FnDecl<'a> {
+    pub fn doc(&self) -> Option<String> {
+        self.0.doc_comment()
+    }
+
+    pub fn ret_type(&self) -> Option<&'a str> {
+        type_name(self.0)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.0
+            .child_token(SyntaxKind::Ident)
+            .map(|t| t.text.as_str())
+    }
+
+    pub fn params(&self) -> Vec<Param<'a>> {
+        match self.0.child_node(SyntaxKind::ParamList) {
+            Some(list) => cast_children(list),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn body(&self) -> Option<Block<'a>> {
+        self.0
+            .child_node(SyntaxKind::Block)
+            .and_then(|n| Block::cast(n))
+    }
+}
+
+impl<'a> Param<'a> {
+    pub fn type_name(&self) -> Option<&'a str> {
+        type_name(self.0)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.0
+            .child_token(SyntaxKind::Ident)
+            .map(|t| t.text.as_str())
+    }
+}
+
+impl<'a> Block<'a> {
+    pub fn locals(&self) -> Vec<VarDecl<'a>> {
+        cast_children(self.0)
+    }
+
+    pub fn assigns(&self) -> Vec<AssignStmt<'a>> {
+        cast_children(self.0)
+    }
+}
+
+impl<'a> AssignStmt<'a> {
+    pub fn lhs(&self) -> Option<&'a SyntaxNode> {
+        self.0.child_nodes().next().map(|n| &**n)
+    }
+
+    pub fn rhs(&self) -> Option<&'a SyntaxNode> {
+        self.0.child_nodes().nth(1).map(|n| &**n)
+    }
+}
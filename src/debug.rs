@@ -0,0 +1,451 @@
+use crate::compiler::{self, type_name};
+use crate::lexer::{DefaultLexer, KeyWord};
+use crate::parser::Parser;
+use crate::repl::{expr_type, returned};
+use crate::vm::{line_at, Function, Global, Hook, Instruction, Local, Program, Value, VM};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+//源码级的调试器。作为虚拟机的钩子在每条语句开头检查断点和单步的状态，
+//停下来时从 input 一行一行读命令，结果写到 output。
+//参数和局部变量的名字、位置和作用域来自编译器生成的 Function::locals。
+//表达式和 REPL 一样放进临时函数编译执行，当前能看到的变量是它的全局变量。
+
+const HELP: &str = "break <line>|<fn>  stop at a line or at the first statement of a function (b)
+delete <n>         remove breakpoint n
+continue           run to the next breakpoint (c)
+step               run to the next line, entering calls (s)
+next               run to the next line, stepping over calls (n)
+finish             run until the current function returns (f)
+print <expr>       evaluate an expression (p)
+locals             show the parameters and local variables
+bt                 show the call stack
+watch <expr>       print an expression at every stop
+unwatch <n>        remove watch n
+quit               stop the program (q)";
+
+//表达式放进一个临时函数里编译
+const EXPR_FN: &str = "__debug";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Break {
+    Line(u32),
+    Func(String),
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Break::Line(n) => write!(f, "line {}", n),
+            Break::Func(name) => write!(f, "`{}`", name),
+        }
+    }
+}
+
+//停下来之后执行到哪里
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Continue,
+    Step,          //下一行，包括进入调用的函数
+    Next(usize),   //调用层数不超过它的下一行
+    Finish(usize), //调用层数小于它的第一条语句，也就是回到调用者
+}
+
+pub struct Debugger {
+    funcs: Vec<Function>,
+    globals: Vec<Global>,
+    lines: Vec<(usize, u32)>,
+    stmts: Vec<(usize, u32)>, //语句开头的位置和行号，不含函数入口的 Ent
+    source: Vec<String>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    breaks: Vec<Option<Break>>, //编号是下标加一，删除的留空
+    watches: Vec<Option<String>>,
+    mode: Mode,
+    last: Option<(u32, usize, usize)>, //上一次经过的语句：行号、调用层数、位置
+}
+
+impl Debugger {
+    //source 是程序的源码，停下来时显示当前行，没有时只显示行号。
+    //一开始是单步的状态，停在第一条语句上
+    pub fn new(
+        p: &Program,
+        source: Option<&str>,
+        input: Box<dyn BufRead>,
+        output: Box<dyn Write>,
+    ) -> Debugger {
+        let stmts = p
+            .lines
+            .iter()
+            .filter(|(pc, _)| p.text.get(*pc) != Some(&(Instruction::Ent as u64)))
+            .copied()
+            .collect();
+        Debugger {
+            funcs: p.funcs.clone(),
+            globals: p.globals.clone(),
+            lines: p.lines.clone(),
+            stmts,
+            source: source.map_or(Vec::new(), |s| s.lines().map(|l| l.to_owned()).collect()),
+            input,
+            output,
+            breaks: Vec::new(),
+            watches: Vec::new(),
+            mode: Mode::Step,
+            last: None,
+        }
+    }
+
+    //设置断点，返回编号。行上没有语句或者没有这个函数时报错
+    pub fn add_break(&mut self, b: Break) -> Result<usize, String> {
+        match &b {
+            Break::Line(n) if !self.stmts.iter().any(|(_, line)| line == n) => {
+                return Err(format!("no code at line {}", n))
+            }
+            Break::Func(name) if self.first_stmt(name).is_none() => {
+                return Err(format!("no function `{}` with statements", name))
+            }
+            _ => (),
+        }
+        self.breaks.push(Some(b));
+        Ok(self.breaks.len())
+    }
+
+    pub fn add_watch(&mut self, expr: &str) -> usize {
+        self.watches.push(Some(expr.to_owned()));
+        self.watches.len()
+    }
+
+    //函数第一条语句的位置，函数的断点停在这里，这时调用帧已经建好
+    fn first_stmt(&self, name: &str) -> Option<usize> {
+        let f = self.funcs.iter().find(|f| f.name == name)?;
+        self.stmts
+            .iter()
+            .map(|(pc, _)| *pc)
+            .find(|pc| f.entry < *pc && *pc < f.end)
+    }
+
+    //pc 处能看到的参数和局部变量，后声明的在后面
+    fn locals(&self, pc: usize) -> Vec<&Local> {
+        match self.funcs.iter().find(|f| f.entry <= pc && pc < f.end) {
+            Some(f) => f
+                .locals
+                .iter()
+                .filter(|l| l.start <= pc && pc < l.end)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    //和 REPL 一样把表达式放进临时函数编译，在另一个虚拟机上执行。
+    //全局变量和能看到的局部变量都声明成临时程序的全局变量，同名时局部变量遮住前面的
+    pub fn eval(&self, vm: &VM, expr: &str) -> Result<Value, String> {
+        if expr.is_empty() {
+            return Err("expected an expression".to_owned());
+        }
+        let mut vars: Vec<(&str, KeyWord, u64)> = Vec::new();
+        for (i, g) in self.globals.iter().enumerate() {
+            vars.push((&g.name, g.typ, vm.global(i)));
+        }
+        for l in self.locals(vm.pc()) {
+            if let Some(bits) = vm.local(l.slot) {
+                vars.retain(|v| v.0 != l.name);
+                vars.push((&l.name, l.typ, bits));
+            }
+        }
+        let decls: String = vars
+            .iter()
+            .map(|(name, typ, _)| format!("var {} {};\n", type_name(*typ), name))
+            .collect();
+        let parse = |typ: KeyWord| {
+            let src = format!(
+                "{}fn {} {}() {{\nreturn {}\n;\n}}\n",
+                decls,
+                type_name(typ),
+                EXPR_FN,
+                expr
+            );
+            Parser::new(DefaultLexer::new(src.as_bytes()))
+                .parse()
+                .map_err(|e| e.to_string())
+        };
+        let mut ast = parse(KeyWord::Int)?;
+        let typ = match returned(&ast) {
+            Some(x) => expr_type(&ast, x)?,
+            None => return Err("expected an expression".to_owned()),
+        };
+        if typ == KeyWord::Float {
+            ast = parse(KeyWord::Float)?;
+        }
+        let p = compiler::compile(&ast).map_err(|e| e.to_string())?;
+        let mut eval = VM::new(&p);
+        for (name, _, bits) in vars.iter() {
+            eval.set_global(p.global(name).unwrap(), *bits);
+        }
+        eval.call(p.func(EXPR_FN).unwrap(), &[])
+            .map_err(|e| e.kind.to_string())
+    }
+
+    fn say(&mut self, s: &str) {
+        let _ = writeln!(self.output, "{}", s);
+    }
+
+    //停下来时显示位置和监视的表达式
+    fn show(&mut self, vm: &VM, hit: Option<usize>, line: u32) {
+        let frame = &vm.backtrace()[0];
+        match hit {
+            Some(id) => self.say(&format!("breakpoint {}, {}", id, frame)),
+            None => self.say(&frame.to_string()),
+        }
+        let text = (line as usize)
+            .checked_sub(1)
+            .and_then(|i| self.source.get(i));
+        if let Some(text) = text.cloned() {
+            self.say(&format!("{:>5}  {}", line, text));
+        }
+        for (i, w) in self.watches.clone().iter().enumerate() {
+            if let Some(expr) = w {
+                match self.eval(vm, expr) {
+                    Ok(v) => self.say(&format!("{}: {} = {}", i + 1, expr, v)),
+                    Err(e) => self.say(&format!("{}: {}: error: {}", i + 1, expr, e)),
+                }
+            }
+        }
+    }
+
+    //执行一条命令。返回 Some(true) 继续执行，Some(false) 中止程序，None 等下一条命令
+    fn command(&mut self, vm: &VM, line: &str) -> Result<Option<bool>, String> {
+        let (cmd, arg) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let number = || {
+            arg.parse::<usize>()
+                .map_err(|_| format!("expected a number but found `{}`", arg))
+        };
+        match cmd {
+            "break" | "b" => {
+                let b = match arg.parse() {
+                    Ok(n) => Break::Line(n),
+                    Err(_) if !arg.is_empty() => Break::Func(arg.to_owned()),
+                    Err(_) => return Err("expected a line or a function".to_owned()),
+                };
+                let id = self.add_break(b.clone())?;
+                self.say(&format!("breakpoint {} at {}", id, b));
+            }
+            "delete" | "d" => match self.breaks.get_mut(number()?.wrapping_sub(1)) {
+                Some(b @ Some(_)) => *b = None,
+                _ => return Err(format!("no breakpoint {}", arg)),
+            },
+            "continue" | "c" => {
+                self.mode = Mode::Continue;
+                return Ok(Some(true));
+            }
+            "step" | "s" => {
+                self.mode = Mode::Step;
+                return Ok(Some(true));
+            }
+            "next" | "n" => {
+                self.mode = Mode::Next(vm.depth());
+                return Ok(Some(true));
+            }
+            "finish" | "f" => {
+                self.mode = Mode::Finish(vm.depth());
+                return Ok(Some(true));
+            }
+            "print" | "p" => {
+                let v = self.eval(vm, arg)?;
+                self.say(&v.to_string());
+            }
+            "locals" => {
+                let locals: Vec<String> = self
+                    .locals(vm.pc())
+                    .iter()
+                    .map(|l| match vm.local(l.slot) {
+                        Some(bits) => format!("{} = {}", l.name, Value::from_bits(bits, l.typ)),
+                        None => format!("{} = ?", l.name),
+                    })
+                    .collect();
+                for l in locals.iter() {
+                    self.say(l);
+                }
+            }
+            "bt" | "backtrace" => {
+                for (i, frame) in vm.backtrace().iter().enumerate() {
+                    self.say(&format!("#{} {}", i, frame));
+                }
+            }
+            "watch" | "w" => {
+                if arg.is_empty() {
+                    return Err("expected an expression".to_owned());
+                }
+                let id = self.add_watch(arg);
+                self.say(&format!("watch {}: {}", id, arg));
+            }
+            "unwatch" => match self.watches.get_mut(number()?.wrapping_sub(1)) {
+                Some(w @ Some(_)) => *w = None,
+                _ => return Err(format!("no watch {}", arg)),
+            },
+            "quit" | "q" => return Ok(Some(false)),
+            "help" | "h" => self.say(HELP),
+            _ => return Err(format!("unknown command `{}`, try help", cmd)),
+        }
+        Ok(None)
+    }
+}
+
+impl Hook for Debugger {
+    fn statement(&mut self, vm: &VM) -> bool {
+        let pc = vm.pc();
+        let depth = vm.depth();
+        let line = line_at(&self.lines, pc).unwrap_or(0);
+        //同一行上的后续语句不算新的一行，跳回来的循环算
+        let new_line = match self.last {
+            Some((l, d, at)) => l != line || d != depth || pc <= at,
+            None => true,
+        };
+        self.last = Some((line, depth, pc));
+        let hit = self.breaks.iter().position(|b| match b {
+            Some(Break::Line(n)) => *n == line && new_line,
+            Some(Break::Func(name)) => self.first_stmt(name) == Some(pc),
+            None => false,
+        });
+        let stop = hit.is_some()
+            || match self.mode {
+                Mode::Continue => false,
+                Mode::Step => new_line,
+                Mode::Next(d) => new_line && depth <= d,
+                Mode::Finish(d) => depth < d,
+            };
+        if !stop {
+            return true;
+        }
+        self.show(vm, hit.map(|i| i + 1), line);
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => (),
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match self.command(vm, line) {
+                Ok(Some(go)) => return go,
+                Ok(None) => (),
+                Err(e) => self.say(&format!("error: {}", e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::ErrorKind;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    //把输出留在内存里
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    //按 commands 调试 s 的 main，返回结果和去掉提示符的输出
+    fn debug(s: &str, commands: &str) -> (Result<Value, ErrorKind>, String) {
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compiler::compile(&ast).unwrap();
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let input = io::Cursor::new(commands.as_bytes().to_vec());
+        let dbg = Debugger::new(&p, Some(s), Box::new(input), Box::new(Capture(out.clone())));
+        let mut vm = VM::new(&p);
+        vm.set_hook(Box::new(dbg));
+        let v = vm.call(p.func("main").unwrap(), &[]).map_err(|e| e.kind);
+        let out = String::from_utf8(out.borrow().clone()).unwrap();
+        (v, out.replace("(debug) ", ""))
+    }
+
+    #[test]
+    fn test_debugger() {
+        let s = "var int total;
+fn int add(int a, int b) {
+    var int c;
+    c = a + b;
+    return c;
+}
+fn int main() {
+    var int i;
+    i = 0;
+    while i < 3 {
+        total = add(total, i);
+        i = i + 1;
+    }
+    return total;
+}
+";
+        //函数断点停在第一条语句，step 进入调用，finish 回到调用者
+        let (v, out) = debug(s, "b add\nc\np a * 10 + b\nlocals\nbt\nfinish\nquit\n");
+        assert_eq!(v, Err(ErrorKind::Stopped));
+        assert_eq!(
+            out,
+            "main (line 8)
+    8      var int i;
+breakpoint 1 at `add`
+breakpoint 1, add (line 3)
+    3      var int c;
+0
+a = 0
+b = 0
+c = 0
+#0 add (line 3)
+#1 main (line 11)
+main (line 12)
+   12          i = i + 1;
+"
+        );
+
+        //行断点每次循环都停，监视表达式在停下时打印，next 跳过调用
+        let (v, out) = debug(s, "b 11\nwatch total * 2\nc\nc\nd 1\nn\nn\nc\n");
+        assert_eq!(v, Ok(Value::Int(3)));
+        assert_eq!(
+            out,
+            "main (line 8)
+    8      var int i;
+breakpoint 1 at line 11
+watch 1: total * 2
+breakpoint 1, main (line 11)
+   11          total = add(total, i);
+1: total * 2 = 0
+breakpoint 1, main (line 11)
+   11          total = add(total, i);
+1: total * 2 = 0
+main (line 12)
+   12          i = i + 1;
+1: total * 2 = 2
+main (line 10)
+   10      while i < 3 {
+1: total * 2 = 2
+"
+        );
+
+        let (_, out) = debug(s, "b 1\nb nothing\np missing\nfoo\nstep\nstep\nstep\nq\n");
+        assert!(out.contains("error: no code at line 1"));
+        assert!(out.contains("error: no function `nothing` with statements"));
+        assert!(out.contains("error: unknown command `foo`, try help"));
+        assert!(out.contains("main (line 11)"));
+    }
+}
@@ -0,0 +1,524 @@
+use crate::compiler::{self, CompileError};
+use crate::lexer::{DefaultLexer, KeyWord};
+use crate::natives::{HostFn, Signature};
+use crate::parser::{line_col, Parser};
+use crate::vm::{Limits, Program, Value, VmError, VM};
+use std::fmt;
+use std::rc::Rc;
+
+//把 tars 嵌入 Rust 程序的接口。宿主先用 register 注册 Rust 函数，再 compile 源码，
+//之后按名字调用 tars 函数、读写全局变量。参数和结果都是 Value 或者
+//i64、f64、bool 这样的 Rust 类型，不需要接触虚拟机的内存和地址。
+//tars 代码里调用注册的函数和调用普通函数一样，参数按签名转换类型。
+//
+//    let mut engine = Engine::new();
+//    engine.register("hypot", |x: f64, y: f64| (x * x + y * y).sqrt());
+//    engine.compile("fn float f(float a) { return hypot(a, 4); }")?;
+//    let v = engine.call("f", &[Value::Float(3.0)])?;
+
+#[derive(Debug, PartialEq)]
+pub enum EngineError {
+    Syntax(Vec<String>), //每条是 `行:列: 原因`
+    Compile(CompileError),
+    NotCompiled,       //还没有成功 compile 过
+    Undefined(String), //没有这个名字的函数或全局变量
+    Runtime(VmError),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Syntax(errors) => write!(f, "{}", errors.join("\n")),
+            EngineError::Compile(e) => write!(f, "{}", e),
+            EngineError::NotCompiled => write!(f, "no program has been compiled"),
+            EngineError::Undefined(name) => write!(f, "undefined name `{}`", name),
+            EngineError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
+
+//可以作为宿主函数参数和返回值的 Rust 类型
+pub trait HostValue: Sized {
+    const TYPE: KeyWord;
+    fn from_value(v: Value) -> Self;
+    fn into_value(self) -> Value;
+}
+
+impl HostValue for i64 {
+    const TYPE: KeyWord = KeyWord::Int;
+    fn from_value(v: Value) -> i64 {
+        match v {
+            Value::Float(n) => n as i64,
+            v => v.bits() as i64,
+        }
+    }
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl HostValue for f64 {
+    const TYPE: KeyWord = KeyWord::Float;
+    fn from_value(v: Value) -> f64 {
+        match v {
+            Value::Float(n) => n,
+            v => v.bits() as i64 as f64,
+        }
+    }
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+}
+
+//tars 里是 int，不等于 0 为真
+impl HostValue for bool {
+    const TYPE: KeyWord = KeyWord::Int;
+    fn from_value(v: Value) -> bool {
+        i64::from_value(v) != 0
+    }
+    fn into_value(self) -> Value {
+        Value::Int(self as i64)
+    }
+}
+
+//没有返回值的函数在 tars 里返回 int 0
+impl HostValue for () {
+    const TYPE: KeyWord = KeyWord::Int;
+    fn from_value(_: Value) {}
+    fn into_value(self) -> Value {
+        Value::Int(0)
+    }
+}
+
+//宿主函数的返回值，返回 Err 时中止 tars 代码的执行
+pub trait HostReturn {
+    const TYPE: KeyWord;
+    fn into_result(self) -> Result<Value, String>;
+}
+
+impl<T: HostValue> HostReturn for T {
+    const TYPE: KeyWord = T::TYPE;
+    fn into_result(self) -> Result<Value, String> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: HostValue> HostReturn for Result<T, String> {
+    const TYPE: KeyWord = T::TYPE;
+    fn into_result(self) -> Result<Value, String> {
+        self.map(|v| v.into_value())
+    }
+}
+
+//参数和返回值都是 HostValue 的闭包，签名从类型推出来。Args 是参数类型的元组
+pub trait IntoHost<Args> {
+    fn into_host(self) -> (Vec<KeyWord>, KeyWord, HostFn);
+}
+
+macro_rules! impl_into_host {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> IntoHost<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: HostReturn,
+            $($arg: HostValue,)*
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn into_host(self) -> (Vec<KeyWord>, KeyWord, HostFn) {
+                let f = move |args: &[Value]| {
+                    let mut args = args.iter();
+                    self($(<$arg>::from_value(*args.next().unwrap())),*).into_result()
+                };
+                (vec![$(<$arg>::TYPE),*], R::TYPE, Rc::new(f))
+            }
+        }
+    };
+}
+
+impl_into_host!();
+impl_into_host!(A);
+impl_into_host!(A, B);
+impl_into_host!(A, B, C);
+impl_into_host!(A, B, C, D);
+impl_into_host!(A, B, C, D, E);
+impl_into_host!(A, B, C, D, E, G);
+
+pub struct Engine {
+    hosts: Vec<(Signature, HostFn)>,
+    limits: Limits,
+    program: Option<Program>,
+    vm: Option<VM>,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine {
+            hosts: Vec::new(),
+            limits: Limits::default(),
+            program: None,
+            vm: None,
+        }
+    }
+
+    //注册宿主函数，同名的替换之前的。下一次 compile 之后生效
+    pub fn register<Args, F: IntoHost<Args>>(&mut self, name: &str, f: F) {
+        let (params, ret, f) = f.into_host();
+        self.add(name, params, ret, f);
+    }
+
+    //参数和返回值是 Value 的版本，签名单独给出。参数已经按 params 转换好，
+    //返回值会转成 ret
+    pub fn register_raw<F>(&mut self, name: &str, params: &[KeyWord], ret: KeyWord, f: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.add(name, params.to_vec(), ret, Rc::new(f));
+    }
+
+    fn add(&mut self, name: &str, params: Vec<KeyWord>, ret: KeyWord, f: HostFn) {
+        let sig = Signature {
+            name: name.to_owned(),
+            params,
+            ret,
+        };
+        match self.hosts.iter_mut().find(|(s, _)| s.name == name) {
+            Some(h) => *h = (sig, f),
+            None => self.hosts.push((sig, f)),
+        }
+    }
+
+    //编译源码，替换之前的程序。全局变量重新从 0 开始
+    pub fn compile(&mut self, src: &str) -> EngineResult<()> {
+        let ast = Parser::new(DefaultLexer::new(src.as_bytes()))
+            .parse_all()
+            .map_err(|errors| {
+                let errors = errors
+                    .iter()
+                    .map(|e| {
+                        let (line, col) = line_col(src, e.offset);
+                        format!("{}:{}: {}", line, col, e.err)
+                    })
+                    .collect();
+                EngineError::Syntax(errors)
+            })?;
+        let sigs: Vec<Signature> = self.hosts.iter().map(|(s, _)| s.clone()).collect();
+        let program = compiler::compile_with(&ast, &sigs).map_err(EngineError::Compile)?;
+        let mut vm = VM::new(&program);
+        for (s, f) in self.hosts.iter() {
+            vm.bind(&s.name, f.clone());
+        }
+        vm.set_limits(self.limits.clone());
+        self.program = Some(program);
+        self.vm = Some(vm);
+        Ok(())
+    }
+
+    //调用 tars 函数 name，参数转成声明的类型
+    pub fn call(&mut self, name: &str, args: &[Value]) -> EngineResult<Value> {
+        let (program, vm) = self.compiled()?;
+        let f = program
+            .func(name)
+            .ok_or_else(|| EngineError::Undefined(name.to_owned()))?;
+        vm.call(f, args).map_err(EngineError::Runtime)
+    }
+
+    //燃料用完或者超时的调用从停下的地方继续，见 VmError::is_resumable
+    pub fn resume(&mut self) -> EngineResult<Value> {
+        let (_, vm) = self.compiled()?;
+        vm.resume().map_err(EngineError::Runtime)
+    }
+
+    //执行的限制，对已经编译的程序和之后编译的程序都有效
+    pub fn set_limits(&mut self, limits: Limits) {
+        if let Some(vm) = &mut self.vm {
+            vm.set_limits(limits.clone());
+        }
+        self.limits = limits;
+    }
+
+    //全局变量的值，按声明的类型解释
+    pub fn global(&mut self, name: &str) -> EngineResult<Value> {
+        let (program, vm) = self.compiled()?;
+        let i = program
+            .global(name)
+            .ok_or_else(|| EngineError::Undefined(name.to_owned()))?;
+        Ok(Value::from_bits(vm.global(i), program.globals[i].typ))
+    }
+
+    pub fn set_global(&mut self, name: &str, v: Value) -> EngineResult<()> {
+        let (program, vm) = self.compiled()?;
+        let i = program
+            .global(name)
+            .ok_or_else(|| EngineError::Undefined(name.to_owned()))?;
+        vm.set_global(i, v.to_bits(program.globals[i].typ));
+        Ok(())
+    }
+
+    //编译好的程序所在的虚拟机，用来设置调用层数上限、替换输入输出等
+    pub fn vm(&mut self) -> Option<&mut VM> {
+        self.vm.as_mut()
+    }
+
+    fn compiled(&mut self) -> EngineResult<(&Program, &mut VM)> {
+        match (&self.program, &mut self.vm) {
+            (Some(p), Some(vm)) => Ok((p, vm)),
+            _ => Err(EngineError::NotCompiled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{ErrorKind, MAX_ALLOC};
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_engine() {
+        let mut engine = Engine::new();
+        assert!(matches!(
+            engine.call("main", &[]),
+            Err(EngineError::NotCompiled)
+        ));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let l = log.clone();
+        engine.register("hypot", |x: f64, y: f64| (x * x + y * y).sqrt());
+        engine.register("log", move |n: i64| l.borrow_mut().push(n));
+        engine.register("inside", |x: i64, lo: i64, hi: i64| lo <= x && x <= hi);
+        engine.register("checked", |n: i64| {
+            if n < 0 {
+                Err(format!("{} is negative", n))
+            } else {
+                Ok(n * 2)
+            }
+        });
+        engine.register_raw("first", &[KeyWord::Float], KeyWord::Int, |args| Ok(args[0]));
+        let src = "var int calls;
+
+fn float dist(float x, int y) {
+    calls = calls + 1;
+    log(y);
+    return hypot(x, y);
+}
+
+fn int count(int n) {
+    var int i, k;
+    i = 0;
+    while i < n {
+        k = k + inside(i, 3, 5);
+        i = i + 1;
+    }
+    return k + first(2.7);
+}
+
+fn int twice(int n) {
+    return checked(n);
+}";
+        engine.compile(src).unwrap();
+        assert_eq!(
+            engine.call("dist", &[Value::Int(3), Value::Float(4.9)]),
+            Ok(Value::Float(5.0))
+        );
+        engine.set_global("calls", Value::Int(10)).unwrap();
+        engine
+            .call("dist", &[Value::Float(6.0), Value::Int(8)])
+            .unwrap();
+        assert_eq!(engine.global("calls").unwrap(), Value::Int(11));
+        assert_eq!(*log.borrow(), vec![4, 8]);
+        assert_eq!(engine.call("count", &[Value::Int(10)]), Ok(Value::Int(5)));
+        assert_eq!(engine.call("twice", &[Value::Int(21)]), Ok(Value::Int(42)));
+        match engine.call("twice", &[Value::Int(-1)]) {
+            Err(EngineError::Runtime(e)) => {
+                assert_eq!(
+                    e.kind,
+                    ErrorKind::Native("checked".to_owned(), "-1 is negative".to_owned())
+                );
+                assert_eq!(e.backtrace[0].func, "twice");
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            engine.call("nope", &[]),
+            Err(EngineError::Undefined(_))
+        ));
+
+        //调用宿主函数时也检查参数个数
+        match engine.compile("fn int main() {\n    return hypot(1);\n}") {
+            Err(EngineError::Compile(e)) => {
+                assert_eq!(e, CompileError::Arity("hypot".to_owned(), 2, 1))
+            }
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        match engine.compile("fn int main() {\n    return 1 +;\n}") {
+            Err(EngineError::Syntax(errors)) => assert!(errors[0].starts_with("2:")),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        //tars 里的同名函数优先
+        engine
+            .compile("fn int log(int n) { return n + 1; }\nfn int main() { return log(1); }")
+            .unwrap();
+        assert_eq!(engine.call("main", &[]), Ok(Value::Int(2)));
+        assert_eq!(*log.borrow(), vec![4, 8]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut engine = Engine::new();
+        engine.set_limits(Limits {
+            fuel: Some(1000),
+            memory: Some(64),
+            natives: Some(vec!["malloc".to_owned()]),
+            ..Limits::default()
+        });
+        let src = "var int n;
+
+fn int spin() {
+    while 1 {
+        n = n + 1;
+    }
+    return 0;
+}
+
+fn int sum(int k) {
+    var int i, s;
+    i = 0;
+    while i < k {
+        s = s + i;
+        i = i + 1;
+    }
+    return s;
+}
+
+fn int alloc(int size) {
+    return malloc(size) != 0;
+}
+
+fn int print() {
+    return printf(\"hi\\n\");
+}";
+        engine.compile(src).unwrap();
+        //每次 resume 都有新的燃料，结果和一次执行完相同
+        let mut r = engine.call("sum", &[Value::Int(1000)]);
+        let mut slices = 1;
+        while let Err(EngineError::Runtime(e)) = &r {
+            assert_eq!(e.kind, ErrorKind::OutOfFuel);
+            assert!(e.is_resumable());
+            r = engine.resume();
+            slices += 1;
+        }
+        assert_eq!(r, Ok(Value::Int(499500)));
+        assert!(slices > 10);
+        match engine.call("spin", &[]) {
+            Err(EngineError::Runtime(e)) => assert_eq!(e.kind, ErrorKind::OutOfFuel),
+            r => panic!("unexpected {:?}", r),
+        }
+        let n = engine.global("n").unwrap();
+        engine.resume().unwrap_err();
+        assert!(engine.global("n").unwrap() != n);
+
+        assert_eq!(engine.call("alloc", &[Value::Int(40)]), Ok(Value::Int(1)));
+        match engine.call("alloc", &[Value::Int(40)]) {
+            Err(EngineError::Runtime(e)) => {
+                assert_eq!(e.kind, ErrorKind::MemoryLimit(64));
+                assert!(!e.is_resumable());
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        match engine.call("print", &[]) {
+            Err(EngineError::Runtime(e)) => {
+                assert_eq!(e.kind, ErrorKind::Forbidden("printf".to_owned()))
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        match engine.resume() {
+            Err(EngineError::Runtime(e)) => assert_eq!(e.kind, ErrorKind::NotSuspended),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        //超时的调用同样可以继续
+        engine.set_limits(Limits {
+            deadline: Some(std::time::Duration::from_millis(1)),
+            ..Limits::default()
+        });
+        match engine.call("spin", &[]) {
+            Err(EngineError::Runtime(e)) => assert_eq!(e.kind, ErrorKind::Deadline),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            engine.resume(),
+            Err(EngineError::Runtime(VmError {
+                kind: ErrorKind::Deadline,
+                ..
+            }))
+        ));
+
+        //没有 memory 限制时，超大的分配也在宿主分配内存之前停下
+        engine.set_limits(Limits::default());
+        engine
+            .compile(
+                "fn int hostile(int k) {
+    var int p;
+    if k == 0 { p = malloc(1 << 62); }
+    if k == 1 { p = array(1 << 40); }
+    if k == 2 { p = malloc(8); memset(p, 0, 1 << 40); }
+    return p;
+}",
+            )
+            .unwrap();
+        for k in 0..2 {
+            match engine.call("hostile", &[Value::Int(k)]) {
+                Err(EngineError::Runtime(e)) => {
+                    assert_eq!(e.kind, ErrorKind::MemoryLimit(MAX_ALLOC))
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        match engine.call("hostile", &[Value::Int(2)]) {
+            Err(EngineError::Runtime(e)) => {
+                assert!(matches!(e.kind, ErrorKind::InvalidMemory(_)))
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+
+        //反复分配释放越来越大的块，内存不会一直增长
+        engine.set_limits(Limits {
+            memory: Some(200000),
+            ..Limits::default()
+        });
+        engine
+            .compile(
+                "fn int churn(int n) {
+    var int i, p;
+    i = 1;
+    while i <= n {
+        p = malloc(i * 1000);
+        free(p);
+        i = i + 1;
+    }
+    return 0;
+}
+
+fn int merge() {
+    var int a, b, c;
+    a = malloc(16);
+    b = malloc(16);
+    c = malloc(8);
+    free(a);
+    free(b);
+    return malloc(32) == a;
+}",
+            )
+            .unwrap();
+        assert_eq!(engine.call("churn", &[Value::Int(200)]), Ok(Value::Int(0)));
+        //相邻的空闲块合并之后可以分给更大的请求
+        assert_eq!(engine.call("merge", &[]), Ok(Value::Int(1)));
+    }
+}
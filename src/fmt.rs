@@ -0,0 +1,262 @@
+use crate::cst::SyntaxElement;
+use crate::cst::SyntaxKind;
+use crate::cst::SyntaxNode;
+use crate::cst::SyntaxToken;
+use crate::lexer::DefaultLexer;
+use crate::parser::Parser;
+use crate::parser::SyntaxError;
+
+//代码格式化。在无损 CST 上逐个 token 重新输出：统一缩进，运算符两边加空格，
+//每条语句一行，注释原样保留，连续空行最多保留一行。有语法错误的文件不格式化。
+
+#[derive(Debug, Clone)]
+pub struct FmtOptions {
+    pub indent: usize,         //每层缩进的空格数
+    pub split_var_decls: bool, //`var int a, b;` 拆成每行一个声明
+}
+
+impl Default for FmtOptions {
+    fn default() -> FmtOptions {
+        FmtOptions {
+            indent: 4,
+            split_var_decls: false,
+        }
+    }
+}
+
+pub fn format(src: &str, opts: &FmtOptions) -> Result<String, Vec<SyntaxError>> {
+    let (tree, errors) = Parser::new(DefaultLexer::new(src.as_bytes())).parse_tree();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut items = Vec::new();
+    flatten(&tree, SyntaxKind::Root, opts, &mut items);
+    let mut w = Writer {
+        opts,
+        out: String::new(),
+        depth: 0,
+        newlines: 0,
+        prev: None,
+        need_line: false,
+        after_fn: false,
+    };
+    for item in items.iter() {
+        w.item(item);
+    }
+    if !w.out.is_empty() {
+        w.out.push('\n');
+    }
+    Ok(w.out)
+}
+
+//展开后的 token，parent 是它所在的节点类型
+struct Item {
+    kind: SyntaxKind,
+    text: String,
+    parent: SyntaxKind,
+    top: bool, //顶层声明的最后一个 token
+}
+
+fn flatten(node: &SyntaxNode, parent: SyntaxKind, opts: &FmtOptions, items: &mut Vec<Item>) {
+    if opts.split_var_decls && node.kind == SyntaxKind::VarDecl && split_var(node, items) {
+        return;
+    }
+    let start = items.len();
+    for c in node.children.iter() {
+        match c {
+            SyntaxElement::Node(n) => flatten(n, node.kind, opts, items),
+            SyntaxElement::Token(t) => items.push(item(t, node.kind)),
+        }
+    }
+    if parent == SyntaxKind::Root {
+        if let Some(last) = items[start..]
+            .iter_mut()
+            .rev()
+            .find(|i| i.kind != SyntaxKind::Whitespace && !i.kind.is_trivia())
+        {
+            last.top = node.kind == SyntaxKind::FnDecl;
+        }
+    }
+}
+
+fn item(t: &SyntaxToken, parent: SyntaxKind) -> Item {
+    Item {
+        kind: t.kind,
+        text: t.text.clone(),
+        parent,
+        top: false,
+    }
+}
+
+//多个名字的 var 声明拆成多条，名字之间有注释时不拆
+fn split_var(node: &SyntaxNode, items: &mut Vec<Item>) -> bool {
+    let code: Vec<usize> = node
+        .children
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !c.kind().is_trivia())
+        .map(|(i, _)| i)
+        .collect();
+    let (first, last) = (code[0], code[code.len() - 1]);
+    let names: Vec<&SyntaxToken> = node
+        .child_tokens()
+        .filter(|t| t.kind == SyntaxKind::Ident)
+        .collect();
+    let inner_comment = node.children[first..last]
+        .iter()
+        .any(|c| c.kind().is_trivia() && c.kind() != SyntaxKind::Whitespace);
+    if names.len() < 2 || inner_comment {
+        return false;
+    }
+    let tokens: Vec<&SyntaxToken> = node.child_tokens().collect();
+    let (var, typ) = (tokens[0], tokens[1]);
+    let push_trivia = |items: &mut Vec<Item>, list: &[SyntaxElement]| {
+        for c in list.iter() {
+            if let SyntaxElement::Token(t) = c {
+                items.push(item(t, SyntaxKind::VarDecl));
+            }
+        }
+    };
+    push_trivia(items, &node.children[..first]);
+    for name in names.iter() {
+        items.push(item(var, SyntaxKind::VarDecl));
+        items.push(item(typ, SyntaxKind::VarDecl));
+        items.push(item(name, SyntaxKind::VarDecl));
+        items.push(item(tokens[tokens.len() - 1], SyntaxKind::VarDecl));
+    }
+    push_trivia(items, &node.children[last + 1..]);
+    true
+}
+
+struct Writer<'a> {
+    opts: &'a FmtOptions,
+    out: String,
+    depth: usize,
+    newlines: usize, //上一个 token 之后源码中的换行数
+    prev: Option<(SyntaxKind, String, SyntaxKind)>,
+    need_line: bool, //下一个 token 必须另起一行
+    after_fn: bool,  //刚输出完一个函数，之后空一行
+}
+
+impl<'a> Writer<'a> {
+    fn item(&mut self, item: &Item) {
+        match item.kind {
+            SyntaxKind::Whitespace => {
+                self.newlines += item.text.matches('\n').count();
+                return;
+            }
+            SyntaxKind::LineComment | SyntaxKind::DocComment => {
+                if self.newlines == 0 && !self.out.is_empty() {
+                    self.out.push(' ');
+                } else {
+                    self.line();
+                }
+                self.out.push_str(item.text.trim_end());
+                self.need_line = true;
+            }
+            SyntaxKind::BlockComment => {
+                if self.newlines == 0 && !self.out.is_empty() && !self.need_line {
+                    self.out.push(' ');
+                } else {
+                    self.line();
+                }
+                self.out.push_str(&item.text);
+            }
+            _ => {
+                if item.text == "}" {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.need_line = true;
+                    self.newlines = self.newlines.min(1);
+                }
+                //`else` 跟在 `}` 后面同一行
+                let after_brace = matches!(&self.prev, Some((_, p, _)) if p == "}");
+                if item.text == "else" && after_brace {
+                    self.need_line = false;
+                }
+                if self.need_line {
+                    self.line();
+                } else if self.space_before(item) {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&item.text);
+                match item.text.as_str() {
+                    "{" => {
+                        self.depth += 1;
+                        self.need_line = true;
+                    }
+                    ";" | "}" => self.need_line = true,
+                    _ => (),
+                }
+                //函数之间空一行
+                self.after_fn = item.top;
+                self.prev = Some((item.kind, item.text.clone(), item.parent));
+            }
+        }
+        self.newlines = 0;
+    }
+
+    //换行并缩进，源码里的空行最多保留一个
+    fn line(&mut self) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+            let blank = self.newlines >= 2 || self.after_fn;
+            if blank && !self.out.ends_with("{\n") {
+                self.out.push('\n');
+            }
+            for _ in 0..self.depth * self.opts.indent {
+                self.out.push(' ');
+            }
+        }
+        self.need_line = false;
+        self.after_fn = false;
+    }
+
+    fn space_before(&self, item: &Item) -> bool {
+        let (pkind, ptext, pparent) = match &self.prev {
+            Some(p) => (p.0, p.1.as_str(), p.2),
+            None => return false,
+        };
+        let t = item.text.as_str();
+        if t == ";" || t == "," || t == ")" || ptext == "(" {
+            return false;
+        }
+        //一元运算符紧贴操作数
+        if pparent == SyntaxKind::UnaryExpr && pkind == SyntaxKind::Punct {
+            return false;
+        }
+        //函数名和参数列表、调用的实参列表之间没有空格
+        if t == "(" && (item.parent == SyntaxKind::ParamList || item.parent == SyntaxKind::ArgList)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let src = "/// 计数\nvar int a,b;   // 两个变量\n\n\n\nfn int f(int x,int y){var int c;\n/* 求值 */ c=-x+y*(a+b);   \n\n  a = c;}\nfn int g(){ }";
+        let want = "/// 计数\nvar int a, b; // 两个变量\n\nfn int f(int x, int y) {\n    var int c;\n    /* 求值 */ c = -x + y * (a + b);\n\n    a = c;\n}\n\nfn int g() {\n}\n";
+        let opts = FmtOptions::default();
+        let out = format(src, &opts).unwrap();
+        assert_eq!(out, want);
+        assert_eq!(format(&out, &opts).unwrap(), out);
+
+        let opts = FmtOptions {
+            indent: 2,
+            split_var_decls: true,
+        };
+        let out = format("fn int f() {\n var int a, b;\n}\n", &opts).unwrap();
+        assert_eq!(out, "fn int f() {\n  var int a;\n  var int b;\n}\n");
+        assert!(format("var int ;", &opts).is_err());
+
+        let src =
+            "fn int f(int x){if x<0{return -x;}\nelse if x==0 {return f (1);} else {return x;}}";
+        let want = "fn int f(int x) {\n  if x < 0 {\n    return -x;\n  } else if x == 0 {\n    return f(1);\n  } else {\n    return x;\n  }\n}\n";
+        assert_eq!(format(src, &opts).unwrap(), want);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::vm::ErrorKind;
+use std::fmt;
+
+//虚拟机的堆。每个对象是一个按字存放的数组，tars 代码通过引用访问它。
+//引用是一个 NaN 形式的位模式：高 16 位固定是 REF，低 32 位是对象的编号，
+//所以引用可以和整数、浮点数一样放在栈和全局变量里。
+//回收用标记-清除：根是栈上的字和全局变量，从根出发能到达的对象保留，其余释放。
+//虚拟机不记录哪些字是引用，看起来像引用的字都当作引用（保守式回收），
+//多留下一些对象但不会释放还在用的对象。对象里的字同样这样扫描。
+
+const REF: u64 = 0x7ff4 << 48;
+const REF_MASK: u64 = 0xffff << 48;
+
+//第一次回收前最多分配的字数，之后是上次回收后存活字数的两倍
+const INITIAL_THRESHOLD: usize = 64 * 1024;
+
+pub fn to_ref(i: u32) -> u64 {
+    REF | i as u64
+}
+
+//位模式是引用时返回对象编号
+pub fn from_ref(w: u64) -> Option<u32> {
+    if w & REF_MASK == REF && w & 0xffff_0000_0000 == 0 {
+        Some(w as u32)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub allocations: usize, //分配过的对象个数
+    pub collections: usize, //回收的次数
+    pub freed: usize,       //释放的对象个数
+    pub live: usize,        //存活的对象个数
+    pub words: usize,       //存活对象占用的字数
+    pub peak: usize,        //存活字数的最大值
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations, {} collections, {} freed, {} live ({} bytes, peak {} bytes)",
+            self.allocations,
+            self.collections,
+            self.freed,
+            self.live,
+            self.words * 8,
+            self.peak * 8
+        )
+    }
+}
+
+struct Object {
+    data: Vec<u64>,
+    marked: bool,
+}
+
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<u32>, //空出来的编号，分配时优先使用
+    threshold: usize,
+    stress: bool,
+    stats: Stats,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            stress: false,
+            stats: Stats::default(),
+        }
+    }
+
+    //压力模式下每次分配之前都回收，用来在测试中找出漏掉的根
+    pub fn set_stress(&mut self, on: bool) {
+        self.stress = on;
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    //再分配 n 个字之前是否应该回收
+    pub fn should_collect(&self, n: usize) -> bool {
+        self.stress || self.stats.words + n > self.threshold
+    }
+
+    //分配 n 个字的对象，内容清零，返回引用
+    pub fn alloc(&mut self, n: usize) -> u64 {
+        let obj = Object {
+            data: vec![0; n],
+            marked: false,
+        };
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.objects[i as usize] = Some(obj);
+                i
+            }
+            None => {
+                self.objects.push(Some(obj));
+                (self.objects.len() - 1) as u32
+            }
+        };
+        self.stats.allocations += 1;
+        self.stats.live += 1;
+        self.stats.words += n;
+        self.stats.peak = self.stats.peak.max(self.stats.words);
+        to_ref(i)
+    }
+
+    pub fn len(&self, r: u64) -> Result<usize, ErrorKind> {
+        Ok(self.object(r)?.data.len())
+    }
+
+    pub fn get(&self, r: u64, i: i64) -> Result<u64, ErrorKind> {
+        let data = &self.object(r)?.data;
+        match data.get(i as usize) {
+            Some(v) if i >= 0 => Ok(*v),
+            _ => Err(ErrorKind::OutOfBounds(i, data.len())),
+        }
+    }
+
+    pub fn set(&mut self, r: u64, i: i64, v: u64) -> Result<(), ErrorKind> {
+        let data = &mut self.object_mut(r)?.data;
+        let len = data.len();
+        match data.get_mut(i as usize) {
+            Some(w) if i >= 0 => {
+                *w = v;
+                Ok(())
+            }
+            _ => Err(ErrorKind::OutOfBounds(i, len)),
+        }
+    }
+
+    //从 roots 出发标记能到达的对象，释放其余的对象
+    pub fn collect<I: IntoIterator<Item = u64>>(&mut self, roots: I) {
+        let mut work: Vec<u32> = Vec::new();
+        for w in roots {
+            self.mark(w, &mut work);
+        }
+        while let Some(i) = work.pop() {
+            let len = self.objects[i as usize]
+                .as_ref()
+                .map_or(0, |obj| obj.data.len());
+            for k in 0..len {
+                let w = self.objects[i as usize].as_ref().unwrap().data[k];
+                self.mark(w, &mut work);
+            }
+        }
+        for (i, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(obj) if obj.marked => obj.marked = false,
+                Some(obj) => {
+                    self.stats.freed += 1;
+                    self.stats.live -= 1;
+                    self.stats.words -= obj.data.len();
+                    *slot = None;
+                    self.free.push(i as u32);
+                }
+                None => (),
+            }
+        }
+        self.stats.collections += 1;
+        self.threshold = INITIAL_THRESHOLD.max(self.stats.words * 2);
+    }
+
+    fn mark(&mut self, w: u64, work: &mut Vec<u32>) {
+        if let Some(i) = from_ref(w) {
+            if let Some(Some(obj)) = self.objects.get_mut(i as usize) {
+                if !obj.marked {
+                    obj.marked = true;
+                    work.push(i);
+                }
+            }
+        }
+    }
+
+    fn object(&self, r: u64) -> Result<&Object, ErrorKind> {
+        match from_ref(r).and_then(|i| self.objects.get(i as usize)) {
+            Some(Some(obj)) => Ok(obj),
+            _ => Err(ErrorKind::BadRef(r)),
+        }
+    }
+
+    fn object_mut(&mut self, r: u64) -> Result<&mut Object, ErrorKind> {
+        let objects = &mut self.objects;
+        match from_ref(r).and_then(move |i| objects.get_mut(i as usize)) {
+            Some(Some(obj)) => Ok(obj),
+            _ => Err(ErrorKind::BadRef(r)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(2);
+        let b = heap.alloc(3);
+        let c = heap.alloc(1);
+        heap.set(a, 1, b).unwrap();
+        heap.set(b, 0, 1.5f64.to_bits()).unwrap();
+        assert_eq!(heap.get(b, 0), Ok(1.5f64.to_bits()));
+        assert_eq!(heap.get(b, 3), Err(ErrorKind::OutOfBounds(3, 3)));
+        assert_eq!(heap.set(c, -1, 0), Err(ErrorKind::OutOfBounds(-1, 1)));
+        assert_eq!(heap.len(b), Ok(3));
+        //b 通过 a 到达，c 没有引用
+        heap.collect(vec![7, a]);
+        assert_eq!(heap.len(c), Err(ErrorKind::BadRef(c)));
+        assert_eq!(heap.len(b), Ok(3));
+        assert_eq!(
+            heap.stats(),
+            &Stats {
+                allocations: 3,
+                collections: 1,
+                freed: 1,
+                live: 2,
+                words: 5,
+                peak: 6,
+            }
+        );
+        //编号重新使用
+        assert_eq!(heap.alloc(4), c);
+        heap.collect(vec![]);
+        assert_eq!(heap.stats().live, 0);
+        assert_eq!(from_ref(1.5f64.to_bits()), None);
+        assert_eq!(from_ref(f64::NAN.to_bits()), None);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::cst::SyntaxElement;
+use crate::cst::SyntaxKind;
+use crate::cst::SyntaxNode;
+use crate::lexer::DefaultLexer;
+use crate::parser::Parser;
+use crate::parser::SyntaxError;
+use std::rc::Rc;
+
+//增量解析。编辑器每次只改一小段文本，如果改动完全落在某个顶层声明（函数或全局变量）
+//内部，只重新词法分析、语法分析这个声明对应的文本，其余子树原样复用（共享 Rc）。
+//重新解析的结果不是恰好一个同类声明时，退回到整个文件重新解析。
+
+//把 start..end 字节范围替换成 text，偏移必须落在字符边界上
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reparse {
+    Incremental, //只重新解析了一个顶层声明
+    Full,
+}
+
+pub struct Document {
+    text: String,
+    tree: Rc<SyntaxNode>,
+    errors: Vec<SyntaxError>,
+}
+
+impl Document {
+    pub fn new(text: String) -> Document {
+        let (tree, errors) = parse(&text);
+        Document { text, tree, errors }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn tree(&self) -> &Rc<SyntaxNode> {
+        &self.tree
+    }
+
+    pub fn errors(&self) -> &[SyntaxError] {
+        &self.errors
+    }
+
+    pub fn edit(&mut self, edit: TextEdit) -> Reparse {
+        let delta = edit.text.len() as isize - (edit.end - edit.start) as isize;
+        self.text.replace_range(edit.start..edit.end, &edit.text);
+        if self.reparse_item(&edit, delta) {
+            return Reparse::Incremental;
+        }
+        let (tree, errors) = parse(&self.text);
+        self.tree = tree;
+        self.errors = errors;
+        Reparse::Full
+    }
+
+    fn reparse_item(&mut self, edit: &TextEdit, delta: isize) -> bool {
+        //找到包含这次改动的顶层声明，改动不能碰到它的首尾
+        let mut start = 0;
+        let mut found = None;
+        for (i, c) in self.tree.children.iter().enumerate() {
+            let end = start + c.len();
+            if start < edit.start && edit.end < end {
+                if let SyntaxElement::Node(n) = c {
+                    if n.kind == SyntaxKind::FnDecl || n.kind == SyntaxKind::VarDecl {
+                        found = Some((i, start, end, n.kind));
+                    }
+                }
+                break;
+            }
+            if edit.end < end {
+                break;
+            }
+            start = end;
+        }
+        let (index, start, end, kind) = match found {
+            Some(f) => f,
+            None => return false,
+        };
+        let new_end = (end as isize + delta) as usize;
+        let (item, errors) = parse(&self.text[start..new_end]);
+        if !errors.is_empty() || item.children.len() != 1 || item.children[0].kind() != kind {
+            return false;
+        }
+        //旧声明范围内的错误去掉，之后的错误平移
+        self.errors.retain(|e| e.offset < start || e.offset >= end);
+        for e in self.errors.iter_mut() {
+            if e.offset >= end {
+                e.offset = (e.offset as isize + delta) as usize;
+            }
+        }
+        let mut children = self.tree.children.clone();
+        children[index] = item.children[0].clone();
+        self.tree = Rc::new(SyntaxNode::new(SyntaxKind::Root, children));
+        true
+    }
+}
+
+fn parse(text: &str) -> (Rc<SyntaxNode>, Vec<SyntaxError>) {
+    Parser::new(DefaultLexer::new(text.as_bytes())).parse_tree()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(tree: &SyntaxNode) -> Vec<Rc<SyntaxNode>> {
+        tree.child_nodes().cloned().collect()
+    }
+
+    #[test]
+    fn test_incremental_reparse() {
+        let s = "var int a;\nfn int f(int x) {\n  a = x;\n}\nfn int g() {\n  a = a;\n}\n";
+        let mut doc = Document::new(s.to_owned());
+        let before = nodes(doc.tree());
+        let at = s.find("x;").unwrap();
+        let r = doc.edit(TextEdit {
+            start: at,
+            end: at + 1,
+            text: "x * (a + x)".to_owned(),
+        });
+        assert_eq!(r, Reparse::Incremental);
+        assert_eq!(doc.tree().text(), doc.text());
+        let after = nodes(doc.tree());
+        assert!(Rc::ptr_eq(&before[0], &after[0]));
+        assert!(!Rc::ptr_eq(&before[1], &after[1]));
+        assert!(Rc::ptr_eq(&before[2], &after[2]));
+        let full = parse(doc.text()).0;
+        assert_eq!(*doc.tree(), full);
+
+        // 删掉右括号，函数不再完整，整体重新解析
+        let at = doc.text().find("}").unwrap();
+        let r = doc.edit(TextEdit {
+            start: at,
+            end: at + 1,
+            text: String::new(),
+        });
+        assert_eq!(r, Reparse::Full);
+        assert_eq!(doc.tree().text(), doc.text());
+        assert!(!doc.errors().is_empty());
+    }
+}
@@ -1,14 +1,30 @@
-use std::io::{Bytes, Read};
-use std::iter::{Iterator, Peekable};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufReader, Bytes, Read};
+use std::iter::Iterator;
+use unicode_xid::UnicodeXID;
 
 pub type LexResult = Result<Token, LexerError>;
 
-static KEY_WORD: &'static [(&'static str, KeyWord)] = &[
+//关键字表。标识符规则为 `[A-Za-z_][A-Za-z0-9_]*`（以及 Unicode XID），
+//与下表同名的标识符会被识别成关键字。
+pub static KEY_WORD: &[(&str, KeyWord)] = &[
     ("int", KeyWord::Int),
     ("float", KeyWord::Float),
     ("var", KeyWord::Var),
     ("fn", KeyWord::Fn),
     ("return", KeyWord::Return),
+    ("if", KeyWord::If),
+    ("else", KeyWord::Else),
+    ("while", KeyWord::While),
+];
+
+//保留字，目前还不是关键字，但留给以后的语法使用，不能作为普通标识符。
+//需要使用同名标识符时写成原始标识符，例如 `r#while`，
+//这样这些词以后变成关键字时已有的 tars 程序也不会失效。
+pub static RESERVED: &[&str] = &[
+    "for", "loop", "break", "continue", "true", "false", "bool", "char", "string", "struct",
+    "enum", "const", "import", "match", "in", "as", "self",
 ];
 
 fn is_keyword(s: &str) -> Option<KeyWord> {
@@ -19,11 +35,48 @@ fn is_keyword(s: &str) -> Option<KeyWord> {
     }
 }
 
+fn is_reserved(s: &str) -> bool {
+    RESERVED.contains(&s)
+}
+
+//源码位置，行列均从 1 开始，列按字符计数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pos {
+    pub line: u32,
+    pub col: u32,
+}
+
 #[derive(Debug)]
 pub enum LexerError {
     Eof,
     Unterminated(String),
     UnExpected,
+    InvalidUtf8(Pos),
+    InvalidEscape(Pos),
+    InvalidChar(Pos),
+    Reserved(String, Pos),
+    Overflow(String), //整数字面量超出范围
+    Io(io::Error),    //读源码失败
+}
+
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexerError::Eof => write!(f, "unexpected end of file"),
+            LexerError::Unterminated(s) => write!(f, "unterminated literal: {}", s),
+            LexerError::UnExpected => write!(f, "unexpected character"),
+            LexerError::InvalidUtf8(_) => write!(f, "invalid UTF-8"),
+            LexerError::InvalidEscape(_) => write!(f, "invalid escape"),
+            LexerError::InvalidChar(_) => write!(f, "invalid character literal"),
+            LexerError::Reserved(s, _) => write!(
+                f,
+                "`{}` is a reserved word, write `r#{}` to use it as a name",
+                s, s
+            ),
+            LexerError::Overflow(s) => write!(f, "number `{}` is too large", s),
+            LexerError::Io(e) => write!(f, "cannot read the source: {}", e),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -33,6 +86,9 @@ pub enum KeyWord {
     Var,
     Fn, // fn
     Return,
+    If,
+    Else,
+    While,
 }
 
 impl KeyWord {
@@ -52,8 +108,10 @@ pub enum Token {
     Oper(Operator),
     Aide(Aides),
     Str(String),
+    Char(char),
     Ident(String),
     Number(isize),
+    Float(f64),
     Eof,
 }
 
@@ -61,8 +119,19 @@ impl Token {
     pub fn level(&self) -> u32 {
         match self {
             Token::Oper(Operator) => match Operator {
-                Operator::Add | Operator::Sub => 2,
-                Operator::Star | Operator::Div => 3,
+                Operator::Equal
+                | Operator::NotEqual
+                | Operator::Greate
+                | Operator::GreateEqual
+                | Operator::Less
+                | Operator::LessEqual => 1,
+                Operator::Add | Operator::Sub | Operator::BitOr | Operator::BitXor => 2,
+                Operator::Star
+                | Operator::Div
+                | Operator::Mod
+                | Operator::BitAnd
+                | Operator::BitShiftLeft
+                | Operator::BitShiftRight => 3,
                 _ => 0,
             },
             _ => 0,
@@ -76,8 +145,40 @@ pub enum Aides {
     Comma,     // ,
     Semicolon, // ;
     Colon,     // :
-    Note,      // //
-    MultNote,  // /**/
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,  // //
+    BlockComment, // /* */ 可嵌套
+    DocComment,   // ///
+}
+
+//空白和注释，不参与语法分析，挂在相邻的 token 上
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+impl Trivia {
+    //去掉 `///` 前缀后的文档内容
+    pub fn doc_text(&self) -> &str {
+        let s = self.text.trim_start_matches("///");
+        s.strip_prefix(' ').unwrap_or(s)
+    }
+}
+
+//带 trivia 的 token。leading 是 token 之前的空白和注释，
+//trailing 是 token 之后到行尾为止的空白和注释
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub tok: Token,
+    pub text: String, //token 在源码中的原文
+    pub pos: Pos,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -95,6 +196,7 @@ pub enum Operator {
     BitAnd,        // &
     BitOr,         // |
     BitNot,        // ~
+    BitXor,        // ^
     BitShiftRight, // >>
     BitShiftLeft,  // <<
     LogicAnd,      // &&
@@ -118,85 +220,240 @@ pub enum Operator {
 }
 
 pub trait lexer {
-    fn lex(&mut self) -> LexResult;
+    //文件结束时返回 Token::Eof，它的 leading 带着文件末尾的 trivia
+    fn lex_with_trivia(&mut self) -> Result<Lexeme, LexerError>;
+
+    //lex_with_trivia 出错后取回这次已经读掉的原文，tok 为 Token::Unknown，
+    //用来在无损语法树中保留错误 token
+    fn recover(&mut self) -> Option<Lexeme> {
+        None
+    }
+
+    fn lex(&mut self) -> LexResult {
+        match self.lex_with_trivia()?.tok {
+            Token::Eof => Err(LexerError::Eof),
+            t => Ok(t),
+        }
+    }
+}
+
+//把字节流按 UTF-8 解码成字符，非法序列返回 Err。
+//读出错时记下错误并当作输入结束，由 DefaultLexer 报告
+struct Utf8Chars<R: Read> {
+    bytes: Bytes<BufReader<R>>,
+    back: Option<u8>, //截断序列后面读多了的那个字节，下次先返回它
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Utf8Chars<R> {
+    fn byte(&mut self) -> Option<u8> {
+        match self.bytes.next()? {
+            Ok(b) => Some(b),
+            Err(e) => {
+                self.error.get_or_insert(e);
+                None
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for Utf8Chars<R> {
+    type Item = Result<char, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.back.take() {
+            Some(b) => b,
+            None => self.byte()?,
+        };
+        let width = match first {
+            0x00..=0x7F => return Some(Ok(first as char)),
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return Some(Err(())),
+        };
+        let mut buf = [first, 0, 0, 0];
+        for byte in &mut buf[1..width] {
+            match self.byte() {
+                Some(b @ 0x80..=0xBF) => *byte = b,
+                //不是后续字节，留给下一个字符
+                Some(b) => {
+                    self.back = Some(b);
+                    return Some(Err(()));
+                }
+                _ => return Some(Err(())),
+            }
+        }
+        match std::str::from_utf8(&buf[..width]) {
+            Ok(s) => s.chars().next().map(Ok),
+            Err(_) => Some(Err(())),
+        }
+    }
 }
 
 pub struct DefaultLexer<R: Read> {
-    peeker: Peekable<Bytes<R>>,
+    chars: Utf8Chars<R>,
+    ahead: VecDeque<Result<char, ()>>,
     line: u32,
+    col: u32,
+    invalid: Option<Pos>,
+    text: String,           //本次 lex_with_trivia 读掉的原文
+    failed: Option<Lexeme>, //出错时读掉的内容
 }
 
 impl<R: Read> lexer for DefaultLexer<R> {
-    fn lex(&mut self) -> LexResult {
-        while let Some(c) = self.next() {
-            match c {
-                b'\n' | b'\r' | b'\t' => self.skip_line(),
-                b' ' => self.skip_space(),
-                b'.' => return Ok(Token::Aide(Aides::Dot)),
-                b',' => return Ok(Token::Aide(Aides::Comma)),
-                b';' => return Ok(Token::Aide(Aides::Semicolon)),
-                b':' => return Ok(Token::Aide(Aides::Colon)),
-                b'+' => return self.parse_add(),
-                b'-' => return self.parse_sub(),
-                b'=' => return self.parse_equal(),
-                b'"' => return self.parse_string(),
-                b'*' => return Ok(Token::Oper(Operator::Star)),
-                b'/' => return self.parse_div(),
-                b'%' => return Ok(Token::Oper(Operator::Mod)),
-                b'&' => return self.parse_and(),
-                b'|' => return self.parse_or(),
-                b'~' => return Ok(Token::Oper(Operator::BitNot)),
-                b'>' => return self.parse_greate(),
-                b'<' => return self.parse_less(),
-                b'!' => return self.parse_excl(),
-                b'?' => return Ok(Token::Oper(Operator::Question)),
-                b'{' => return Ok(Token::Oper(Operator::LeftBrace)),
-                b'}' => return Ok(Token::Oper(Operator::RightBrace)),
-                b'[' => return Ok(Token::Oper(Operator::LeftBracket)),
-                b']' => return Ok(Token::Oper(Operator::RightBracket)),
-                b'(' => return Ok(Token::Oper(Operator::LeftParen)),
-                b')' => return Ok(Token::Oper(Operator::RightParen)),
-                _ => {
-                    if c.is_ascii_alphabetic() || c == b'_' {
-                        return self.parse_varorkeyword(c);
-                    } else if c.is_ascii_digit() {
-                        return self.parse_num(c);
-                    } else {
-                        return Ok(Token::Unknown);
-                    }
-                }
-            };
+    fn lex_with_trivia(&mut self) -> Result<Lexeme, LexerError> {
+        self.text.clear();
+        let start = self.pos();
+        let l = self.lex_lexeme();
+        if l.is_err() {
+            self.failed = Some(Lexeme {
+                tok: Token::Unknown,
+                text: self.text.clone(),
+                pos: start,
+                leading: Vec::new(),
+                trailing: Vec::new(),
+            });
         }
-        // Ok(Token::Eof)
-        Err(LexerError::Eof)
+        l
+    }
+
+    fn recover(&mut self) -> Option<Lexeme> {
+        self.failed.take()
     }
 }
 
 impl<R: Read> DefaultLexer<R> {
     pub fn new(r: R) -> DefaultLexer<R> {
         DefaultLexer {
-            peeker: r.bytes().peekable(),
-            line: 0,
+            chars: Utf8Chars {
+                bytes: BufReader::new(r).bytes(),
+                back: None,
+                error: None,
+            },
+            ahead: VecDeque::new(),
+            line: 1,
+            col: 1,
+            invalid: None,
+            text: String::new(),
+            failed: None,
         }
     }
 
-    fn skip_space(&mut self) {
-        while let Some(c) = self.peek() {
-            match c {
-                b' ' => {
-                    self.take();
-                    continue;
+    fn lex_lexeme(&mut self) -> Result<Lexeme, LexerError> {
+        let leading = self.lex_trivia(true)?;
+        let pos = self.pos();
+        let mark = self.text.len();
+        let tok = match self.lex_token() {
+            Ok(t) => t,
+            Err(LexerError::Eof) => Token::Eof,
+            Err(e) => return Err(e),
+        };
+        if let Some(e) = self.chars.error.take() {
+            return Err(LexerError::Io(e));
+        }
+        //token 中出现非法 UTF-8 时报告第一个出错位置
+        if let Some(pos) = self.invalid.take() {
+            return Err(LexerError::InvalidUtf8(pos));
+        }
+        let text = self.text[mark..].to_owned();
+        let trailing = match tok {
+            Token::Eof => Vec::new(),
+            _ => self.lex_trivia(false)?,
+        };
+        Ok(Lexeme {
+            tok,
+            text,
+            pos,
+            leading,
+            trailing,
+        })
+    }
+
+    //下一个待读取字符的位置
+    pub fn pos(&self) -> Pos {
+        Pos {
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    //收集空白和注释，trailing 模式下遇到换行就停止
+    fn lex_trivia(&mut self, leading: bool) -> Result<Vec<Trivia>, LexerError> {
+        let mut list = Vec::new();
+        loop {
+            match (self.peek(), self.peek_nth(1)) {
+                (Some('/'), Some('/')) => list.push(self.parse_note()),
+                (Some('/'), Some('*')) => list.push(self.parse_multnote()?),
+                (Some(c), _) if c.is_whitespace() && (leading || c != '\n') => {
+                    let mut text = String::new();
+                    while let Some(c) = self.peek() {
+                        if !c.is_whitespace() || (!leading && c == '\n') {
+                            break;
+                        }
+                        text.push(c);
+                        self.take();
+                    }
+                    list.push(Trivia {
+                        kind: TriviaKind::Whitespace,
+                        text,
+                    });
                 }
-                _ => return,
+                _ => return Ok(list),
             }
         }
     }
 
+    fn lex_token(&mut self) -> LexResult {
+        let start = self.pos();
+        if let Some(c) = self.next() {
+            match c {
+                '.' => return Ok(Token::Aide(Aides::Dot)),
+                ',' => return Ok(Token::Aide(Aides::Comma)),
+                ';' => return Ok(Token::Aide(Aides::Semicolon)),
+                ':' => return Ok(Token::Aide(Aides::Colon)),
+                '+' => return self.parse_add(),
+                '-' => return self.parse_sub(),
+                '=' => return self.parse_equal(),
+                '"' => return self.parse_string(),
+                '\'' => return self.parse_char(start),
+                '*' => return Ok(Token::Oper(Operator::Star)),
+                '/' => return self.parse_div(),
+                '%' => return Ok(Token::Oper(Operator::Mod)),
+                '&' => return self.parse_and(),
+                '|' => return self.parse_or(),
+                '~' => return Ok(Token::Oper(Operator::BitNot)),
+                '^' => return Ok(Token::Oper(Operator::BitXor)),
+                '>' => return self.parse_greate(),
+                '<' => return self.parse_less(),
+                '!' => return self.parse_excl(),
+                '?' => return Ok(Token::Oper(Operator::Question)),
+                '{' => return Ok(Token::Oper(Operator::LeftBrace)),
+                '}' => return Ok(Token::Oper(Operator::RightBrace)),
+                '[' => return Ok(Token::Oper(Operator::LeftBracket)),
+                ']' => return Ok(Token::Oper(Operator::RightBracket)),
+                '(' => return Ok(Token::Oper(Operator::LeftParen)),
+                ')' => return Ok(Token::Oper(Operator::RightParen)),
+                _ => {
+                    if c.is_xid_start() || c == '_' {
+                        return self.parse_varorkeyword(c, start);
+                    } else if c.is_ascii_digit() {
+                        return self.parse_num(c);
+                    } else {
+                        return Ok(Token::Unknown);
+                    }
+                }
+            };
+        }
+        // Ok(Token::Eof)
+        Err(LexerError::Eof)
+    }
+
     fn parse_add(&mut self) -> LexResult {
         match self.peek() {
             Some(c) => match c {
-                b'+' => self.take_token(Token::Oper(Operator::Plus)),
-                b'=' => self.take_token(Token::Oper(Operator::AddEqual)),
+                '+' => self.take_token(Token::Oper(Operator::Plus)),
+                '=' => self.take_token(Token::Oper(Operator::AddEqual)),
                 _ => Ok(Token::Oper(Operator::Add)),
             },
             None => Ok(Token::Oper(Operator::Add)),
@@ -206,9 +463,9 @@ impl<R: Read> DefaultLexer<R> {
     fn parse_sub(&mut self) -> LexResult {
         match self.peek() {
             Some(c) => match c {
-                b'-' => self.take_token(Token::Oper(Operator::Minus)),
-                b'=' => self.take_token(Token::Oper(Operator::SubEqual)),
-                b'>' => self.take_token(Token::Oper(Operator::RightArrow)),
+                '-' => self.take_token(Token::Oper(Operator::Minus)),
+                '=' => self.take_token(Token::Oper(Operator::SubEqual)),
+                '>' => self.take_token(Token::Oper(Operator::RightArrow)),
                 _ => Ok(Token::Oper(Operator::Sub)),
             },
             None => Ok(Token::Oper(Operator::Sub)),
@@ -217,24 +474,18 @@ impl<R: Read> DefaultLexer<R> {
 
     fn parse_equal(&mut self) -> LexResult {
         match self.peek() {
-            Some(b'=') => self.take_token(Token::Oper(Operator::Equal)),
+            Some('=') => self.take_token(Token::Oper(Operator::Equal)),
             _ => Ok(Token::Oper(Operator::Assign)),
         }
     }
 
+    //注释已经在 lex_trivia 中处理
     fn parse_div(&mut self) -> LexResult {
-        match self.peek() {
-            Some(c) => match c {
-                b'/' => self.parse_note(),
-                b'*' => self.parse_multnote(),
-                _ => Ok(Token::Oper(Operator::Div)),
-            },
-            None => Err(LexerError::UnExpected),
-        }
+        Ok(Token::Oper(Operator::Div))
     }
 
     fn parse_and(&mut self) -> LexResult {
-        if let Some(b'&') = self.peek() {
+        if let Some('&') = self.peek() {
             self.take_token(Token::Oper(Operator::LogicAnd))
         } else {
             Ok(Token::Oper(Operator::BitAnd))
@@ -242,35 +493,49 @@ impl<R: Read> DefaultLexer<R> {
     }
 
     fn parse_or(&mut self) -> LexResult {
-        if let Some(b'|') = self.peek() {
+        if let Some('|') = self.peek() {
             self.take_token(Token::Oper(Operator::LogicOr))
         } else {
             Ok(Token::Oper(Operator::BitOr))
         }
     }
 
-    fn parse_note(&mut self) -> LexResult {
-        self.take();
-        while let Some(b'\n') = self.next() {
-            break;
+    // `//` 到行尾，不包含换行；`///` 开头的是文档注释
+    fn parse_note(&mut self) -> Trivia {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.take();
         }
-        Ok(Token::Aide(Aides::Note))
+        let kind = if text.starts_with("///") && !text.starts_with("////") {
+            TriviaKind::DocComment
+        } else {
+            TriviaKind::LineComment
+        };
+        Trivia { kind, text }
     }
 
-    fn parse_multnote(&mut self) -> LexResult {
-        self.take();
+    // `/* */`，支持嵌套
+    fn parse_multnote(&mut self) -> Result<Trivia, LexerError> {
+        let mut text = String::new();
+        let mut depth = 0;
         while let Some(c) = self.next() {
-            match c {
-                b'*' => {
-                    if let Some(b'/') = self.peek() {
-                        return self.take_token(Token::Aide(Aides::MultNote));
-                    }
-                    return Err(LexerError::Unterminated(
-                        "found '/*', no '*/' end ".to_owned(),
-                    ));
-                }
+            text.push(c);
+            match (c, self.peek()) {
+                ('/', Some('*')) => depth += 1,
+                ('*', Some('/')) => depth -= 1,
                 _ => continue,
             }
+            text.push(self.next().unwrap());
+            if depth == 0 {
+                return Ok(Trivia {
+                    kind: TriviaKind::BlockComment,
+                    text,
+                });
+            }
         }
         Err(LexerError::Unterminated(
             "found '/*', no '*/' end ".to_owned(),
@@ -280,28 +545,28 @@ impl<R: Read> DefaultLexer<R> {
     fn parse_greate(&mut self) -> LexResult {
         match self.peek() {
             Some(c) => match c {
-                b'>' => self.take_token(Token::Oper(Operator::BitShiftRight)),
-                b'=' => self.take_token(Token::Oper(Operator::GreateEqual)),
-                _ => self.take_token(Token::Oper(Operator::Greate)),
+                '>' => self.take_token(Token::Oper(Operator::BitShiftRight)),
+                '=' => self.take_token(Token::Oper(Operator::GreateEqual)),
+                _ => Ok(Token::Oper(Operator::Greate)),
             },
-            None => self.take_token(Token::Oper(Operator::Greate)),
+            None => Ok(Token::Oper(Operator::Greate)),
         }
     }
 
     fn parse_less(&mut self) -> LexResult {
         match self.peek() {
             Some(c) => match c {
-                b'<' => self.take_token(Token::Oper(Operator::BitShiftLeft)),
-                b'=' => self.take_token(Token::Oper(Operator::LessEqual)),
-                b'-' => self.take_token(Token::Oper(Operator::LeftArrow)),
-                _ => self.take_token(Token::Oper(Operator::Less)),
+                '<' => self.take_token(Token::Oper(Operator::BitShiftLeft)),
+                '=' => self.take_token(Token::Oper(Operator::LessEqual)),
+                '-' => self.take_token(Token::Oper(Operator::LeftArrow)),
+                _ => Ok(Token::Oper(Operator::Less)),
             },
-            None => self.take_token(Token::Oper(Operator::Less)),
+            None => Ok(Token::Oper(Operator::Less)),
         }
     }
 
     fn parse_excl(&mut self) -> LexResult {
-        if let Some(b'=') = self.peek() {
+        if let Some('=') = self.peek() {
             self.take_token(Token::Oper(Operator::NotEqual))
         } else {
             Ok(Token::Oper(Operator::LogicNot))
@@ -310,10 +575,13 @@ impl<R: Read> DefaultLexer<R> {
 
     fn parse_string(&mut self) -> LexResult {
         let mut s = String::new();
-        while let Some(c) = self.next() {
-            match c {
-                b'"' => return Ok(Token::Str(s)),
-                _ => s.push(c as char),
+        loop {
+            let pos = self.pos();
+            match self.next() {
+                Some('"') => return Ok(Token::Str(s)),
+                Some('\\') => s.push(self.parse_escape(pos)?),
+                Some(c) => s.push(c),
+                None => break,
             }
         }
         Err(LexerError::Unterminated(
@@ -321,48 +589,132 @@ impl<R: Read> DefaultLexer<R> {
         ))
     }
 
-    fn parse_varorkeyword(&mut self, c: u8) -> LexResult {
-        let mut s = String::new();
-        s.push(c as char);
-        while let Some(c) = self.peek() {
-            if c.is_ascii_alphabetic() {
-                s.push(c as char);
-                self.take();
-            } else {
-                break;
+    // char ::= '\'' (c | escape) '\''
+    fn parse_char(&mut self, start: Pos) -> LexResult {
+        let pos = self.pos();
+        let c = match self.next() {
+            Some('\\') => self.parse_escape(pos)?,
+            Some('\'') => return Err(LexerError::InvalidChar(start)),
+            Some(c) => c,
+            None => {
+                return Err(LexerError::Unterminated(
+                    "found '\'', no '\'' end ".to_owned(),
+                ))
             }
+        };
+        match self.next() {
+            Some('\'') => Ok(Token::Char(c)),
+            Some(_) => Err(LexerError::InvalidChar(start)),
+            None => Err(LexerError::Unterminated(
+                "found '\'', no '\'' end ".to_owned(),
+            )),
+        }
+    }
+
+    //转义字符，'\\' 已经被读取，pos 是 '\\' 的位置
+    fn parse_escape(&mut self, pos: Pos) -> Result<char, LexerError> {
+        match self.next() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('0') => Ok('\0'),
+            Some('\\') => Ok('\\'),
+            Some('\'') => Ok('\''),
+            Some('"') => Ok('"'),
+            Some('u') => {
+                // \u{1F600}
+                if self.next() != Some('{') {
+                    return Err(LexerError::InvalidEscape(pos));
+                }
+                let mut code: u32 = 0;
+                let mut digits = 0;
+                loop {
+                    match self.next() {
+                        Some('}') if digits > 0 => break,
+                        Some(c) if c.is_ascii_hexdigit() && digits < 6 => {
+                            code = code * 16 + c.to_digit(16).unwrap();
+                            digits += 1;
+                        }
+                        _ => return Err(LexerError::InvalidEscape(pos)),
+                    }
+                }
+                std::char::from_u32(code).ok_or(LexerError::InvalidEscape(pos))
+            }
+            _ => Err(LexerError::InvalidEscape(pos)),
+        }
+    }
+
+    // ident ::= [A-Za-z_][A-Za-z0-9_]* | 'r#' ident
+    fn parse_varorkeyword(&mut self, c: char, start: Pos) -> LexResult {
+        let s = self.parse_ident_rest(c);
+        if s == "r" && self.peek() == Some('#') {
+            return self.parse_raw_ident();
         }
         if let Some(k) = is_keyword(s.as_str()) {
             Ok(Token::KeyWord(k))
+        } else if is_reserved(s.as_str()) {
+            Err(LexerError::Reserved(s, start))
         } else {
             Ok(Token::Ident(s))
         }
     }
 
-    fn parse_num(&mut self, c: u8) -> LexResult {
+    //原始标识符 r#name，不做关键字检查
+    fn parse_raw_ident(&mut self) -> LexResult {
+        self.take();
+        match self.peek() {
+            Some(c) if c.is_xid_start() || c == '_' => {
+                self.take();
+                Ok(Token::Ident(self.parse_ident_rest(c)))
+            }
+            _ => Err(LexerError::UnExpected),
+        }
+    }
+
+    fn parse_ident_rest(&mut self, c: char) -> String {
         let mut s = String::new();
-        s.push(c as char);
+        s.push(c);
         while let Some(c) = self.peek() {
-            if c.is_ascii_digit() {
-                s.push(c as char);
+            if c.is_xid_continue() {
+                s.push(c);
                 self.take();
             } else {
                 break;
             }
         }
-        Ok(Token::Number(s.parse::<isize>().unwrap()))
+        s
+    }
+
+    //整数或者 `1.5` 这样的浮点数，小数点两边都要有数字
+    fn parse_num(&mut self, c: char) -> LexResult {
+        let mut s = String::new();
+        s.push(c);
+        self.parse_digits(&mut s);
+        let frac = match self.peek_nth(1) {
+            Some(c) => c.is_ascii_digit(),
+            None => false,
+        };
+        if self.peek() == Some('.') && frac {
+            s.push('.');
+            self.take();
+            self.parse_digits(&mut s);
+            return s
+                .parse::<f64>()
+                .map(Token::Float)
+                .map_err(|_| LexerError::Overflow(s));
+        }
+        s.parse::<isize>()
+            .map(Token::Number)
+            .map_err(|_| LexerError::Overflow(s))
     }
 
-    fn skip_line(&mut self) {
-        self.line += 1;
+    fn parse_digits(&mut self, s: &mut String) {
         while let Some(c) = self.peek() {
-            match c {
-                b'\n' | b'\r' | b'\t' => {
-                    self.line += 1;
-                    self.take();
-                    continue;
-                }
-                _ => return,
+            if c.is_ascii_digit() {
+                s.push(c);
+                self.take();
+            } else {
+                break;
             }
         }
     }
@@ -373,29 +725,48 @@ impl<R: Read> DefaultLexer<R> {
     }
 
     fn take(&mut self) {
-        let _ = self.peeker.next();
+        let _ = self.next();
     }
 
-    //获取下一个字符
-    fn next(&mut self) -> Option<u8> {
-        match self.peeker.next() {
-            Some(Ok(ch)) => Some(ch),
-            _ => None,
+    //获取下一个字符，非法 UTF-8 记录位置后以 U+FFFD 代替
+    fn next(&mut self) -> Option<char> {
+        let pos = self.pos();
+        let ch = match self.ahead.pop_front().or_else(|| self.chars.next())? {
+            Ok(ch) => ch,
+            Err(()) => {
+                if self.invalid.is_none() {
+                    self.invalid = Some(pos);
+                }
+                std::char::REPLACEMENT_CHARACTER
+            }
+        };
+        self.text.push(ch);
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
         }
+        Some(ch)
     }
 
     //向前偷看一个字符
-    fn peek(&mut self) -> Option<u8> {
-        match self.peeker.peek() {
-            Some(&Ok(ch)) => Some(ch),
-            _ => None,
+    fn peek(&mut self) -> Option<char> {
+        self.peek_nth(0)
+    }
+
+    //向前偷看第 n+1 个字符
+    fn peek_nth(&mut self, n: usize) -> Option<char> {
+        while self.ahead.len() <= n {
+            self.ahead.push_back(self.chars.next()?);
         }
+        Some(self.ahead[n].unwrap_or(std::char::REPLACEMENT_CHARACTER))
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::OpenOptions;
     #[test]
     fn test_lexer() {
         let s = "
@@ -443,4 +814,175 @@ mod tests {
             }
         }
     }
+
+    fn lex_all(s: &[u8]) -> Vec<LexResult> {
+        let mut lexer = DefaultLexer::new(s);
+        let mut list = Vec::new();
+        loop {
+            match lexer.lex() {
+                Err(LexerError::Eof) => return list,
+                m => list.push(m),
+            }
+        }
+    }
+
+    #[test]
+    fn test_lexer_utf8() {
+        let toks: Vec<Token> =
+            lex_all("var int 速度 = \"你好, 世界\" 'é' '\\n' '\\u{1F600}'".as_bytes())
+                .into_iter()
+                .map(|t| t.unwrap())
+                .collect();
+        assert_eq!(
+            toks,
+            vec![
+                Token::KeyWord(KeyWord::Var),
+                Token::KeyWord(KeyWord::Int),
+                Token::Ident("速度".to_owned()),
+                Token::Oper(Operator::Assign),
+                Token::Str("你好, 世界".to_owned()),
+                Token::Char('é'),
+                Token::Char('\n'),
+                Token::Char('😀'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_read_error() {
+        //读完 `var a` 之后出错
+        struct Failing(&'static [u8]);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::other("disk on fire"));
+                }
+                self.0.read(buf)
+            }
+        }
+        let mut lexer = DefaultLexer::new(Failing(b"var a"));
+        assert_eq!(lexer.lex().unwrap(), Token::KeyWord(KeyWord::Var));
+        match lexer.lex() {
+            Err(LexerError::Io(e)) => assert_eq!(e.to_string(), "disk on fire"),
+            t => panic!("unexpected {:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_lexer_invalid_utf8() {
+        let toks = lex_all(b"a\n  b\xff c");
+        assert_eq!(toks.len(), 4);
+        match &toks[2] {
+            Err(LexerError::InvalidUtf8(pos)) => assert_eq!(*pos, Pos { line: 2, col: 4 }),
+            t => panic!("unexpected {:?}", t),
+        }
+        match lex_all(b"''").pop() {
+            Some(Err(LexerError::InvalidChar(pos))) => assert_eq!(pos, Pos { line: 1, col: 1 }),
+            t => panic!("unexpected {:?}", t),
+        }
+        //字符和字符串里的非法转义都报告在 '\\' 上
+        for src in ["'\\q'", "\"\\q\""] {
+            match lex_all(src.as_bytes()).into_iter().next() {
+                Some(Err(LexerError::InvalidEscape(pos))) => {
+                    assert_eq!(pos, Pos { line: 1, col: 2 })
+                }
+                t => panic!("unexpected {:?}", t),
+            }
+        }
+        //截断的多字节序列不吞掉后面的字符
+        let toks = lex_all(b"\xC3a b");
+        match &toks[0] {
+            Err(LexerError::InvalidUtf8(pos)) => assert_eq!(*pos, Pos { line: 1, col: 1 }),
+            t => panic!("unexpected {:?}", t),
+        }
+        assert_eq!(toks[1].as_ref().unwrap(), &Token::Ident("a".to_owned()));
+    }
+
+    #[test]
+    fn test_lexer_ident() {
+        let toks: Vec<Token> = lex_all(b"x1 max_value vec3 _tmp2 r#while r#int 9lives")
+            .into_iter()
+            .map(|t| t.unwrap())
+            .collect();
+        let ident = |s: &str| Token::Ident(s.to_owned());
+        assert_eq!(
+            toks,
+            vec![
+                ident("x1"),
+                ident("max_value"),
+                ident("vec3"),
+                ident("_tmp2"),
+                ident("while"),
+                ident("int"),
+                Token::Number(9),
+                ident("lives"),
+            ]
+        );
+        match lex_all(b"var int  loop;").remove(2) {
+            Err(LexerError::Reserved(s, pos)) => {
+                assert_eq!(s, "loop");
+                assert_eq!(pos, Pos { line: 1, col: 10 });
+            }
+            t => panic!("unexpected {:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_lexer_compare() {
+        let mut toks = lex_all(b"a>b<c>=1 while 2.5^3. 99999999999999999999");
+        match toks.pop() {
+            Some(Err(LexerError::Overflow(s))) => assert_eq!(s, "99999999999999999999"),
+            t => panic!("unexpected {:?}", t),
+        }
+        let toks: Vec<Token> = toks.into_iter().map(|t| t.unwrap()).collect();
+        let ident = |s: &str| Token::Ident(s.to_owned());
+        assert_eq!(
+            toks,
+            vec![
+                ident("a"),
+                Token::Oper(Operator::Greate),
+                ident("b"),
+                Token::Oper(Operator::Less),
+                ident("c"),
+                Token::Oper(Operator::GreateEqual),
+                Token::Number(1),
+                Token::KeyWord(KeyWord::While),
+                Token::Float(2.5),
+                Token::Oper(Operator::BitXor),
+                Token::Number(3),
+                Token::Aide(Aides::Dot),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_trivia() {
+        let s = "/// 加法\n/* a /* nested */ comment */ var x; // tail\n\n// end";
+        let mut lexer = DefaultLexer::new(s.as_bytes());
+        let var = lexer.lex_with_trivia().unwrap();
+        assert_eq!(var.tok, Token::KeyWord(KeyWord::Var));
+        let kinds: Vec<TriviaKind> = var.leading.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TriviaKind::DocComment,
+                TriviaKind::Whitespace,
+                TriviaKind::BlockComment,
+                TriviaKind::Whitespace,
+            ]
+        );
+        assert_eq!(var.leading[0].doc_text(), "加法");
+        assert_eq!(var.leading[2].text, "/* a /* nested */ comment */");
+        assert_eq!(lexer.lex().unwrap(), Token::Ident("x".to_owned()));
+        let semi = lexer.lex_with_trivia().unwrap();
+        assert_eq!(semi.text, ";");
+        assert_eq!(semi.trailing[1].text, "// tail");
+        let eof = lexer.lex_with_trivia().unwrap();
+        assert_eq!(eof.tok, Token::Eof);
+        assert_eq!(eof.leading[1].kind, TriviaKind::LineComment);
+        match lex_all(b"/* /* */").pop() {
+            Some(Err(LexerError::Unterminated(_))) => (),
+            t => panic!("unexpected {:?}", t),
+        }
+    }
 }
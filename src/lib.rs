@@ -0,0 +1,22 @@
+pub mod asm;
+pub mod ast;
+pub mod bytecode;
+pub mod compiler;
+pub mod engine;
+pub mod cst;
+pub mod debug;
+pub mod fmt;
+pub mod gc;
+pub mod incr;
+pub mod lexer;
+pub mod llvm;
+pub mod lsp;
+pub mod natives;
+pub mod parser;
+pub mod profile;
+pub mod regcompiler;
+pub mod regvm;
+pub mod repl;
+pub mod semantic;
+pub mod verify;
+pub mod vm;
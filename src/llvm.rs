@@ -1,88 +1,520 @@
 extern crate llvm_sys as llvm;
-use std::ffi::CString;
-use std::fs::File;
-use std::io::Read;
+use crate::ast::{ExprNode, FuncDecl, StmtNode, AST};
+use crate::compiler::is_builtin;
+use crate::lexer::{KeyWord, Operator, Token};
+use llvm::prelude::*;
+use llvm::target_machine::*;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
 use std::ptr;
 
-// fn main() {
-//     let mut input = String::new();
-//     let mut f = File::open("in.ex").unwrap();
-//     f.read_to_string(&mut input).unwrap();
-
-//     let parsed_input = parser::program(&input).unwrap();
-
-//     unsafe {
-//         codegen(parsed_input);
-//     }
-// }
-
-// peg::parser!(
-//     r#"
-//     #[pub]
-//     program -> String
-//         = i:int_literal "\n" { i }
-
-//     int_literal -> String
-//         = [0-9]+ { match_str.to_owned() }
-// "#
-// );
-
-unsafe fn codegen(input: String) {
-    let context = llvm::core::LLVMContextCreate();
-    let module = llvm::core::LLVMModuleCreateWithName(b"example_module\0".as_ptr() as *const _);
-    let builder = llvm::core::LLVMCreateBuilderInContext(context);
-
-    // In LLVM, you get your types from functions.
-    let int_type = llvm::core::LLVMInt64TypeInContext(context);
-    let function_type = llvm::core::LLVMFunctionType(int_type, ptr::null_mut(), 0, 0);
-    let function =
-        llvm::core::LLVMAddFunction(module, b"main\0".as_ptr() as *const _, function_type);
-
-    let entry_name = CString::new("entry").unwrap();
-    let bb = llvm::core::LLVMAppendBasicBlockInContext(context, function, entry_name.as_ptr());
-    llvm::core::LLVMPositionBuilderAtEnd(builder, bb);
-
-    // The juicy part: construct a `LLVMValue` from a Rust value:
-    let int_value: u64 = input.parse().unwrap();
-    let int_value = llvm::core::LLVMConstInt(int_type, int_value, 0);
-
-    llvm::core::LLVMBuildRet(builder, int_value);
-
-    // Instead of dumping to stdout, let's write out the IR to `out.ll`
-    let out_file = CString::new("out.ll").unwrap();
-    llvm::core::LLVMPrintModuleToFile(module, out_file.as_ptr(), ptr::null_mut());
-
-    llvm::core::LLVMDisposeBuilder(builder);
-    llvm::core::LLVMDisposeModule(module);
-    llvm::core::LLVMContextDispose(context);
+//把 AST 翻译成 LLVM IR。int 对应 i64，float 对应 double，
+//全局变量初始化为 0，参数和局部变量放在函数入口的 alloca 里。
+//混合运算时 int 先转成 float，赋值时转换成左边变量的类型。
+
+pub struct Codegen {
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    builder: LLVMBuilderRef,
+    globals: HashMap<String, (LLVMValueRef, KeyWord)>,
+    funcs: HashMap<String, (LLVMValueRef, KeyWord, Vec<KeyWord>)>, //函数，返回类型，参数类型
+    locals: HashMap<String, (LLVMValueRef, KeyWord)>,
+    function: LLVMValueRef, //正在生成的函数
+    ret: KeyWord,
+}
+
+impl Codegen {
+    pub fn new(name: &str) -> Codegen {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let context = llvm::core::LLVMContextCreate();
+            Codegen {
+                context,
+                module: llvm::core::LLVMModuleCreateWithNameInContext(name.as_ptr(), context),
+                builder: llvm::core::LLVMCreateBuilderInContext(context),
+                globals: HashMap::new(),
+                funcs: HashMap::new(),
+                locals: HashMap::new(),
+                function: ptr::null_mut(),
+                ret: KeyWord::Int,
+            }
+        }
+    }
+
+    pub fn compile(&mut self, ast: &AST) -> Result<(), String> {
+        unsafe {
+            for spec in ast.global.list.iter() {
+                let ty = self.llvm_type(spec.typ);
+                for ident in spec.names.iter() {
+                    let name = CString::new(ident.name.as_str()).unwrap();
+                    let g = llvm::core::LLVMAddGlobal(self.module, ty, name.as_ptr());
+                    llvm::core::LLVMSetInitializer(g, llvm::core::LLVMConstNull(ty));
+                    self.globals.insert(ident.name.clone(), (g, spec.typ));
+                }
+            }
+            //先声明全部函数，函数体里可以调用后面的函数
+            for f in ast.funcs.iter() {
+                let mut params: Vec<LLVMTypeRef> =
+                    f.params.iter().map(|p| self.llvm_type(p.typ)).collect();
+                let fn_type = llvm::core::LLVMFunctionType(
+                    self.llvm_type(f.typ),
+                    params.as_mut_ptr(),
+                    params.len() as u32,
+                    0,
+                );
+                let name = CString::new(f.fn_name.name.as_str()).unwrap();
+                let function = llvm::core::LLVMAddFunction(self.module, name.as_ptr(), fn_type);
+                let types = f.params.iter().map(|p| p.typ).collect();
+                self.funcs
+                    .insert(f.fn_name.name.clone(), (function, f.typ, types));
+            }
+            for f in ast.funcs.iter() {
+                self.compile_fn(f)?;
+            }
+            self.verify()
+        }
+    }
+
+    unsafe fn compile_fn(&mut self, f: &FuncDecl<StmtNode>) -> Result<(), String> {
+        let (function, ret, _) = self.funcs[&f.fn_name.name].clone();
+        self.function = function;
+        self.ret = ret;
+        self.append_block("entry");
+        self.locals.clear();
+        for (i, p) in f.params.iter().enumerate() {
+            let slot = self.alloca(&p.ident.name, p.typ);
+            llvm::core::LLVMBuildStore(
+                self.builder,
+                llvm::core::LLVMGetParam(function, i as u32),
+                slot,
+            );
+        }
+        self.compile_block(&f.body.list)?;
+        //没有 return 时返回 0
+        if !self.terminated() {
+            llvm::core::LLVMBuildRet(
+                self.builder,
+                llvm::core::LLVMConstNull(self.llvm_type(f.typ)),
+            );
+        }
+        Ok(())
+    }
+
+    //块里定义的局部变量在块结束后不可见
+    unsafe fn compile_block(&mut self, list: &[StmtNode]) -> Result<(), String> {
+        let saved = self.locals.clone();
+        for stmt in list.iter() {
+            self.compile_stmt(stmt)?;
+        }
+        self.locals = saved;
+        Ok(())
+    }
+
+    unsafe fn compile_stmt(&mut self, stmt: &StmtNode) -> Result<(), String> {
+        //return 之后的语句放进一个不可达的新块
+        if self.terminated() {
+            self.append_block("dead");
+        }
+        match stmt {
+            StmtNode::ValueSepc(spec) => {
+                for ident in spec.names.iter() {
+                    let slot = self.alloca(&ident.name, spec.typ);
+                    let zero = llvm::core::LLVMConstNull(self.llvm_type(spec.typ));
+                    llvm::core::LLVMBuildStore(self.builder, zero, slot);
+                }
+            }
+            StmtNode::AssignStmt(assign) => {
+                let (slot, typ) = match &assign.x {
+                    ExprNode::IdentExpr(ident) => self.lookup(&ident.name)?,
+                    _ => return Err("left side of `=` must be a variable".to_owned()),
+                };
+                let (v, vt) = self.compile_expr(&assign.y)?;
+                let v = self.convert(v, vt, typ);
+                llvm::core::LLVMBuildStore(self.builder, v, slot);
+            }
+            StmtNode::ExprStmt(x) => {
+                self.compile_expr(x)?;
+            }
+            StmtNode::ReturnStmt(ret) => {
+                let v = match &ret.x {
+                    Some(x) => {
+                        let (v, vt) = self.compile_expr(x)?;
+                        self.convert(v, vt, self.ret)
+                    }
+                    None => llvm::core::LLVMConstNull(self.llvm_type(self.ret)),
+                };
+                llvm::core::LLVMBuildRet(self.builder, v);
+            }
+            StmtNode::BlockStmt(block) => self.compile_block(&block.list)?,
+            StmtNode::IfStmt(s) => {
+                let cond = self.compile_cond(&s.cond)?;
+                let then = self.new_block("then");
+                let els = self.new_block("else");
+                let end = self.new_block("endif");
+                llvm::core::LLVMBuildCondBr(self.builder, cond, then, els);
+                self.enter_block(then);
+                self.compile_block(&s.body.list)?;
+                self.branch(end);
+                self.enter_block(els);
+                if let Some(stmt) = &s.els {
+                    self.compile_stmt(stmt)?;
+                }
+                self.branch(end);
+                self.enter_block(end);
+            }
+            StmtNode::WhileStmt(s) => {
+                let head = self.new_block("while");
+                let body = self.new_block("body");
+                let end = self.new_block("endwhile");
+                self.branch(head);
+                self.enter_block(head);
+                let cond = self.compile_cond(&s.cond)?;
+                llvm::core::LLVMBuildCondBr(self.builder, cond, body, end);
+                self.enter_block(body);
+                self.compile_block(&s.body.list)?;
+                self.branch(head);
+                self.enter_block(end);
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn compile_expr(&mut self, x: &ExprNode) -> Result<(LLVMValueRef, KeyWord), String> {
+        let tmp = b"\0".as_ptr() as *const _;
+        match x {
+            ExprNode::IdentExpr(ident) => {
+                let (slot, typ) = self.lookup(&ident.name)?;
+                Ok((llvm::core::LLVMBuildLoad(self.builder, slot, tmp), typ))
+            }
+            ExprNode::BasicLit(lit) => match &lit.value {
+                Token::Number(n) => {
+                    let ty = self.llvm_type(KeyWord::Int);
+                    Ok((llvm::core::LLVMConstInt(ty, *n as u64, 1), KeyWord::Int))
+                }
+                Token::Float(n) => {
+                    let ty = self.llvm_type(KeyWord::Float);
+                    Ok((llvm::core::LLVMConstReal(ty, *n), KeyWord::Float))
+                }
+                t => Err(format!("unsupported literal {:?}", t)),
+            },
+            ExprNode::ParenExpr(p) => self.compile_expr(&p.x),
+            ExprNode::UnaryExpr(u) => {
+                let (v, typ) = self.compile_expr(&u.x)?;
+                match (&u.op, typ) {
+                    (Token::Oper(Operator::Add), _) => Ok((v, typ)),
+                    (Token::Oper(Operator::Sub), KeyWord::Float) => {
+                        Ok((llvm::core::LLVMBuildFNeg(self.builder, v, tmp), typ))
+                    }
+                    (Token::Oper(Operator::Sub), _) => {
+                        Ok((llvm::core::LLVMBuildNeg(self.builder, v, tmp), typ))
+                    }
+                    (Token::Oper(Operator::BitNot), KeyWord::Int) => {
+                        Ok((llvm::core::LLVMBuildNot(self.builder, v, tmp), typ))
+                    }
+                    (Token::Oper(Operator::LogicNot), _) => {
+                        let zero = llvm::core::LLVMConstNull(self.llvm_type(typ));
+                        let op = Token::Oper(Operator::Equal);
+                        let v = self.compare(&op, typ, v, zero).unwrap();
+                        let int = self.llvm_type(KeyWord::Int);
+                        Ok((
+                            llvm::core::LLVMBuildZExt(self.builder, v, int, tmp),
+                            KeyWord::Int,
+                        ))
+                    }
+                    (op, _) => Err(format!("unsupported unary operator {:?}", op)),
+                }
+            }
+            ExprNode::BinaryExpr(b) => {
+                let (x, xt) = self.compile_expr(&b.x)?;
+                let (y, yt) = self.compile_expr(&b.y)?;
+                let typ = if xt == KeyWord::Float || yt == KeyWord::Float {
+                    KeyWord::Float
+                } else {
+                    KeyWord::Int
+                };
+                let x = self.convert(x, xt, typ);
+                let y = self.convert(y, yt, typ);
+                if let Some(v) = self.compare(&b.op, typ, x, y) {
+                    //比较的结果是 int 的 0 或 1
+                    let int = self.llvm_type(KeyWord::Int);
+                    return Ok((
+                        llvm::core::LLVMBuildZExt(self.builder, v, int, tmp),
+                        KeyWord::Int,
+                    ));
+                }
+                let build = match (&b.op, typ) {
+                    (Token::Oper(Operator::Add), KeyWord::Float) => llvm::core::LLVMBuildFAdd,
+                    (Token::Oper(Operator::Sub), KeyWord::Float) => llvm::core::LLVMBuildFSub,
+                    (Token::Oper(Operator::Star), KeyWord::Float) => llvm::core::LLVMBuildFMul,
+                    (Token::Oper(Operator::Div), KeyWord::Float) => llvm::core::LLVMBuildFDiv,
+                    (Token::Oper(Operator::Mod), KeyWord::Float) => llvm::core::LLVMBuildFRem,
+                    (Token::Oper(Operator::Add), _) => llvm::core::LLVMBuildAdd,
+                    (Token::Oper(Operator::Sub), _) => llvm::core::LLVMBuildSub,
+                    (Token::Oper(Operator::Star), _) => llvm::core::LLVMBuildMul,
+                    (Token::Oper(Operator::Div), _) => llvm::core::LLVMBuildSDiv,
+                    (Token::Oper(Operator::Mod), _) => llvm::core::LLVMBuildSRem,
+                    (Token::Oper(Operator::BitAnd), KeyWord::Int) => llvm::core::LLVMBuildAnd,
+                    (Token::Oper(Operator::BitOr), KeyWord::Int) => llvm::core::LLVMBuildOr,
+                    (Token::Oper(Operator::BitXor), KeyWord::Int) => llvm::core::LLVMBuildXor,
+                    (Token::Oper(Operator::BitShiftLeft), KeyWord::Int) => llvm::core::LLVMBuildShl,
+                    (Token::Oper(Operator::BitShiftRight), KeyWord::Int) => {
+                        llvm::core::LLVMBuildAShr
+                    }
+                    (op, _) => return Err(format!("unsupported binary operator {:?}", op)),
+                };
+                Ok((build(self.builder, x, y, tmp), typ))
+            }
+            ExprNode::CallExpr(call) => {
+                let name = match &*call.fun {
+                    ExprNode::IdentExpr(ident) => ident.name.as_str(),
+                    _ => return Err("only named functions can be called".to_owned()),
+                };
+                let (function, ret, params) = match self.funcs.get(name) {
+                    Some(f) => f.clone(),
+                    None if is_builtin(name) => {
+                        return Err(format!("builtin `{}` is only available on the VM", name))
+                    }
+                    None => return Err(format!("`{}` is not a function", name)),
+                };
+                if params.len() != call.args.len() {
+                    return Err(format!(
+                        "`{}` takes {} argument(s) but {} were given",
+                        name,
+                        params.len(),
+                        call.args.len()
+                    ));
+                }
+                let mut args = Vec::new();
+                for (arg, typ) in call.args.iter().zip(params.iter()) {
+                    let (v, vt) = self.compile_expr(arg)?;
+                    args.push(self.convert(v, vt, *typ));
+                }
+                let v = llvm::core::LLVMBuildCall(
+                    self.builder,
+                    function,
+                    args.as_mut_ptr(),
+                    args.len() as u32,
+                    tmp,
+                );
+                Ok((v, ret))
+            }
+        }
+    }
+
+    //比较运算，返回 i1；不是比较运算符时返回 None
+    unsafe fn compare(
+        &self,
+        op: &Token,
+        typ: KeyWord,
+        x: LLVMValueRef,
+        y: LLVMValueRef,
+    ) -> Option<LLVMValueRef> {
+        use llvm::LLVMIntPredicate::*;
+        use llvm::LLVMRealPredicate::*;
+        let (i, f) = match op {
+            Token::Oper(Operator::Equal) => (LLVMIntEQ, LLVMRealOEQ),
+            Token::Oper(Operator::NotEqual) => (LLVMIntNE, LLVMRealUNE),
+            Token::Oper(Operator::Less) => (LLVMIntSLT, LLVMRealOLT),
+            Token::Oper(Operator::Greate) => (LLVMIntSGT, LLVMRealOGT),
+            Token::Oper(Operator::LessEqual) => (LLVMIntSLE, LLVMRealOLE),
+            Token::Oper(Operator::GreateEqual) => (LLVMIntSGE, LLVMRealOGE),
+            _ => return None,
+        };
+        let tmp = b"\0".as_ptr() as *const _;
+        Some(match typ {
+            KeyWord::Float => llvm::core::LLVMBuildFCmp(self.builder, f, x, y, tmp),
+            _ => llvm::core::LLVMBuildICmp(self.builder, i, x, y, tmp),
+        })
+    }
+
+    //条件表达式不等于 0 时为真
+    unsafe fn compile_cond(&mut self, x: &ExprNode) -> Result<LLVMValueRef, String> {
+        let (v, typ) = self.compile_expr(x)?;
+        let zero = llvm::core::LLVMConstNull(self.llvm_type(typ));
+        let op = Token::Oper(Operator::NotEqual);
+        Ok(self.compare(&op, typ, v, zero).unwrap())
+    }
+
+    unsafe fn new_block(&self, name: &str) -> LLVMBasicBlockRef {
+        let name = CString::new(name).unwrap();
+        llvm::core::LLVMAppendBasicBlockInContext(self.context, self.function, name.as_ptr())
+    }
+
+    unsafe fn append_block(&mut self, name: &str) {
+        let bb = self.new_block(name);
+        self.enter_block(bb);
+    }
+
+    unsafe fn enter_block(&mut self, bb: LLVMBasicBlockRef) {
+        llvm::core::LLVMPositionBuilderAtEnd(self.builder, bb);
+    }
+
+    //当前块还没结束时跳到 bb
+    unsafe fn branch(&mut self, bb: LLVMBasicBlockRef) {
+        if !self.terminated() {
+            llvm::core::LLVMBuildBr(self.builder, bb);
+        }
+    }
+
+    unsafe fn terminated(&self) -> bool {
+        let bb = llvm::core::LLVMGetInsertBlock(self.builder);
+        !llvm::core::LLVMGetBasicBlockTerminator(bb).is_null()
+    }
+
+    unsafe fn lookup(&self, name: &str) -> Result<(LLVMValueRef, KeyWord), String> {
+        match self.locals.get(name).or_else(|| self.globals.get(name)) {
+            Some(v) => Ok(*v),
+            None => Err(format!("undefined name `{}`", name)),
+        }
+    }
+
+    //alloca 都放在入口块的开头，循环里定义的变量不会让栈一直增长
+    unsafe fn alloca(&mut self, name: &str, typ: KeyWord) -> LLVMValueRef {
+        let cname = CString::new(name).unwrap();
+        let entry = llvm::core::LLVMGetEntryBasicBlock(self.function);
+        let builder = llvm::core::LLVMCreateBuilderInContext(self.context);
+        let first = llvm::core::LLVMGetFirstInstruction(entry);
+        if first.is_null() {
+            llvm::core::LLVMPositionBuilderAtEnd(builder, entry);
+        } else {
+            llvm::core::LLVMPositionBuilderBefore(builder, first);
+        }
+        let slot = llvm::core::LLVMBuildAlloca(builder, self.llvm_type(typ), cname.as_ptr());
+        llvm::core::LLVMDisposeBuilder(builder);
+        self.locals.insert(name.to_owned(), (slot, typ));
+        slot
+    }
+
+    unsafe fn convert(&self, v: LLVMValueRef, from: KeyWord, to: KeyWord) -> LLVMValueRef {
+        let tmp = b"\0".as_ptr() as *const _;
+        match (from, to) {
+            (KeyWord::Int, KeyWord::Float) => {
+                llvm::core::LLVMBuildSIToFP(self.builder, v, self.llvm_type(to), tmp)
+            }
+            (KeyWord::Float, KeyWord::Int) => {
+                llvm::core::LLVMBuildFPToSI(self.builder, v, self.llvm_type(to), tmp)
+            }
+            _ => v,
+        }
+    }
+
+    unsafe fn llvm_type(&self, typ: KeyWord) -> LLVMTypeRef {
+        match typ {
+            KeyWord::Float => llvm::core::LLVMDoubleTypeInContext(self.context),
+            _ => llvm::core::LLVMInt64TypeInContext(self.context),
+        }
+    }
+
+    unsafe fn verify(&self) -> Result<(), String> {
+        let mut msg = ptr::null_mut();
+        let failed = llvm::analysis::LLVMVerifyModule(
+            self.module,
+            llvm::analysis::LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            &mut msg,
+        );
+        let text = take_message(msg);
+        if failed != 0 {
+            return Err(text);
+        }
+        Ok(())
+    }
+
+    //文本形式的 IR
+    pub fn ir(&self) -> String {
+        unsafe { take_message(llvm::core::LLVMPrintModuleToString(self.module)) }
+    }
+
+    //按本机目标生成目标文件
+    pub fn write_object(&self, path: &str) -> Result<(), String> {
+        unsafe {
+            if llvm::target::LLVM_InitializeNativeTarget() != 0
+                || llvm::target::LLVM_InitializeNativeAsmPrinter() != 0
+            {
+                return Err("failed to initialize the native target".to_owned());
+            }
+            let triple = LLVMGetDefaultTargetTriple();
+            let mut target = ptr::null_mut();
+            let mut msg = ptr::null_mut();
+            if LLVMGetTargetFromTriple(triple, &mut target, &mut msg) != 0 {
+                llvm::core::LLVMDisposeMessage(triple);
+                return Err(take_message(msg));
+            }
+            let empty = b"\0".as_ptr() as *const _;
+            let machine = LLVMCreateTargetMachine(
+                target,
+                triple,
+                empty,
+                empty,
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            );
+            llvm::core::LLVMSetTarget(self.module, triple);
+            llvm::core::LLVMDisposeMessage(triple);
+            let path = CString::new(path).unwrap();
+            let failed = LLVMTargetMachineEmitToFile(
+                machine,
+                self.module,
+                path.as_ptr() as *mut _,
+                LLVMCodeGenFileType::LLVMObjectFile,
+                &mut msg,
+            );
+            LLVMDisposeTargetMachine(machine);
+            if failed != 0 {
+                return Err(take_message(msg));
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Codegen {
+    fn drop(&mut self) {
+        unsafe {
+            llvm::core::LLVMDisposeBuilder(self.builder);
+            llvm::core::LLVMDisposeModule(self.module);
+            llvm::core::LLVMContextDispose(self.context);
+        }
+    }
 }
 
-unsafe fn codegen_expr() {}
+//LLVM 返回的字符串要用 LLVMDisposeMessage 释放
+unsafe fn take_message(msg: *mut c_char) -> String {
+    if msg.is_null() {
+        return String::new();
+    }
+    let s = CStr::from_ptr(msg).to_string_lossy().into_owned();
+    llvm::core::LLVMDisposeMessage(msg);
+    s
+}
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::OpenOptions;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+
     #[test]
-    fn test_parser() {
+    fn test_codegen() {
         let s = "
         var int a,c;
-        fn int b(int d,int e){ 
+        fn float b(int d,float e){
             var int f;
-            f = a + b * (c + e);
+            f = a + d * (c + e);
+            e = -f;
         }
         ";
-        let mut lexer = DefaultLexer::new(s.as_bytes());
-        let mut parser = Parser::new(lexer);
-        parser.parse_func_body();
-        // loop {
-        //     let m = lexer.lex();
-        //     match m {
-        //         Ok(c) => println!("{:?}", c),
-        //         Err(e) => {
-        //             println!("{:?}", e);
-        //             break;
-        //         }
-        //     }
-        // }
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let mut cg = Codegen::new("test");
+        cg.compile(&ast).unwrap();
+        let ir = cg.ir();
+        assert!(ir.contains("@a = global i64 0"));
+        assert!(ir.contains("define double @b(i64 %0, double %1)"));
+        assert!(ir.contains("sitofp"));
+        assert!(ir.contains("fptosi"));
+        assert!(ir.contains("ret double 0.000000e+00"));
     }
 }
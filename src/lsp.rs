@@ -0,0 +1,561 @@
+use crate::incr::{Document, TextEdit};
+use crate::lexer::KEY_WORD;
+use crate::semantic::{self, Analysis, SymbolKind};
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References,
+    Request as LspRequest,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, InitializeParams, Location, MarkupContent, MarkupKind, OneOf,
+    Position, PublishDiagnosticsParams, Range, ReferenceParams, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+//语言服务器，基于 lexer、parser、cst 和 semantic，通过 stdio 收发 LSP 消息。
+//文档用增量方式同步，每次修改交给 incr::Document 只重新解析受影响的声明。
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::INCREMENTAL,
+        )),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    }
+}
+
+pub fn run() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    serve(&connection)?;
+    //先关掉连接，写线程才会退出
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+pub fn serve(connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let caps = serde_json::to_value(capabilities())?;
+    let params = connection.initialize(caps)?;
+    let _: InitializeParams = serde_json::from_value(params)?;
+    let mut server = Server::new();
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    return Ok(());
+                }
+                let resp = server.handle_request(req);
+                connection.sender.send(Message::Response(resp))?;
+            }
+            Message::Notification(not) => {
+                for n in server.handle_notification(not) {
+                    connection.sender.send(Message::Notification(n))?;
+                }
+            }
+            Message::Response(_) => (),
+        }
+    }
+    Ok(())
+}
+
+pub struct Server {
+    docs: HashMap<Url, Document>,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            docs: HashMap::new(),
+        }
+    }
+
+    pub fn handle_request(&mut self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            HoverRequest::METHOD => self.with_params(req, |s, p| s.hover(p)),
+            GotoDefinition::METHOD => self.with_params(req, |s, p| s.definition(p)),
+            References::METHOD => self.with_params(req, |s, p| s.references(p)),
+            DocumentSymbolRequest::METHOD => self.with_params(req, |s, p| s.document_symbols(p)),
+            Completion::METHOD => self.with_params(req, |s, p| s.completion(p)),
+            _ => {
+                return Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("unknown request {}", req.method),
+                )
+            }
+        };
+        match result {
+            Ok(v) => Response::new_ok(id, v),
+            Err(e) => Response::new_err(id, lsp_server::ErrorCode::InvalidParams as i32, e),
+        }
+    }
+
+    fn with_params<P, R, F>(&mut self, req: Request, f: F) -> Result<serde_json::Value, String>
+    where
+        P: serde::de::DeserializeOwned,
+        R: serde::Serialize,
+        F: FnOnce(&mut Server, P) -> Option<R>,
+    {
+        let params: P = serde_json::from_value(req.params).map_err(|e| e.to_string())?;
+        serde_json::to_value(f(self, params)).map_err(|e| e.to_string())
+    }
+
+    //返回需要发给客户端的诊断通知
+    pub fn handle_notification(&mut self, not: Notification) -> Vec<Notification> {
+        let uri = match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let p: DidOpenTextDocumentParams = match serde_json::from_value(not.params) {
+                    Ok(p) => p,
+                    Err(_) => return Vec::new(),
+                };
+                let uri = p.text_document.uri;
+                self.docs
+                    .insert(uri.clone(), Document::new(p.text_document.text));
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let p: DidChangeTextDocumentParams = match serde_json::from_value(not.params) {
+                    Ok(p) => p,
+                    Err(_) => return Vec::new(),
+                };
+                let uri = p.text_document.uri;
+                let doc = match self.docs.get_mut(&uri) {
+                    Some(doc) => doc,
+                    None => return Vec::new(),
+                };
+                for change in p.content_changes {
+                    match change.range {
+                        Some(range) => {
+                            let start = offset(doc.text(), range.start);
+                            let end = offset(doc.text(), range.end);
+                            doc.edit(TextEdit {
+                                start,
+                                end,
+                                text: change.text,
+                            });
+                        }
+                        None => *doc = Document::new(change.text),
+                    }
+                }
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                if let Ok(p) = serde_json::from_value::<DidCloseTextDocumentParams>(not.params) {
+                    self.docs.remove(&p.text_document.uri);
+                }
+                return Vec::new();
+            }
+            _ => return Vec::new(),
+        };
+        let params = PublishDiagnosticsParams {
+            diagnostics: self.diagnostics(&uri),
+            uri,
+            version: None,
+        };
+        vec![Notification::new(
+            PublishDiagnostics::METHOD.to_owned(),
+            params,
+        )]
+    }
+
+    pub fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        let doc = match self.docs.get(uri) {
+            Some(doc) => doc,
+            None => return Vec::new(),
+        };
+        let text = doc.text();
+        let mut list: Vec<Diagnostic> = doc
+            .errors()
+            .iter()
+            .map(|e| {
+                let end = next_char(text, e.offset);
+                diagnostic(text, e.offset..end, e.err.to_string())
+            })
+            .collect();
+        for d in semantic::analyze(doc.tree()).diagnostics {
+            list.push(diagnostic(text, d.range, d.message));
+        }
+        list
+    }
+
+    fn analysis(&self, uri: &Url, pos: Position) -> Option<(&Document, Analysis, usize)> {
+        let doc = self.docs.get(uri)?;
+        let at = offset(doc.text(), pos);
+        Some((doc, semantic::analyze(doc.tree()), at))
+    }
+
+    fn hover(&mut self, p: HoverParams) -> Option<Hover> {
+        let pos = p.text_document_position_params;
+        let (doc, a, at) = self.analysis(&pos.text_document.uri, pos.position)?;
+        let sym = &a.symbols[a.symbol_at(at)?];
+        let mut value = format!("```tars\n{}\n```", sym.detail);
+        if let Some(d) = &sym.doc {
+            value.push_str("\n\n");
+            value.push_str(d);
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range(doc.text(), sym.def.clone())),
+        })
+    }
+
+    fn definition(&mut self, p: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let pos = p.text_document_position_params;
+        let uri = pos.text_document.uri;
+        let (doc, a, at) = self.analysis(&uri, pos.position)?;
+        let sym = &a.symbols[a.symbol_at(at)?];
+        Some(GotoDefinitionResponse::Scalar(Location {
+            uri: uri.clone(),
+            range: range(doc.text(), sym.def.clone()),
+        }))
+    }
+
+    fn references(&mut self, p: ReferenceParams) -> Option<Vec<Location>> {
+        let pos = p.text_document_position;
+        let uri = pos.text_document.uri;
+        let (doc, a, at) = self.analysis(&uri, pos.position)?;
+        let sym = a.symbol_at(at)?;
+        let mut list = Vec::new();
+        if p.context.include_declaration {
+            list.push(a.symbols[sym].def.clone());
+        }
+        list.append(&mut a.references(sym));
+        Some(
+            list.into_iter()
+                .map(|r| Location {
+                    uri: uri.clone(),
+                    range: range(doc.text(), r),
+                })
+                .collect(),
+        )
+    }
+
+    #[allow(deprecated)]
+    fn document_symbols(&mut self, p: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let doc = self.docs.get(&p.text_document.uri)?;
+        let text = doc.text();
+        let a = semantic::analyze(doc.tree());
+        let symbol = |s: &semantic::Symbol, children: Option<Vec<DocumentSymbol>>| {
+            let kind = match s.kind {
+                SymbolKind::Func => lsp_types::SymbolKind::FUNCTION,
+                _ => lsp_types::SymbolKind::VARIABLE,
+            };
+            DocumentSymbol {
+                name: s.name.clone(),
+                detail: Some(s.detail.clone()),
+                kind,
+                tags: None,
+                deprecated: None,
+                range: range(text, s.def.clone()),
+                selection_range: range(text, s.def.clone()),
+                children,
+            }
+        };
+        let mut list = Vec::new();
+        for (i, s) in a.symbols.iter().enumerate() {
+            match s.kind {
+                SymbolKind::Global => list.push(symbol(s, None)),
+                SymbolKind::Func => {
+                    //函数的参数和局部变量作为子符号
+                    let children = a
+                        .symbols
+                        .iter()
+                        .filter(|x| x.parent == Some(i))
+                        .map(|x| symbol(x, None))
+                        .collect();
+                    list.push(symbol(s, Some(children)));
+                }
+                _ => (),
+            }
+        }
+        Some(DocumentSymbolResponse::Nested(list))
+    }
+
+    fn completion(&mut self, p: CompletionParams) -> Option<CompletionResponse> {
+        let pos = p.text_document_position;
+        let (_, a, at) = self.analysis(&pos.text_document.uri, pos.position)?;
+        let mut items: Vec<CompletionItem> = a
+            .visible_at(at)
+            .into_iter()
+            .map(|s| CompletionItem {
+                label: s.name.clone(),
+                kind: Some(match s.kind {
+                    SymbolKind::Func => CompletionItemKind::FUNCTION,
+                    _ => CompletionItemKind::VARIABLE,
+                }),
+                detail: Some(s.detail.clone()),
+                ..CompletionItem::default()
+            })
+            .collect();
+        for (k, _) in KEY_WORD.iter() {
+            items.push(CompletionItem {
+                label: k.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                ..CompletionItem::default()
+            });
+        }
+        Some(CompletionResponse::Array(items))
+    }
+}
+
+fn diagnostic(text: &str, r: std::ops::Range<usize>, message: String) -> Diagnostic {
+    Diagnostic {
+        range: range(text, r),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("tars".to_owned()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+fn next_char(text: &str, offset: usize) -> usize {
+    match text[offset..].chars().next() {
+        Some(c) => offset + c.len_utf8(),
+        None => offset,
+    }
+}
+
+//LSP 的位置是行号加 UTF-16 列号
+pub fn position(text: &str, offset: usize) -> Position {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Position {
+        line: text[..line_start].matches('\n').count() as u32,
+        character: text[line_start..offset].encode_utf16().count() as u32,
+    }
+}
+
+pub fn offset(text: &str, pos: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..pos.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= pos.character as usize || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, r: std::ops::Range<usize>) -> Range {
+    Range {
+        start: position(text, r.start),
+        end: position(text, r.end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_server::RequestId;
+    use lsp_types::{
+        TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+        TextDocumentPositionParams, VersionedTextDocumentIdentifier,
+    };
+
+    fn request<R: LspRequest>(s: &mut Server, params: R::Params) -> serde_json::Value {
+        let resp = s.handle_request(Request::new(
+            RequestId::from(1),
+            R::METHOD.to_owned(),
+            params,
+        ));
+        resp.result.unwrap()
+    }
+
+    fn at(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position: Position { line, character },
+        }
+    }
+
+    #[test]
+    fn test_lsp_server() {
+        let uri = Url::parse("file:///tmp/a.tars").unwrap();
+        let text = "/// 计数\nvar int count;\nfn int f(int x) {\n  count = x + y;\n}\n";
+        let mut s = Server::new();
+        let open = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: "tars".to_owned(),
+                version: 1,
+                text: text.to_owned(),
+            },
+        };
+        let out = s.handle_notification(Notification::new(
+            DidOpenTextDocument::METHOD.to_owned(),
+            open,
+        ));
+        let diags: PublishDiagnosticsParams =
+            serde_json::from_value(out[0].params.clone()).unwrap();
+        assert_eq!(diags.diagnostics.len(), 1);
+        assert_eq!(
+            diags.diagnostics[0].range.start,
+            Position {
+                line: 3,
+                character: 14
+            }
+        );
+
+        let hover = request::<HoverRequest>(
+            &mut s,
+            HoverParams {
+                text_document_position_params: at(&uri, 3, 3),
+                work_done_progress_params: Default::default(),
+            },
+        );
+        let value = hover["contents"]["value"].as_str().unwrap();
+        assert_eq!(value, "```tars\nvar int count\n```\n\n计数");
+
+        let def = request::<GotoDefinition>(
+            &mut s,
+            GotoDefinitionParams {
+                text_document_position_params: at(&uri, 3, 10),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            },
+        );
+        let def: Location = serde_json::from_value(def).unwrap();
+        assert_eq!(
+            def.range.start,
+            Position {
+                line: 2,
+                character: 13
+            }
+        );
+
+        // 修改 y 为 x 后诊断消失
+        let change = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: 2,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position {
+                        line: 3,
+                        character: 14,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 15,
+                    },
+                }),
+                range_length: None,
+                text: "x".to_owned(),
+            }],
+        };
+        let out = s.handle_notification(Notification::new(
+            DidChangeTextDocument::METHOD.to_owned(),
+            change,
+        ));
+        let diags: PublishDiagnosticsParams =
+            serde_json::from_value(out[0].params.clone()).unwrap();
+        assert!(diags.diagnostics.is_empty());
+
+        let refs = request::<References>(
+            &mut s,
+            ReferenceParams {
+                text_document_position: at(&uri, 2, 13),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: lsp_types::ReferenceContext {
+                    include_declaration: true,
+                },
+            },
+        );
+        assert_eq!(refs.as_array().unwrap().len(), 3);
+
+        let symbols = request::<DocumentSymbolRequest>(
+            &mut s,
+            DocumentSymbolParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            },
+        );
+        assert_eq!(symbols[0]["name"], "count");
+        assert_eq!(symbols[1]["name"], "f");
+        assert_eq!(symbols[1]["children"][0]["name"], "x");
+
+        let items = request::<Completion>(
+            &mut s,
+            CompletionParams {
+                text_document_position: at(&uri, 3, 2),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            },
+        );
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"count") && labels.contains(&"x") && labels.contains(&"return"));
+    }
+
+    #[test]
+    fn test_lsp_position() {
+        let text = "a😀b\nc";
+        assert_eq!(
+            position(text, 5),
+            Position {
+                line: 0,
+                character: 3
+            }
+        );
+        assert_eq!(
+            offset(
+                text,
+                Position {
+                    line: 0,
+                    character: 3
+                }
+            ),
+            5
+        );
+        assert_eq!(
+            offset(
+                text,
+                Position {
+                    line: 1,
+                    character: 0
+                }
+            ),
+            7
+        );
+    }
+}
@@ -1,68 +1,677 @@
-mod ast;
-mod lexer;
-mod llvm;
-mod parser;
-mod vm;
-use lexer::DefaultLexer;
+use lina::asm;
+use lina::bytecode;
+use lina::compiler;
+use lina::debug::Debugger;
+use lina::fmt::{format, FmtOptions};
+use lina::lexer::{lexer, DefaultLexer, Token};
+use lina::llvm::Codegen;
+use lina::parser::{line_col, Parser};
+use lina::profile::SAMPLE_INTERVAL;
+use lina::repl::Repl;
+use lina::semantic;
+use lina::verify;
+use lina::vm::{self, ErrorKind, Limits, Program, Value, VmResult, VM};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 use std::env;
-use std::fs::OpenOptions;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::Duration;
 
-fn open(pathname: *mut u8) {}
+//退出码：0 成功，1 源码有错误（或 fmt --check 发现没格式化的文件），
+//2 用法错误或读写文件失败，3 运行时错误
+const USAGE: &str = "usage: tars <command> [options] [file]
+
+commands:
+    tokens [file]              print the token stream
+    ast [file]                 print the syntax tree
+    check [file]               report syntax and name errors
+    run [--max-depth n] [--gc-stats] [--gc-stress] [--profile] [--folded out] [file]
+                               execute on the VM, failing past n nested calls;
+                               print heap statistics or collect on every allocation;
+                               print instruction counts per function and opcode, or
+                               write sampled call stacks in flamegraph folded format;
+                               [--fuel n] [--timeout ms] [--max-memory bytes] [--allow f,g]
+                               limit instructions, time and memory, or the natives a
+                               script may call;
+                               a `.tbc` file is loaded as precompiled bytecode
+    debug file                 run under the debugger, `help` at its prompt lists the commands
+    build [--emit ir|obj|exe|tbc] [-o out] [file]
+                               compile with LLVM (default: exe), or to VM bytecode
+    disasm [file]              print the VM bytecode of a source or `.tbc` file
+    asm [-o out] [file]        assemble a bytecode listing into a `.tbc` file
+    fmt [--check] [--split-var] [files...]
+                               format files in place
+    repl                       interactive prompt
+
+Without a file, the source is read from stdin.
+
+Exit status: 0 on success, 1 for errors in the source, 2 for usage or I/O
+errors, 3 for runtime errors. `run` and `debug` exit with main's return value
+(or the argument of `exit`) masked to 0..=255 instead when the program finishes.";
 
 fn main() {
-    // let args: Vec<String> = env::args().collect();
-    // let filename = &args[1];
-    // let f = OpenOptions::new().read(true).open(&filename).unwrap();
-    // let mut lexer = DefaultLexer::new(f);
-
-    // while let Ok(c) = lexer.lex() {
-    //     println!("{:?}", c);
-    // }
-    //  let size = 8192; // 256*1024/32
-    // unsafe {
-    //     let text: *mut u64 = alloc::alloc(Layout::from_size_align_unchecked(
-    //         4096 * mem::size_of::<u64>(),
-    //         mem::size_of::<u64>(),
-    //     )) as *mut u64;
-    //     let stack: *mut u64 = alloc::alloc(Layout::from_size_align_unchecked(
-    //         4096 * mem::size_of::<u64>(),
-    //         mem::size_of::<u64>(),
-    //     )) as *mut u64;
-    //     let data: *mut u64 = alloc::alloc(Layout::from_size_align_unchecked(
-    //         4096 * mem::size_of::<u64>(),
-    //         mem::size_of::<u64>(),
-    //     )) as *mut u64;
-    //     let sp = stack.add(4096);
-    //     let slice: &mut [u64] = slice::from_raw_parts_mut(text, 64);
-    //     let mut i: usize = 1;
-    //     slice[i] = Instruction::IMM as u64;
-    //     i += 1;
-    //     slice[i] = 10;
-    //     i += 1;
-    //     slice[i] = Instruction::PUSH as u64;
-    //     i += 1;
-    //     slice[i] = Instruction::IMM as u64;
-    //     i += 1;
-    //     slice[i] = 20;
-    //     i += 1;
-    //     slice[i] = Instruction::ADD as u64;
-    //     i += 1;
-    //     slice[i] = Instruction::PUSH as u64;
-    //     i += 1;
-    //     slice[i] = Instruction::EXIT as u64;
-    //     let mut vm = VM {
-    //         pc: text,
-    //         sp: sp,
-    //         bp: sp,
-    //         ax: 0,
-    //         text: text,
-    //         old_text: text,
-    //         stack: stack,
-    //         data: data,
-    //     };
-    //     vm.eval();
-    //     println!("ax : {}", vm.ax);
-    // };
-}
-
-mod tests {}
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let rest = &args[2..];
+    let code = match args[1].as_str() {
+        "tokens" => tokens(rest),
+        "ast" => ast(rest),
+        "check" => check(rest),
+        "run" => run(rest),
+        "debug" => debug(rest),
+        "build" => build(rest),
+        "disasm" => disasm(rest),
+        "asm" => assemble(rest),
+        "fmt" => fmt(rest),
+        "repl" => repl(),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            0
+        }
+        cmd => {
+            eprintln!("tars: unknown command `{}`\n\n{}", cmd, USAGE);
+            2
+        }
+    };
+    process::exit(code);
+}
+
+//一个源文件，name 用在错误信息里
+struct Source {
+    name: String,
+    text: String,
+}
+
+//读文件，没有给出文件或者是 `-` 时读标准输入
+fn read_source(args: &[String]) -> Result<Source, i32> {
+    let files: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    if files.len() > 1 {
+        eprintln!("tars: expected at most one file\n\n{}", USAGE);
+        return Err(2);
+    }
+    let mut text = String::new();
+    let name = match files.first() {
+        Some(f) if f.as_str() != "-" => {
+            text = fs::read_to_string(f).map_err(|e| {
+                eprintln!("{}: {}", f, e);
+                2
+            })?;
+            f.to_string()
+        }
+        _ => {
+            io::stdin().read_to_string(&mut text).map_err(|e| {
+                eprintln!("<stdin>: {}", e);
+                2
+            })?;
+            "<stdin>".to_owned()
+        }
+    };
+    Ok(Source { name, text })
+}
+
+fn report(src: &Source, offset: usize, msg: &dyn std::fmt::Display) {
+    let (line, col) = line_col(&src.text, offset);
+    eprintln!("{}:{}:{}: error: {}", src.name, line, col, msg);
+}
+
+//语法检查加名字解析，有错误时全部打印出来并返回 false
+fn diagnose(src: &Source) -> bool {
+    let (tree, errors) = Parser::new(DefaultLexer::new(src.text.as_bytes())).parse_tree();
+    for e in errors.iter() {
+        report(src, e.offset, &e.err);
+    }
+    if !errors.is_empty() {
+        return false;
+    }
+    let analysis = semantic::analyze(&tree);
+    for d in analysis.diagnostics.iter() {
+        report(src, d.range.start, &d.message);
+    }
+    analysis.diagnostics.is_empty()
+}
+
+fn tokens(args: &[String]) -> i32 {
+    let src = match read_source(args) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let mut lex = DefaultLexer::new(src.text.as_bytes());
+    let mut code = 0;
+    loop {
+        match lex.lex_with_trivia() {
+            Ok(l) if l.tok == Token::Eof => break,
+            Ok(l) => println!("{}:{}\t{:?}", l.pos.line, l.pos.col, l.tok),
+            Err(e) => {
+                eprintln!("{}: error: {}", src.name, e);
+                code = 1;
+                //跳过出错的 token，取不回来说明已经到了文件末尾
+                if lex.recover().is_none() {
+                    break;
+                }
+            }
+        }
+    }
+    code
+}
+
+fn ast(args: &[String]) -> i32 {
+    let src = match read_source(args) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    if !diagnose(&src) {
+        return 1;
+    }
+    let ast = Parser::new(DefaultLexer::new(src.text.as_bytes()))
+        .parse()
+        .unwrap();
+    println!("{:#?}", ast);
+    0
+}
+
+fn check(args: &[String]) -> i32 {
+    let src = match read_source(args) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    if diagnose(&src) {
+        0
+    } else {
+        1
+    }
+}
+
+fn run(args: &[String]) -> i32 {
+    let mut max_depth = vm::MAX_DEPTH;
+    let mut gc_stats = false;
+    let mut gc_stress = false;
+    let mut profile = false;
+    let mut folded = None;
+    let mut limits = Limits::default();
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--max-depth" | "--fuel" | "--timeout" | "--max-memory" => {
+                let n = match args.get(i + 1).and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("tars: `{}` needs a number\n\n{}", args[i], USAGE);
+                        return 2;
+                    }
+                };
+                match args[i].as_str() {
+                    "--max-depth" => max_depth = n as usize,
+                    "--fuel" => limits.fuel = Some(n),
+                    "--timeout" => limits.deadline = Some(Duration::from_millis(n)),
+                    _ => limits.memory = Some(n as usize),
+                }
+                i += 1;
+            }
+            "--allow" => match args.get(i + 1) {
+                Some(names) => {
+                    let names = names.split(',').filter(|n| !n.is_empty());
+                    limits.natives = Some(names.map(|n| n.to_owned()).collect());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("tars: `--allow` needs a list of functions\n\n{}", USAGE);
+                    return 2;
+                }
+            },
+            "--gc-stats" => gc_stats = true,
+            "--gc-stress" => gc_stress = true,
+            "--profile" => profile = true,
+            "--folded" => match args.get(i + 1) {
+                Some(path) => {
+                    folded = Some(path.clone());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("tars: `--folded` needs a file\n\n{}", USAGE);
+                    return 2;
+                }
+            },
+            _ => files.push(args[i].clone()),
+        }
+        i += 1;
+    }
+    let (name, program) = match load_program(&files) {
+        Ok(p) => p,
+        Err(code) => return code,
+    };
+    let main = match entry(&name, &program) {
+        Ok(f) => f,
+        Err(code) => return code,
+    };
+    let mut vm = VM::new(&program);
+    vm.set_max_depth(max_depth);
+    vm.set_gc_stress(gc_stress);
+    vm.set_limits(limits);
+    if profile || folded.is_some() {
+        vm.set_profile(SAMPLE_INTERVAL);
+    }
+    let result = vm.call(main, &[]);
+    if gc_stats {
+        eprintln!("gc: {}", vm.heap_stats());
+    }
+    if let Some(p) = vm.profile() {
+        if profile {
+            eprint!("{}", p.report());
+        }
+        if let Some(path) = &folded {
+            if let Err(e) = fs::write(path, p.folded()) {
+                eprintln!("{}: {}", path, e);
+                return 2;
+            }
+        }
+    }
+    exit_code(&name, result)
+}
+
+//程序入口是没有参数的 `fn int main()`
+fn entry(name: &str, program: &Program) -> Result<usize, i32> {
+    match program.func("main") {
+        Some(f) if program.funcs[f].params.is_empty() => Ok(f),
+        Some(_) => {
+            eprintln!("{}: error: `main` must not take parameters", name);
+            Err(1)
+        }
+        None => {
+            eprintln!("{}: error: no `main` function", name);
+            Err(1)
+        }
+    }
+}
+
+//main 的返回值（或 exit 的参数）取低 8 位作为退出码，和操作系统的截断方式一致。
+//它优先于 1/2/3：脚本返回 1 到 3 时与失败无法区分，失败时 stderr 上总有错误信息。
+//运行时错误打印调用栈
+fn exit_code(name: &str, result: VmResult<Value>) -> i32 {
+    match result {
+        Ok(Value::Float(n)) => (n as i64 & 0xFF) as i32,
+        Ok(v) => (v.bits() & 0xFF) as i32,
+        Err(e) => {
+            eprintln!("{}: runtime error: {}", name, e.kind);
+            for frame in e.backtrace.iter() {
+                match frame.line {
+                    Some(line) => eprintln!("    at {} ({}:{})", frame.func, name, line),
+                    None => eprintln!("    at {}", frame.func),
+                }
+            }
+            3
+        }
+    }
+}
+
+//在调试器里运行 main，命令从标准输入读。源码要从文件读，标准输入留给命令
+fn debug(args: &[String]) -> i32 {
+    let files: Vec<String> = args.to_vec();
+    if files.len() != 1 || files[0] == "-" {
+        eprintln!("tars: `debug` needs exactly one file\n\n{}", USAGE);
+        return 2;
+    }
+    let (name, program) = match load_program(&files) {
+        Ok(p) => p,
+        Err(code) => return code,
+    };
+    let main = match entry(&name, &program) {
+        Ok(f) => f,
+        Err(code) => return code,
+    };
+    //.tbc 文件没有源码，只显示行号
+    let source = fs::read_to_string(&name)
+        .ok()
+        .filter(|_| !name.ends_with(".tbc"));
+    let debugger = Debugger::new(
+        &program,
+        source.as_deref(),
+        Box::new(io::BufReader::new(io::stdin())),
+        Box::new(io::stdout()),
+    );
+    let mut vm = VM::new(&program);
+    vm.set_hook(Box::new(debugger));
+    match vm.call(main, &[]) {
+        Err(e) if e.kind == ErrorKind::Stopped => 0,
+        result => {
+            if let Ok(v) = &result {
+                println!("program exited with {}", v);
+            }
+            exit_code(&name, result)
+        }
+    }
+}
+
+//编译源码，或者读取 .tbc 文件里预编译的程序。返回错误信息里用的名字和程序
+fn load_program(files: &[String]) -> Result<(String, Program), i32> {
+    if let Some(path) = files.iter().find(|f| f.ends_with(".tbc")) {
+        let bytes = fs::read(path).map_err(|e| {
+            eprintln!("{}: {}", path, e);
+            2
+        })?;
+        return match bytecode::decode(&bytes) {
+            Ok(p) => Ok((path.clone(), p)),
+            Err(e) => {
+                eprintln!("{}: error: {}", path, e);
+                Err(1)
+            }
+        };
+    }
+    let src = read_source(files)?;
+    if !diagnose(&src) {
+        return Err(1);
+    }
+    let ast = Parser::new(DefaultLexer::new(src.text.as_bytes()))
+        .parse()
+        .unwrap();
+    match compiler::compile(&ast) {
+        Ok(p) => Ok((src.name, p)),
+        Err(e) => {
+            eprintln!("{}: error: {}", src.name, e);
+            Err(1)
+        }
+    }
+}
+
+fn disasm(args: &[String]) -> i32 {
+    match load_program(args) {
+        Ok((_, program)) => {
+            print!("{}", asm::disassemble(&program));
+            0
+        }
+        Err(code) => code,
+    }
+}
+
+//把 disasm 格式的清单汇编成 .tbc 文件，默认和清单同名
+fn assemble(args: &[String]) -> i32 {
+    let mut out = None;
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" => match args.get(i + 1) {
+                Some(path) => {
+                    out = Some(path.clone());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("tars: `-o` needs a value\n\n{}", USAGE);
+                    return 2;
+                }
+            },
+            _ => files.push(args[i].clone()),
+        }
+        i += 1;
+    }
+    let src = match read_source(&files) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let program = match asm::assemble(&src.text) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}:{}: error: {}", src.name, e.line, e.msg);
+            return 1;
+        }
+    };
+    if let Err(e) = verify::verify(&program) {
+        eprintln!("{}: error: {}", src.name, e);
+        return 1;
+    }
+    let stem = Path::new(&src.name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|_| src.name != "<stdin>")
+        .unwrap_or("out")
+        .to_owned();
+    let path = out.unwrap_or(format!("{}.tbc", stem));
+    match fs::write(&path, bytecode::encode(&program)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("tars: {}: {}", path, e);
+            2
+        }
+    }
+}
+
+fn build(args: &[String]) -> i32 {
+    let mut emit = "exe";
+    let mut out = None;
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--emit" | "-o" if i + 1 == args.len() => {
+                eprintln!("tars: `{}` needs a value\n\n{}", args[i], USAGE);
+                return 2;
+            }
+            "--emit" => {
+                emit = args[i + 1].as_str();
+                i += 1;
+            }
+            "-o" => {
+                out = Some(args[i + 1].clone());
+                i += 1;
+            }
+            _ => files.push(args[i].clone()),
+        }
+        i += 1;
+    }
+    if emit != "ir" && emit != "obj" && emit != "exe" && emit != "tbc" {
+        eprintln!("tars: unknown --emit kind `{}`\n\n{}", emit, USAGE);
+        return 2;
+    }
+    let src = match read_source(&files) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    if !diagnose(&src) {
+        return 1;
+    }
+    let ast = Parser::new(DefaultLexer::new(src.text.as_bytes()))
+        .parse()
+        .unwrap();
+    //输出文件默认和源文件同名，读标准输入时叫 out
+    let stem = Path::new(&src.name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|_| src.name != "<stdin>")
+        .unwrap_or("out")
+        .to_owned();
+    if emit == "tbc" {
+        let program = match compiler::compile(&ast) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}: error: {}", src.name, e);
+                return 1;
+            }
+        };
+        let path = out.unwrap_or(format!("{}.tbc", stem));
+        return match fs::write(&path, bytecode::encode(&program)) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("tars: {}: {}", path, e);
+                2
+            }
+        };
+    }
+    let mut cg = Codegen::new(&stem);
+    if let Err(e) = cg.compile(&ast) {
+        eprintln!("{}: error: {}", src.name, e);
+        return 1;
+    }
+    let written = match emit {
+        "ir" => {
+            let ir = cg.ir();
+            match &out {
+                Some(path) => fs::write(path, ir).map_err(|e| format!("{}: {}", path, e)),
+                None => {
+                    print!("{}", ir);
+                    Ok(())
+                }
+            }
+        }
+        "obj" => cg.write_object(&out.unwrap_or(format!("{}.o", stem))),
+        _ => {
+            let exe = out.unwrap_or(stem.clone());
+            let obj = format!("{}.o", exe);
+            cg.write_object(&obj).and_then(|_| link(&obj, &exe))
+        }
+    };
+    match written {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("tars: {}", e);
+            2
+        }
+    }
+}
+
+//用系统的 C 编译器链接，程序入口是 tars 里的 `fn int main()`
+fn link(obj: &str, exe: &str) -> Result<(), String> {
+    let cc = env::var("CC").unwrap_or("cc".to_owned());
+    let status = Command::new(&cc)
+        .arg(obj)
+        .arg("-o")
+        .arg(exe)
+        .status()
+        .map_err(|e| format!("{}: {}", cc, e))?;
+    let _ = fs::remove_file(obj);
+    if !status.success() {
+        return Err(format!("{} failed to link {}", cc, exe));
+    }
+    Ok(())
+}
+
+fn repl() -> i32 {
+    let mut rl = match DefaultEditor::new() {
+        Ok(rl) => rl,
+        Err(e) => {
+            eprintln!("tars: {}", e);
+            return 2;
+        }
+    };
+    let history = env::var("HOME")
+        .map(|home| Path::new(&home).join(".tars_history"))
+        .ok();
+    if let Some(path) = &history {
+        let _ = rl.load_history(path);
+    }
+    let mut repl = Repl::new();
+    loop {
+        let line = match rl.readline(repl.prompt()) {
+            Ok(line) => line,
+            //Ctrl-C 丢掉当前输入，Ctrl-D 退出
+            Err(ReadlineError::Interrupted) => {
+                repl.cancel();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("tars: {}", e);
+                return 2;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = rl.add_history_entry(line.as_str());
+        }
+        if !repl.is_pending() && (line.trim() == ":quit" || line.trim() == ":q") {
+            break;
+        }
+        match repl.feed(&line) {
+            Some(Ok(out)) if !out.is_empty() => println!("{}", out),
+            Some(Err(e)) => eprintln!("error: {}", e),
+            _ => (),
+        }
+    }
+    if let Some(path) = &history {
+        let _ = rl.save_history(path);
+    }
+    0
+}
+
+//tars fmt [--check] [--split-var] [files...]，没有文件时从标准输入读、写到标准输出
+fn fmt(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut opts = FmtOptions::default();
+    let mut files = Vec::new();
+    for a in args.iter() {
+        match a.as_str() {
+            "--check" => check = true,
+            "--split-var" => opts.split_var_decls = true,
+            s if s.starts_with("--") => {
+                eprintln!("tars: unknown option `{}`\n\n{}", s, USAGE);
+                return 2;
+            }
+            _ => files.push(a.clone()),
+        }
+    }
+    if files.is_empty() {
+        let src = match read_source(&files) {
+            Ok(s) => s,
+            Err(code) => return code,
+        };
+        return match format(&src.text, &opts) {
+            Ok(out) if check => (out != src.text) as i32,
+            Ok(out) => {
+                print!("{}", out);
+                0
+            }
+            Err(errors) => {
+                for e in errors.iter() {
+                    report(&src, e.offset, &e.err);
+                }
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for f in files.iter() {
+        let src = match read_source(std::slice::from_ref(f)) {
+            Ok(s) => s,
+            Err(c) => {
+                code = c;
+                continue;
+            }
+        };
+        match format(&src.text, &opts) {
+            Ok(out) if out == src.text => (),
+            Ok(_) if check => {
+                println!("{}", f);
+                code = code.max(1);
+            }
+            Ok(out) => {
+                if let Err(e) = fs::write(f, out) {
+                    eprintln!("{}: {}", f, e);
+                    code = 2;
+                }
+            }
+            Err(errors) => {
+                for e in errors.iter() {
+                    report(&src, e.offset, &e.err);
+                }
+                code = code.max(1);
+            }
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fmt_unknown_option() {
+        //不能把拼错的选项当成文件名，然后去等标准输入
+        assert_eq!(fmt(&["--chek".to_owned(), "x.tars".to_owned()]), 2);
+        assert_eq!(fmt(&["--help".to_owned()]), 2);
+    }
+}
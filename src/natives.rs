@@ -0,0 +1,530 @@
+use crate::lexer::KeyWord;
+use crate::vm::{ErrorKind, Value, VM};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+//用 Rust 实现、tars 代码可以直接调用的本地函数。编译器把调用编译成
+//参数依次压栈、Imm 参数个数、Native n、Adj 参数个数，返回值放在 ax 里。
+//字符串是以 0 结尾的字节序列，指针就是 int；文件用 open 返回的编号访问，
+//0、1、2 是标准输入、标准输出和标准错误。和 C 一样，I/O 失败时返回 -1，
+//只有访问非法内存这样的错误才中止执行。
+
+pub type NativeResult = Result<u64, ErrorKind>;
+
+pub struct Native {
+    pub name: &'static str,
+    pub params: &'static [KeyWord],
+    pub variadic: bool, //params 之后还能跟任意个参数，按各自的类型传入
+    pub ret: KeyWord,
+    pub func: fn(&mut VM, &[u64]) -> NativeResult,
+}
+
+const INT: KeyWord = KeyWord::Int;
+
+pub const NATIVES: &[Native] = &[
+    //printf(fmt, ...) 支持 %d %i %u %x %X %c %s %f %%，可以带 - 0 宽度和精度，返回输出的字节数
+    Native {
+        name: "printf",
+        params: &[INT],
+        variadic: true,
+        ret: INT,
+        func: printf,
+    },
+    //open(path, mode) mode 为 0 读，1 写（创建或清空），2 追加，返回文件编号
+    Native {
+        name: "open",
+        params: &[INT, INT],
+        variadic: false,
+        ret: INT,
+        func: open,
+    },
+    //read(fd, buf, n) 返回读到的字节数，0 表示读完了
+    Native {
+        name: "read",
+        params: &[INT, INT, INT],
+        variadic: false,
+        ret: INT,
+        func: read,
+    },
+    //write(fd, buf, n) 返回写入的字节数
+    Native {
+        name: "write",
+        params: &[INT, INT, INT],
+        variadic: false,
+        ret: INT,
+        func: write,
+    },
+    Native {
+        name: "close",
+        params: &[INT],
+        variadic: false,
+        ret: INT,
+        func: close,
+    },
+    //从标准输入读一个字节，读完了返回 -1
+    Native {
+        name: "getchar",
+        params: &[],
+        variadic: false,
+        ret: INT,
+        func: getchar,
+    },
+    //malloc(n) 分配 n 个字节，内容清零
+    Native {
+        name: "malloc",
+        params: &[INT],
+        variadic: false,
+        ret: INT,
+        func: malloc,
+    },
+    Native {
+        name: "free",
+        params: &[INT],
+        variadic: false,
+        ret: INT,
+        func: free,
+    },
+    //memset(p, c, n) 返回 p
+    Native {
+        name: "memset",
+        params: &[INT, INT, INT],
+        variadic: false,
+        ret: INT,
+        func: memset,
+    },
+    Native {
+        name: "memcmp",
+        params: &[INT, INT, INT],
+        variadic: false,
+        ret: INT,
+        func: memcmp,
+    },
+    //exit(code) 结束执行，code 作为调用的返回值
+    Native {
+        name: "exit",
+        params: &[INT],
+        variadic: false,
+        ret: INT,
+        func: exit,
+    },
+    //虚拟机创建以来经过的秒数
+    Native {
+        name: "clock",
+        params: &[],
+        variadic: false,
+        ret: KeyWord::Float,
+        func: clock,
+    },
+];
+
+pub fn native(name: &str) -> Option<usize> {
+    NATIVES.iter().position(|n| n.name == name)
+}
+
+//嵌入 tars 的宿主程序提供的函数。编译时按签名检查参数个数并转换类型，
+//运行时参数和返回值都是 Value，不接触虚拟机的内存。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub params: Vec<KeyWord>,
+    pub ret: KeyWord,
+}
+
+//返回 Err 时中止执行，错误是 ErrorKind::Native(函数名, 原因)
+pub type HostFn = Rc<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+//本地函数用到的输入输出，可以换成别的读写器，比如测试时捕获输出
+pub struct Io {
+    pub stdout: Box<dyn Write>,
+    pub stdin: Box<dyn BufRead>,
+    files: Vec<Option<File>>, //编号 3 开始的文件
+    start: Instant,
+}
+
+impl Default for Io {
+    fn default() -> Io {
+        Io::new()
+    }
+}
+
+impl Io {
+    pub fn new() -> Io {
+        Io {
+            stdout: Box::new(io::stdout()),
+            stdin: Box::new(BufReader::new(io::stdin())),
+            files: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    fn file(&mut self, fd: i64) -> Option<&mut File> {
+        if fd < 3 {
+            return None;
+        }
+        self.files.get_mut(fd as usize - 3).and_then(|f| f.as_mut())
+    }
+}
+
+const FAIL: u64 = -1i64 as u64;
+
+fn printf(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let fmt = vm.read_cstr(args[0])?;
+    let out = format(vm, &fmt, &args[1..])?;
+    match vm.io().stdout.write_all(&out) {
+        Ok(_) => Ok(out.len() as u64),
+        Err(_) => Ok(FAIL),
+    }
+}
+
+//按 C 的 printf 格式化，args 是格式串后面的参数
+pub fn format(vm: &VM, fmt: &[u8], args: &[u64]) -> Result<Vec<u8>, ErrorKind> {
+    let error = |msg: String| ErrorKind::Native("printf".to_owned(), msg);
+    let mut out = Vec::new();
+    let mut args = args.iter();
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            out.push(fmt[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let (mut left, mut zero) = (false, false);
+        while i < fmt.len() && (fmt[i] == b'-' || fmt[i] == b'0') {
+            left |= fmt[i] == b'-';
+            zero |= fmt[i] == b'0';
+            i += 1;
+        }
+        let mut width = 0;
+        while i < fmt.len() && fmt[i].is_ascii_digit() {
+            width = width * 10 + (fmt[i] - b'0') as usize;
+            i += 1;
+        }
+        let mut precision = None;
+        if i < fmt.len() && fmt[i] == b'.' {
+            i += 1;
+            let mut p = 0;
+            while i < fmt.len() && fmt[i].is_ascii_digit() {
+                p = p * 10 + (fmt[i] - b'0') as usize;
+                i += 1;
+            }
+            precision = Some(p);
+        }
+        let conv = match fmt.get(i) {
+            Some(c) => *c as char,
+            None => return Err(error("format ends with `%`".to_owned())),
+        };
+        i += 1;
+        if conv == '%' {
+            out.push(b'%');
+            continue;
+        }
+        let arg = match args.next() {
+            Some(a) => *a,
+            None => return Err(error(format!("missing argument for `%{}`", conv))),
+        };
+        let text = match conv {
+            'd' | 'i' => (arg as i64).to_string().into_bytes(),
+            'u' => arg.to_string().into_bytes(),
+            'x' => format!("{:x}", arg).into_bytes(),
+            'X' => format!("{:X}", arg).into_bytes(),
+            'c' => vec![arg as u8],
+            's' => {
+                let mut s = vm.read_cstr(arg)?;
+                if let Some(p) = precision {
+                    s.truncate(p);
+                }
+                s
+            }
+            'f' => format!("{:.*}", precision.unwrap_or(6), f64::from_bits(arg)).into_bytes(),
+            c => return Err(error(format!("unknown conversion `%{}`", c))),
+        };
+        let pad = width.saturating_sub(text.len());
+        if left {
+            out.extend_from_slice(&text);
+            out.extend(std::iter::repeat_n(b' ', pad));
+        } else if zero && conv != 's' && conv != 'c' {
+            //负号放在补的 0 前面
+            let (sign, digits) = match text.first() {
+                Some(b'-') => (&text[..1], &text[1..]),
+                _ => (&text[..0], &text[..]),
+            };
+            out.extend_from_slice(sign);
+            out.extend(std::iter::repeat_n(b'0', pad));
+            out.extend_from_slice(digits);
+        } else {
+            out.extend(std::iter::repeat_n(b' ', pad));
+            out.extend_from_slice(&text);
+        }
+    }
+    Ok(out)
+}
+
+fn open(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let path = String::from_utf8_lossy(&vm.read_cstr(args[0])?).into_owned();
+    let file = match args[1] {
+        0 => File::open(&path),
+        1 => File::create(&path),
+        2 => OpenOptions::new().append(true).create(true).open(&path),
+        _ => return Ok(FAIL),
+    };
+    let file = match file {
+        Ok(f) => f,
+        Err(_) => return Ok(FAIL),
+    };
+    let files = &mut vm.io().files;
+    let i = match files.iter().position(|f| f.is_none()) {
+        Some(i) => {
+            files[i] = Some(file);
+            i
+        }
+        None => {
+            files.push(Some(file));
+            files.len() - 1
+        }
+    };
+    Ok(i as u64 + 3)
+}
+
+fn read(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let (fd, buf, n) = (args[0] as i64, args[1], args[2] as i64);
+    if n < 0 {
+        return Ok(FAIL);
+    }
+    //先确认缓冲区在内存里，再按 n 分配宿主缓冲区
+    vm.check_bytes(buf, n as usize)?;
+    let mut tmp = vec![0; n as usize];
+    let io = vm.io();
+    let got = match fd {
+        0 => io.stdin.read(&mut tmp),
+        _ => match io.file(fd) {
+            Some(f) => f.read(&mut tmp),
+            None => return Ok(FAIL),
+        },
+    };
+    match got {
+        Ok(k) => {
+            vm.write_bytes(buf, &tmp[..k])?;
+            Ok(k as u64)
+        }
+        Err(_) => Ok(FAIL),
+    }
+}
+
+fn write(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let (fd, buf, n) = (args[0] as i64, args[1], args[2] as i64);
+    if n < 0 {
+        return Ok(FAIL);
+    }
+    let bytes = vm.read_bytes(buf, n as usize)?;
+    let io = vm.io();
+    let done = match fd {
+        1 => io.stdout.write_all(&bytes),
+        2 => io::stderr().write_all(&bytes),
+        _ => match io.file(fd) {
+            Some(f) => f.write_all(&bytes),
+            None => return Ok(FAIL),
+        },
+    };
+    match done {
+        Ok(_) => Ok(n as u64),
+        Err(_) => Ok(FAIL),
+    }
+}
+
+fn close(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let fd = args[0] as i64;
+    let io = vm.io();
+    if io.file(fd).is_none() {
+        return Ok(FAIL);
+    }
+    io.files[fd as usize - 3] = None;
+    Ok(0)
+}
+
+fn getchar(vm: &mut VM, _: &[u64]) -> NativeResult {
+    let mut b = [0u8];
+    match vm.io().stdin.read(&mut b) {
+        Ok(1) => Ok(b[0] as u64),
+        _ => Ok(FAIL),
+    }
+}
+
+fn malloc(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let n = args[0] as i64;
+    if n < 0 {
+        return Err(ErrorKind::NegativeSize(n));
+    }
+    vm.malloc(n as usize)
+}
+
+fn free(vm: &mut VM, args: &[u64]) -> NativeResult {
+    vm.free(args[0])?;
+    Ok(0)
+}
+
+fn memset(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let n = args[2] as i64;
+    if n < 0 {
+        return Err(ErrorKind::NegativeSize(n));
+    }
+    vm.check_bytes(args[0], n as usize)?;
+    vm.write_bytes(args[0], &vec![args[1] as u8; n as usize])?;
+    Ok(args[0])
+}
+
+fn memcmp(vm: &mut VM, args: &[u64]) -> NativeResult {
+    let n = args[2] as i64;
+    if n < 0 {
+        return Err(ErrorKind::NegativeSize(n));
+    }
+    let a = vm.read_bytes(args[0], n as usize)?;
+    let b = vm.read_bytes(args[1], n as usize)?;
+    let d = a
+        .iter()
+        .zip(b.iter())
+        .find(|(x, y)| x != y)
+        .map_or(0, |(x, y)| *x as i64 - *y as i64);
+    Ok(d as u64)
+}
+
+fn exit(_: &mut VM, args: &[u64]) -> NativeResult {
+    Err(ErrorKind::Exit(args[0] as i64))
+}
+
+fn clock(vm: &mut VM, _: &[u64]) -> NativeResult {
+    Ok(vm.io().start.elapsed().as_secs_f64().to_bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    //把输出留在内存里
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(s: &str, stdin: &str) -> (Result<Value, ErrorKind>, String) {
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        let out = Rc::new(RefCell::new(Vec::new()));
+        vm.io().stdout = Box::new(Capture(out.clone()));
+        vm.io().stdin = Box::new(io::Cursor::new(stdin.as_bytes().to_vec()));
+        let v = vm.call(p.func("main").unwrap(), &[]).map_err(|e| e.kind);
+        let out = String::from_utf8(out.borrow().clone()).unwrap();
+        (v, out)
+    }
+
+    #[test]
+    fn test_natives() {
+        let s = r#"fn int main() {
+            var int n, c;
+            n = printf("%d|%5d|%-4x|%05d|%c|%s|%.2f|%3.1s|100%%\n", 42, -7, 255, -42, 65, "hi", 2.0 / 3, "xyz");
+            while 1 {
+                c = getchar();
+                if c < 0 { return n; }
+                printf("[%c]", c - 32);
+            }
+        }"#;
+        let (v, out) = run(s, "ab");
+        assert_eq!(out, "42|   -7|ff  |-0042|A|hi|0.67|  x|100%\n[A][B]");
+        assert_eq!(v, Ok(Value::Int(39)));
+
+        let path = std::env::temp_dir().join("tars_natives_test.txt");
+        let s = format!(
+            r#"fn int main() {{
+            var int fd, buf, n;
+            fd = open("{}", 1);
+            write(fd, "hello, file", 11);
+            close(fd);
+            buf = malloc(16);
+            fd = open("{}", 0);
+            n = read(fd, buf, 16);
+            close(fd);
+            printf("%d %s ", n, buf);
+            memset(buf, 120, 5);
+            printf("%s %d %d ", buf, memcmp(buf, "xxxxx", 5), memcmp(buf, "xxy", 3) < 0);
+            free(buf);
+            printf("%d %d ", close(fd), open("/nonexistent/dir/file", 0));
+            exit(clock() >= 0.0);
+            return 99;
+        }}"#,
+            path.display(),
+            path.display()
+        );
+        let (v, out) = run(&s, "");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(out, "11 hello, file xxxxx, file 0 1 -1 -1 ");
+        assert_eq!(v, Ok(Value::Int(1)));
+
+        let (v, _) = run(r#"fn int main() { return printf("%d %d", 1); }"#, "");
+        assert_eq!(
+            v,
+            Err(ErrorKind::Native(
+                "printf".to_owned(),
+                "missing argument for `%d`".to_owned()
+            ))
+        );
+        let (v, _) = run("fn int main() { free(8); }", "");
+        assert_eq!(v, Err(ErrorKind::InvalidMemory(8)));
+        //free 不对齐的地址不影响原来的块
+        let ast = Parser::new(DefaultLexer::new("fn int main() {}".as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        let a = vm.malloc(16).unwrap();
+        assert_eq!(vm.free(a + 1), Err(ErrorKind::InvalidMemory(a + 1)));
+        assert_eq!(vm.free(a), Ok(()));
+        //缓冲区越界时在分配宿主内存之前就报错
+        let (v, _) = run(
+            r#"fn int main() {
+            var int p;
+            p = malloc(16);
+            memset(p, 0, 1 << 40);
+        }"#,
+            "",
+        );
+        assert!(matches!(v, Err(ErrorKind::InvalidMemory(_))));
+        let (v, _) = run(
+            r#"fn int main() {
+            var int p;
+            p = malloc(16);
+            read(0, p, 1 << 40);
+        }"#,
+            "",
+        );
+        assert!(matches!(v, Err(ErrorKind::InvalidMemory(_))));
+
+        //没有经过校验的字节码给的参数个数不对时报错，不会越界访问参数
+        for (name, argc, want) in [("printf", 0, 1), ("free", 3, 1)] {
+            let src = format!(
+                ".fn int main()\nEnt 0\nImm 0\nPush\nImm {}\nNative {}\nLev\n.end",
+                argc, name
+            );
+            let p = crate::asm::assemble(&src).unwrap();
+            let v = VM::new(&p).call(p.func("main").unwrap(), &[]);
+            assert_eq!(v.unwrap_err().kind, ErrorKind::Arity(want, argc));
+        }
+    }
+}
@@ -1,14 +1,19 @@
 use crate::ast;
-use crate::ast::Stmt;
 use crate::ast::StmtNode;
 use crate::ast::AST;
+use crate::cst;
+use crate::cst::SyntaxKind;
+use crate::cst::SyntaxNode;
 use crate::lexer::lexer;
 use crate::lexer::Aides;
-use crate::lexer::DefaultLexer;
 use crate::lexer::KeyWord;
-use crate::lexer::LexResult;
+use crate::lexer::Lexeme;
+use crate::lexer::LexerError;
 use crate::lexer::Operator;
 use crate::lexer::Token;
+use crate::lexer::TriviaKind;
+use std::fmt;
+use std::rc::Rc;
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
@@ -19,11 +24,42 @@ pub enum ParseError {
     NoFoundIdent,
     NoStmt,
     Expect(Token),
+    Lex(LexerError),
 }
 
-struct Parser<L: lexer> {
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Eof => write!(f, "unexpected end of file"),
+            ParseError::NoFoundType => write!(f, "expected a type or an expression"),
+            ParseError::NoFoundIdent => write!(f, "expected an identifier"),
+            ParseError::NoStmt => write!(f, "expected a declaration or a statement"),
+            ParseError::Expect(t) => write!(f, "expected {:?}", t),
+            ParseError::Lex(e) => e.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub offset: usize, //出错 token 在源码中的字节偏移
+    pub err: ParseError,
+}
+
+//字节偏移转成 1 开始的行号和列号，列按字符计
+pub fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, col)
+}
+
+pub struct Parser<L: lexer> {
     lex: L,
     tok: Token,
+    lexeme: Option<Lexeme>, //当前 token 连同 trivia，被消耗时放进 CST
+    cst: cst::Builder,
+    errors: Vec<SyntaxError>,
 }
 
 impl<L: lexer> Parser<L> {
@@ -31,44 +67,154 @@ impl<L: lexer> Parser<L> {
         Self {
             lex: l,
             tok: Token::Eof,
+            lexeme: None,
+            cst: cst::Builder::new(),
+            errors: Vec::new(),
         }
     }
-    fn parse(&mut self) -> ParseResult<AST> {
+
+    pub fn parse(&mut self) -> ParseResult<AST> {
         self.next();
         let mut gro_decl = ast::GlobalDecl { list: Vec::new() };
+        let mut funcs = Vec::new();
         loop {
             match self.tok {
                 Token::Eof => break,
                 Token::KeyWord(KeyWord::Var) => {
-                    println!("parse var");
-                    match self.parse_global_declaration() {
-                        Ok(value_spec) => {
-                            println!("{:?}", value_spec);
-                            gro_decl.list.push(value_spec);
-                        }
-                        Err(e) => println!("{:?}", e),
-                    }
+                    gro_decl.list.push(self.parse_global_declaration()?);
                 }
                 Token::KeyWord(KeyWord::Fn) => {
-                    println!("parse fn");
-                    match self.parse_function_declaration() {
-                        Ok(fn_spec) => {
-                            println!("{:?}", fn_spec);
+                    funcs.push(self.parse_function_declaration()?);
+                }
+                _ => return Err(ParseError::NoStmt),
+            }
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0).err);
+        }
+        Ok(AST {
+            global: gro_decl,
+            funcs,
+        })
+    }
+
+    //容错解析，生成无损 CST，拼接所有叶子即得到原始输入。
+    //出错的声明之后直到下一个 `var` / `fn` 的 token 放进 Error 节点
+    pub fn parse_tree(mut self) -> (Rc<SyntaxNode>, Vec<SyntaxError>) {
+        self.parse_file();
+        (self.cst.finish(), self.errors)
+    }
+
+    //容错解析，没有错误时返回 AST，否则返回全部错误
+    pub fn parse_all(mut self) -> Result<AST, Vec<SyntaxError>> {
+        let ast = self.parse_file();
+        if self.errors.is_empty() {
+            Ok(ast)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    //parse_tree 和 parse_all 共用，出错的声明不放进 AST
+    fn parse_file(&mut self) -> AST {
+        let mut ast = AST {
+            global: ast::GlobalDecl { list: Vec::new() },
+            funcs: Vec::new(),
+        };
+        self.next();
+        loop {
+            let depth = self.cst.depth();
+            let r = match self.tok {
+                Token::Eof => break,
+                Token::KeyWord(KeyWord::Var) => self
+                    .parse_global_declaration()
+                    .map(|d| ast.global.list.push(d)),
+                Token::KeyWord(KeyWord::Fn) => {
+                    self.parse_function_declaration().map(|f| ast.funcs.push(f))
+                }
+                _ => Err(ParseError::NoStmt),
+            };
+            if let Err(e) = r {
+                self.error(e);
+                while self.cst.depth() > depth {
+                    self.cst.finish_node();
+                }
+                self.cst.start_node(SyntaxKind::Error);
+                while self.tok != Token::Eof
+                    && self.tok != Token::KeyWord(KeyWord::Var)
+                    && self.tok != Token::KeyWord(KeyWord::Fn)
+                {
+                    self.next();
+                }
+                self.cst.finish_node();
+            }
+        }
+        if let Some(l) = self.lexeme.take() {
+            self.cst.lexeme(l);
+        }
+        ast
+    }
+
+    fn next(&mut self) {
+        if let Some(l) = self.lexeme.take() {
+            self.cst.lexeme(l);
+        }
+        loop {
+            match self.lex.lex_with_trivia() {
+                Ok(l) => {
+                    self.tok = l.tok.clone();
+                    self.lexeme = Some(l);
+                    return;
+                }
+                Err(e) => {
+                    let offset = self.cst.offset();
+                    self.errors.push(SyntaxError {
+                        offset,
+                        err: ParseError::Lex(e),
+                    });
+                    match self.lex.recover() {
+                        //跳过出错的 token 继续
+                        Some(l) => self.cst.error_token(l),
+                        //词法器无法恢复，当作文件结束
+                        None => {
+                            self.tok = Token::Eof;
+                            return;
                         }
-                        Err(e) => println!("{:?}", e),
                     }
                 }
-                _ => break,
             }
         }
-        Err(ParseError::Eof)
     }
 
-    fn next(&mut self) {
-        if let Ok(t) = self.lex.lex() {
-            self.tok = t
+    fn error(&mut self, e: ParseError) {
+        let leading: usize = match &self.lexeme {
+            Some(l) => l.leading.iter().map(|t| t.text.len()).sum(),
+            None => 0,
+        };
+        self.errors.push(SyntaxError {
+            offset: self.cst.offset() + leading,
+            err: e,
+        });
+    }
+
+    //当前 token 前紧挨着的 `///` 注释，中间隔了空行或普通注释的不算
+    fn doc_comment(&self) -> Option<String> {
+        let leading = match &self.lexeme {
+            Some(l) => &l.leading,
+            None => return None,
+        };
+        let mut lines: Vec<&str> = Vec::new();
+        for t in leading.iter() {
+            match t.kind {
+                TriviaKind::DocComment => lines.push(t.doc_text()),
+                TriviaKind::Whitespace if t.text.matches('\n').count() < 2 => (),
+                _ => lines.clear(),
+            }
+        }
+        if lines.is_empty() {
+            None
         } else {
-            self.tok = Token::Eof;
+            Some(lines.join("\n"))
         }
     }
 
@@ -77,48 +223,135 @@ impl<L: lexer> Parser<L> {
     }
 
     fn parse_declaration(&mut self) -> ParseResult<ast::ValueSepc> {
+        let doc = self.doc_comment();
+        self.cst.start_node(SyntaxKind::VarDecl);
         self.next();
-        return self.parse_var_define();
+        let mut spec = self.parse_var_define()?;
+        self.cst.finish_node();
+        spec.doc = doc;
+        Ok(spec)
     }
 
     // fn parse_gen_decl<F:>(t: Token, f: F) {}
 
     fn parse_function_declaration(&mut self) -> ParseResult<ast::FuncDecl<StmtNode>> {
+        let doc = self.doc_comment();
+        let line = self.line();
+        self.cst.start_node(SyntaxKind::FnDecl);
         self.next();
-        return self.parse_function_define();
+        let mut decl = self.parse_function_define()?;
+        decl.line = line;
+        self.cst.finish_node();
+        decl.doc = doc;
+        Ok(decl)
     }
 
-    fn parse_stmt_list(&mut self) -> ParseResult<Vec<StmtNode>> {
+    //语句和它们开始的行号
+    fn parse_stmt_list(&mut self) -> ParseResult<(Vec<StmtNode>, Vec<u32>)> {
         let mut list: Vec<StmtNode> = Vec::new();
+        let mut lines = Vec::new();
         while self.tok != Token::Oper(Operator::RightBrace) && self.tok != Token::Eof {
+            lines.push(self.line());
             list.push(self.parse_stmt()?);
         }
-        Ok(list)
+        Ok((list, lines))
+    }
+
+    //当前 token 所在的行
+    fn line(&self) -> u32 {
+        self.lexeme.as_ref().map_or(0, |l| l.pos.line)
     }
 
     fn parse_stmt(&mut self) -> ParseResult<StmtNode> {
         return match &self.tok {
             Token::KeyWord(KeyWord::Var) => Ok(StmtNode::ValueSepc(self.parse_declaration()?)),
-            Token::Ident(s) => self.parse_simple_stmt(),
+            Token::KeyWord(KeyWord::Return) => self.parse_return_stmt(),
+            Token::KeyWord(KeyWord::If) => Ok(StmtNode::IfStmt(self.parse_if_stmt()?)),
+            Token::KeyWord(KeyWord::While) => self.parse_while_stmt(),
+            Token::Oper(Operator::LeftBrace) => Ok(StmtNode::BlockStmt(self.parse_block()?)),
+            Token::Ident(_)
+            | Token::Number(_)
+            | Token::Float(_)
+            | Token::Oper(Operator::LeftParen) => self.parse_simple_stmt(),
             _ => Err(ParseError::NoStmt),
         };
     }
 
+    //simple_stmt ::= expr '=' expr ';' | expr ';'
     fn parse_simple_stmt(&mut self) -> ParseResult<StmtNode> {
+        let checkpoint = self.cst.checkpoint();
         let x = self.parse_lhs()?;
         return match self.tok {
             Token::Oper(Operator::Assign) => {
+                self.cst.start_node_at(checkpoint, SyntaxKind::AssignStmt);
                 let op = self.tok.clone();
                 self.next();
                 let y = self.parse_rhs()?;
                 self.expect_token(Token::Aide(Aides::Semicolon))?;
-                let stmt = ast::AssignStmt { x: x, op: op, y: y };
+                self.cst.finish_node();
+                let stmt = ast::AssignStmt { x, op, y };
                 Ok(StmtNode::AssignStmt(stmt))
             }
-            _ => Err(ParseError::NoStmt),
+            _ => {
+                self.cst.start_node_at(checkpoint, SyntaxKind::ExprStmt);
+                self.expect_token(Token::Aide(Aides::Semicolon))?;
+                self.cst.finish_node();
+                Ok(StmtNode::ExprStmt(x))
+            }
         };
     }
 
+    //return_stmt ::= 'return' [expr] ';'
+    fn parse_return_stmt(&mut self) -> ParseResult<StmtNode> {
+        self.cst.start_node(SyntaxKind::ReturnStmt);
+        self.next();
+        let mut x = None;
+        if !self.match_token(Token::Aide(Aides::Semicolon)) {
+            x = Some(self.parse_expr()?);
+        }
+        self.expect_token(Token::Aide(Aides::Semicolon))?;
+        self.cst.finish_node();
+        Ok(StmtNode::ReturnStmt(ast::ReturnStmt { x }))
+    }
+
+    //if_stmt ::= 'if' expr block ['else' (if_stmt | block)]
+    fn parse_if_stmt(&mut self) -> ParseResult<ast::IfStmt> {
+        self.cst.start_node(SyntaxKind::IfStmt);
+        self.next();
+        let cond = self.parse_expr()?;
+        let body = self.parse_block()?;
+        let mut els = None;
+        if self.match_token(Token::KeyWord(KeyWord::Else)) {
+            self.next();
+            let stmt = match self.tok {
+                Token::KeyWord(KeyWord::If) => StmtNode::IfStmt(self.parse_if_stmt()?),
+                _ => StmtNode::BlockStmt(self.parse_block()?),
+            };
+            els = Some(Box::new(stmt));
+        }
+        self.cst.finish_node();
+        Ok(ast::IfStmt { cond, body, els })
+    }
+
+    //while_stmt ::= 'while' expr block
+    fn parse_while_stmt(&mut self) -> ParseResult<StmtNode> {
+        self.cst.start_node(SyntaxKind::WhileStmt);
+        self.next();
+        let cond = self.parse_expr()?;
+        let body = self.parse_block()?;
+        self.cst.finish_node();
+        Ok(StmtNode::WhileStmt(ast::WhileStmt { cond, body }))
+    }
+
+    fn parse_block(&mut self) -> ParseResult<ast::BlockStmt> {
+        self.cst.start_node(SyntaxKind::Block);
+        self.expect_token(Token::Oper(Operator::LeftBrace))?;
+        let (list, lines) = self.parse_stmt_list()?;
+        self.expect_token(Token::Oper(Operator::RightBrace))?;
+        self.cst.finish_node();
+        Ok(ast::BlockStmt { list, lines })
+    }
+
     fn parse_lhs(&mut self) -> ParseResult<ast::ExprNode> {
         self.parse_expr()
     }
@@ -127,8 +360,20 @@ impl<L: lexer> Parser<L> {
         self.parse_expr()
     }
 
+    //expr_list ::= '(' [expr {',' expr}] ')'
     fn parse_expr_list(&mut self) -> ParseResult<Vec<ast::ExprNode>> {
-        Err(ParseError::NoFoundType)
+        self.cst.start_node(SyntaxKind::ArgList);
+        self.expect_token(Token::Oper(Operator::LeftParen))?;
+        let mut list = Vec::new();
+        while !self.match_token(Token::Oper(Operator::RightParen)) {
+            list.push(self.parse_expr()?);
+            if self.expect_token(Token::Aide(Aides::Comma)).is_err() {
+                break;
+            }
+        }
+        self.expect_token(Token::Oper(Operator::RightParen))?;
+        self.cst.finish_node();
+        Ok(list)
     }
 
     fn parse_expr(&mut self) -> ParseResult<ast::ExprNode> {
@@ -136,14 +381,18 @@ impl<L: lexer> Parser<L> {
     }
 
     fn parse_binary_expr(&mut self, level: u32) -> ParseResult<ast::ExprNode> {
+        let checkpoint = self.cst.checkpoint();
         let mut x = self.parse_unary_expr()?;
         while self.tok.level() >= level {
+            self.cst.start_node_at(checkpoint, SyntaxKind::BinaryExpr);
             let op = self.tok.clone();
             self.next();
-            let y = self.parse_binary_expr(op.level())?;
+            //右边只吃优先级更高的运算符，同级的运算符左结合
+            let y = self.parse_binary_expr(op.level() + 1)?;
+            self.cst.finish_node();
             x = ast::ExprNode::BinaryExpr(ast::BinaryExpr {
                 x: Box::new(x),
-                op: op,
+                op,
                 y: Box::new(y),
             });
         }
@@ -152,10 +401,15 @@ impl<L: lexer> Parser<L> {
 
     fn parse_unary_expr(&mut self) -> ParseResult<ast::ExprNode> {
         return match self.tok {
-            Token::Oper(Operator::Add) | Token::Oper(Operator::Sub) => {
+            Token::Oper(Operator::Add)
+            | Token::Oper(Operator::Sub)
+            | Token::Oper(Operator::BitNot)
+            | Token::Oper(Operator::LogicNot) => {
                 let token = self.tok.clone();
+                self.cst.start_node(SyntaxKind::UnaryExpr);
                 self.next();
                 let x = self.parse_unary_expr()?;
+                self.cst.finish_node();
                 return Ok(ast::ExprNode::UnaryExpr(ast::UnaryExpr {
                     op: token,
                     x: Box::new(x),
@@ -165,17 +419,43 @@ impl<L: lexer> Parser<L> {
         };
     }
 
+    //primary_expr ::= operand {'(' expr_list ')'}
     fn parse_primary_expr(&mut self) -> ParseResult<ast::ExprNode> {
-        self.parse_operand()
+        let checkpoint = self.cst.checkpoint();
+        let mut x = self.parse_operand()?;
+        while self.match_token(Token::Oper(Operator::LeftParen)) {
+            self.cst.start_node_at(checkpoint, SyntaxKind::CallExpr);
+            let args = self.parse_expr_list()?;
+            self.cst.finish_node();
+            x = ast::ExprNode::CallExpr(ast::CallExpr {
+                fun: Box::new(x),
+                args,
+            });
+        }
+        Ok(x)
     }
 
     fn parse_operand(&mut self) -> ParseResult<ast::ExprNode> {
         return match &self.tok {
-            Token::Ident(_) => Ok(ast::ExprNode::IdentExpr(self.parse_identifier()?)),
+            Token::Ident(_) => {
+                self.cst.start_node(SyntaxKind::IdentExpr);
+                let ident = self.parse_identifier()?;
+                self.cst.finish_node();
+                Ok(ast::ExprNode::IdentExpr(ident))
+            }
+            Token::Number(_) | Token::Float(_) | Token::Str(_) => {
+                let value = self.tok.clone();
+                self.cst.start_node(SyntaxKind::Literal);
+                self.next();
+                self.cst.finish_node();
+                Ok(ast::ExprNode::BasicLit(ast::BasicLit { value }))
+            }
             Token::Oper(Operator::LeftParen) => {
+                self.cst.start_node(SyntaxKind::ParenExpr);
                 self.next();
                 let x = self.parse_paren_expr()?;
                 self.expect_token(Token::Oper(Operator::RightParen))?;
+                self.cst.finish_node();
                 Ok(ast::ExprNode::ParenExpr(ast::ParenExpr { x: Box::new(x) }))
             }
             _ => Err(ParseError::NoFoundType),
@@ -190,29 +470,34 @@ impl<L: lexer> Parser<L> {
     fn parse_function_define(&mut self) -> ParseResult<ast::FuncDecl<StmtNode>> {
         self.parse_type().and_then(|t| {
             self.parse_identifier().and_then(|s| {
+                self.cst.start_node(SyntaxKind::ParamList);
                 self.expect_token(Token::Oper(Operator::LeftParen))?;
                 let mut params: Vec<ast::Param> = Vec::new();
                 if !self.match_token(Token::Oper(Operator::RightParen)) {
                     params = self.parse_param_list()?;
                 }
                 self.expect_token(Token::Oper(Operator::RightParen))?;
+                self.cst.finish_node();
+                self.cst.start_node(SyntaxKind::Block);
                 self.expect_token(Token::Oper(Operator::LeftBrace))?;
                 let body = self.parse_func_body()?;
                 self.expect_token(Token::Oper(Operator::RightBrace))?;
+                self.cst.finish_node();
                 Ok(ast::FuncDecl {
+                    doc: None,
                     typ: t,
                     fn_name: s,
-                    params: params,
-                    body: body,
+                    line: 0,
+                    params,
+                    body,
                 })
             })
         })
     }
 
     fn parse_func_body(&mut self) -> ParseResult<ast::FuncBody<StmtNode>> {
-        Ok(ast::FuncBody {
-            list: self.parse_stmt_list()?,
-        })
+        let (list, lines) = self.parse_stmt_list()?;
+        Ok(ast::FuncBody { list, lines })
     }
 
     fn parse_param_list(&mut self) -> ParseResult<Vec<ast::Param>> {
@@ -226,10 +511,13 @@ impl<L: lexer> Parser<L> {
     }
 
     fn parse_fn_param(&mut self) -> ParseResult<ast::Param> {
-        self.parse_type().and_then(|t| {
+        self.cst.start_node(SyntaxKind::Param);
+        let param = self.parse_type().and_then(|t| {
             self.parse_identifier()
                 .and_then(|s| Ok(ast::Param { ident: s, typ: t }))
-        })
+        })?;
+        self.cst.finish_node();
+        Ok(param)
     }
 
     // variable_decl ::= type {'*'} id { ',' {'*'} id } ';'
@@ -238,6 +526,7 @@ impl<L: lexer> Parser<L> {
             self.parse_variable_list().and_then(|idents| {
                 self.expect_token(Token::Aide(Aides::Semicolon))?;
                 Ok(ast::ValueSepc {
+                    doc: None,
                     names: idents,
                     typ: t,
                 })
@@ -290,9 +579,11 @@ impl<L: lexer> Parser<L> {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::OpenOptions;
+    use crate::lexer::DefaultLexer;
+
     #[test]
     fn test_parser() {
         let s = "
@@ -302,9 +593,9 @@ mod tests {
             f = a + b * (c + e);
         }
         ";
-        let mut lexer = DefaultLexer::new(s.as_bytes());
+        let lexer = DefaultLexer::new(s.as_bytes());
         let mut parser = Parser::new(lexer);
-        parser.parse();
+        parser.parse().unwrap();
         // loop {
         //     let m = lexer.lex();
         //     match m {
@@ -316,4 +607,82 @@ mod tests {
         //     }
         // }
     }
+
+    #[test]
+    fn test_parser_doc_comment() {
+        let s = "
+        /// 全局计数
+        var int count; // 行尾注释
+        // 普通注释
+
+        /// 求和
+        /// 返回 a + b
+        fn int sum(int a, int b) {
+            /* 局部变量 */
+            var int c;
+            c = a + b;
+        }
+        ";
+        let mut parser = Parser::new(DefaultLexer::new(s.as_bytes()));
+        let ast = parser.parse().unwrap();
+        assert_eq!(ast.global.list[0].doc.as_deref(), Some("全局计数"));
+        assert_eq!(ast.funcs[0].doc.as_deref(), Some("求和\n返回 a + b"));
+        assert!(ast.funcs[0].body.list.len() == 2);
+    }
+
+    #[test]
+    fn test_parser_lossless_tree() {
+        use crate::cst::AstNode;
+        let s = "/// 计数\nvar int a, b; // 尾注释\n\nfn int f(int x) {\n  a = x * (b + a /* c */);\n}\n";
+        let (tree, errors) = Parser::new(DefaultLexer::new(s.as_bytes())).parse_tree();
+        assert_eq!(tree.text(), s);
+        assert!(errors.is_empty());
+        let file = cst::SourceFile::cast(&tree).unwrap();
+        let var = file.globals()[0];
+        assert_eq!(var.doc().as_deref(), Some("计数"));
+        assert_eq!(var.names(), vec!["a", "b"]);
+        let f = file.funcs()[0];
+        assert_eq!(f.name(), Some("f"));
+        assert_eq!(f.params()[0].name(), Some("x"));
+        assert_eq!(f.params()[0].type_name(), Some("int"));
+        let assign = f.body().unwrap().assigns()[0];
+        assert_eq!(assign.rhs().unwrap().kind, SyntaxKind::BinaryExpr);
+        assert_eq!(assign.rhs().unwrap().text(), "x * (b + a /* c */)");
+        // 有错误时仍然无损
+        let s = "var int ;\nfn int g() { x = 'ab' }\nvar float y;\n";
+        let (tree, errors) = Parser::new(DefaultLexer::new(s.as_bytes())).parse_tree();
+        assert_eq!(tree.text(), s);
+        assert!(errors.len() >= 2);
+        let file = cst::SourceFile::cast(&tree).unwrap();
+        assert_eq!(file.globals().last().unwrap().names(), vec!["y"]);
+    }
+
+    //recover 用默认实现的词法器出错时也要报告错误
+    #[test]
+    fn test_parser_lex_error_without_recover() {
+        struct NoRecover<'a>(DefaultLexer<&'a [u8]>);
+        impl<'a> lexer for NoRecover<'a> {
+            fn lex_with_trivia(&mut self) -> Result<Lexeme, LexerError> {
+                self.0.lex_with_trivia()
+            }
+        }
+        let s = "var int a;\nfn int f() { a = ''; }\n";
+        let (_, errors) = Parser::new(NoRecover(DefaultLexer::new(s.as_bytes()))).parse_tree();
+        match errors.first() {
+            Some(SyntaxError {
+                err: ParseError::Lex(LexerError::InvalidChar(_)),
+                ..
+            }) => (),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_line_col() {
+        let s = "var int a;\nfn int 函数() {\n}";
+        assert_eq!(line_col(s, 0), (1, 1));
+        assert_eq!(line_col(s, s.find("fn").unwrap()), (2, 1));
+        assert_eq!(line_col(s, s.find('(').unwrap()), (2, 10));
+        assert_eq!(line_col(s, s.len()), (3, 2));
+    }
 }
@@ -0,0 +1,172 @@
+use crate::vm::{Function, Instruction, INSTRUCTIONS};
+use std::collections::HashMap;
+
+//虚拟机的执行剖析。打开后每执行一条指令按操作码和所在的函数计数，
+//每执行 interval 条指令记下一次调用栈，看时间花在哪些调用路径上。
+//report 是给人看的汇总；folded 是火焰图工具（flamegraph.pl、inferno）读的格式，
+//每行一个调用栈，外层在前，函数名用 `;` 连接，后面是采样次数。
+
+//默认每多少条指令采样一次调用栈
+pub const SAMPLE_INTERVAL: u64 = 100;
+
+pub struct Profile {
+    names: Vec<String>,  //函数名，最后一个 `?` 是函数之外的代码，比如 text[0] 的 Exit
+    owner: Vec<usize>,   //text 中每个位置所在的函数
+    pub total: u64,      //执行的指令数
+    pub ops: Vec<u64>,   //每种指令的执行次数，下标是编码
+    pub funcs: Vec<u64>, //每个函数自身执行的指令数，不含它调用的函数
+    pub samples: HashMap<Vec<usize>, u64>, //调用栈里的函数编号，外层在前，和采样次数
+    interval: u64,
+    next: u64, //下一次采样前还要执行的指令数
+}
+
+impl Profile {
+    pub fn new(funcs: &[Function], text: usize, interval: u64) -> Profile {
+        let mut names: Vec<String> = funcs.iter().map(|f| f.name.clone()).collect();
+        names.push("?".to_owned());
+        let mut owner = vec![funcs.len(); text];
+        for (i, f) in funcs.iter().enumerate() {
+            for o in owner.iter_mut().take(f.end).skip(f.entry) {
+                *o = i;
+            }
+        }
+        let interval = interval.max(1);
+        Profile {
+            names,
+            owner,
+            total: 0,
+            ops: vec![0; INSTRUCTIONS.len() + 1],
+            funcs: vec![0; funcs.len() + 1],
+            samples: HashMap::new(),
+            interval,
+            next: interval,
+        }
+    }
+
+    //执行了 pc 处的 op。需要采样时返回 true，虚拟机接着调用 sample
+    pub fn count(&mut self, pc: usize, op: Instruction) -> bool {
+        self.total += 1;
+        self.ops[op as usize] += 1;
+        let f = self.func(pc);
+        self.funcs[f] += 1;
+        self.next -= 1;
+        if self.next == 0 {
+            self.next = self.interval;
+            return true;
+        }
+        false
+    }
+
+    //pcs 是调用栈上每一层正在执行的位置，当前的在最前面
+    pub fn sample(&mut self, pcs: &[usize]) {
+        let stack: Vec<usize> = pcs.iter().rev().map(|pc| self.func(*pc)).collect();
+        *self.samples.entry(stack).or_insert(0) += 1;
+    }
+
+    fn func(&self, pc: usize) -> usize {
+        self.owner.get(pc).copied().unwrap_or(self.names.len() - 1)
+    }
+
+    pub fn name(&self, f: usize) -> &str {
+        &self.names[f]
+    }
+
+    //按函数和按指令的计数，各自从多到少排列
+    pub fn report(&self) -> String {
+        let total = self.total.max(1) as f64;
+        let line = |n: u64, name: &str| {
+            format!("{:>12} {:>5.1}%  {}\n", n, n as f64 * 100.0 / total, name)
+        };
+        let mut out = format!(
+            "{} instructions executed\n\nby function (self):\n",
+            self.total
+        );
+        let mut funcs: Vec<(u64, &str)> = self
+            .funcs
+            .iter()
+            .zip(self.names.iter())
+            .filter(|(n, _)| **n > 0)
+            .map(|(n, name)| (*n, name.as_str()))
+            .collect();
+        funcs.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        for (n, name) in funcs.iter() {
+            out.push_str(&line(*n, name));
+        }
+        out.push_str("\nby instruction:\n");
+        let mut ops: Vec<(u64, String)> = INSTRUCTIONS
+            .iter()
+            .filter(|op| self.ops[**op as usize] > 0)
+            .map(|op| (self.ops[*op as usize], format!("{:?}", op)))
+            .collect();
+        ops.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        for (n, name) in ops.iter() {
+            out.push_str(&line(*n, name));
+        }
+        let samples: u64 = self.samples.values().sum();
+        out.push_str(&format!(
+            "\n{} stack samples, one every {} instructions\n",
+            samples, self.interval
+        ));
+        out
+    }
+
+    //火焰图工具的输入，按调用栈排序
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .samples
+            .iter()
+            .map(|(stack, n)| {
+                let names: Vec<&str> = stack.iter().map(|f| self.name(*f)).collect();
+                format!("{} {}\n", names.join(";"), n)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use crate::vm::{Value, VM};
+
+    #[test]
+    fn test_profile() {
+        let s = "fn int fib(int n) {
+    if n < 2 {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+fn int main() {
+    return fib(10);
+}
+";
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        vm.set_profile(1);
+        assert_eq!(vm.call(p.func("main").unwrap(), &[]), Ok(Value::Int(55)));
+        let profile = vm.profile().unwrap();
+        assert!(profile.total > 1000);
+        assert_eq!(profile.ops.iter().sum::<u64>(), profile.total);
+        assert_eq!(profile.funcs.iter().sum::<u64>(), profile.total);
+        //fib 被调用 177 次，加上 main 一共执行 178 次 Ent
+        assert_eq!(profile.ops[Instruction::Ent as usize], 178);
+        assert!(profile.funcs[0] > profile.funcs[1]);
+        //每条指令都采样，最后的 Exit 在函数之外
+        assert_eq!(profile.samples.values().sum::<u64>(), profile.total);
+        assert_eq!(profile.samples[&vec![2]], 1);
+        let folded = profile.folded();
+        assert!(folded.contains("main;fib;fib;fib "));
+        assert!(folded.lines().all(|l| l.starts_with("main") || l == "? 1"));
+        let report = profile.report();
+        assert!(report.starts_with(&format!("{} instructions executed", profile.total)));
+        assert!(report.contains("%  Ent\n"));
+    }
+}
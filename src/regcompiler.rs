@@ -0,0 +1,580 @@
+use crate::ast::{CallExpr, ExprNode, FuncDecl, StmtNode, AST};
+use crate::compiler::{
+    binary_op, builtin, join, result_type, type_name, Builtin, CompileError, CompileResult,
+};
+use crate::lexer::{KeyWord, Operator, Token};
+use crate::natives::native;
+use crate::regvm::{Function, Op, Program, Reg};
+use crate::vm::{Global, Instruction};
+use std::collections::HashMap;
+
+//把 AST 编译成 regvm 的字节码。参数占窗口开头的寄存器，局部变量接着往后分配，
+//表达式的中间值放在更高的临时寄存器里，每条语句结束后回收。
+//局部变量直接就是寄存器，读它不需要指令；赋值时右边的结果直接写进变量的寄存器。
+//类型规则和 compiler 一样。
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    Global(usize),
+    Local(Reg),
+}
+
+struct Compiler<'a> {
+    p: Program,
+    funcs: HashMap<&'a str, usize>,
+    globals: HashMap<&'a str, usize>,
+    scopes: Vec<HashMap<&'a str, (Var, KeyWord)>>,
+    top: Reg, //下一个空闲的寄存器
+    max: Reg, //当前函数用到的寄存器个数
+    ret: KeyWord,
+}
+
+pub fn compile(ast: &AST) -> CompileResult<Program> {
+    let mut c = Compiler {
+        p: Program::default(),
+        funcs: HashMap::new(),
+        globals: HashMap::new(),
+        scopes: Vec::new(),
+        top: 0,
+        max: 0,
+        ret: KeyWord::Int,
+    };
+    for spec in ast.global.list.iter() {
+        for ident in spec.names.iter() {
+            c.globals.insert(&ident.name, c.p.globals.len());
+            c.p.globals.push(Global {
+                name: ident.name.clone(),
+                typ: spec.typ,
+            });
+        }
+    }
+    for f in ast.funcs.iter() {
+        c.funcs.insert(&f.fn_name.name, c.p.funcs.len());
+        c.p.funcs.push(Function {
+            name: f.fn_name.name.clone(),
+            entry: 0,
+            end: 0,
+            params: f.params.iter().map(|p| p.typ).collect(),
+            ret: f.typ,
+            regs: 0,
+        });
+    }
+    for (i, f) in ast.funcs.iter().enumerate() {
+        c.compile_fn(i, f)?;
+    }
+    Ok(c.p)
+}
+
+//三地址的二元运算
+fn three(op: Instruction, dst: Reg, a: Reg, b: Reg) -> Op {
+    match op {
+        Instruction::Add => Op::Add { dst, a, b },
+        Instruction::Sub => Op::Sub { dst, a, b },
+        Instruction::Mul => Op::Mul { dst, a, b },
+        Instruction::Div => Op::Div { dst, a, b },
+        Instruction::Mod => Op::Mod { dst, a, b },
+        Instruction::And => Op::And { dst, a, b },
+        Instruction::Or => Op::Or { dst, a, b },
+        Instruction::Xor => Op::Xor { dst, a, b },
+        Instruction::Shl => Op::Shl { dst, a, b },
+        Instruction::Shr => Op::Shr { dst, a, b },
+        Instruction::Eq => Op::Eq { dst, a, b },
+        Instruction::Ne => Op::Ne { dst, a, b },
+        Instruction::Lt => Op::Lt { dst, a, b },
+        Instruction::Gt => Op::Gt { dst, a, b },
+        Instruction::Le => Op::Le { dst, a, b },
+        Instruction::Ge => Op::Ge { dst, a, b },
+        Instruction::FAdd => Op::FAdd { dst, a, b },
+        Instruction::FSub => Op::FSub { dst, a, b },
+        Instruction::FMul => Op::FMul { dst, a, b },
+        Instruction::FDiv => Op::FDiv { dst, a, b },
+        Instruction::FMod => Op::FMod { dst, a, b },
+        Instruction::FEq => Op::FEq { dst, a, b },
+        Instruction::FNe => Op::FNe { dst, a, b },
+        Instruction::FLt => Op::FLt { dst, a, b },
+        Instruction::FGt => Op::FGt { dst, a, b },
+        Instruction::FLe => Op::FLe { dst, a, b },
+        Instruction::FGe => Op::FGe { dst, a, b },
+        op => unreachable!("{:?} is not a binary operator", op),
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn compile_fn(&mut self, index: usize, f: &'a FuncDecl<StmtNode>) -> CompileResult<()> {
+        self.ret = f.typ;
+        self.p.funcs[index].entry = self.p.code.len();
+        let mut params = HashMap::new();
+        for (i, p) in f.params.iter().enumerate() {
+            params.insert(p.ident.name.as_str(), (Var::Local(i as Reg), p.typ));
+        }
+        self.scopes = vec![params];
+        self.top = f.params.len() as Reg;
+        self.max = self.top;
+        self.line(f.line);
+        self.compile_block(&f.body.list, &f.body.lines)?;
+        //没有 return 时返回 0
+        let r = self.alloc();
+        self.emit(Op::Int { dst: r, n: 0 });
+        self.emit(Op::Ret { src: r });
+        self.p.funcs[index].regs = self.max as usize;
+        self.p.funcs[index].end = self.p.code.len();
+        Ok(())
+    }
+
+    fn compile_block(&mut self, list: &'a [StmtNode], lines: &[u32]) -> CompileResult<()> {
+        self.scopes.push(HashMap::new());
+        let top = self.top;
+        for (stmt, line) in list.iter().zip(lines.iter()) {
+            self.line(*line);
+            let mark = self.top;
+            self.compile_stmt(stmt)?;
+            //变量声明占住的寄存器留到块结束
+            if let StmtNode::ValueSepc(_) = stmt {
+                continue;
+            }
+            self.top = mark;
+        }
+        self.top = top;
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn compile_stmt(&mut self, stmt: &'a StmtNode) -> CompileResult<()> {
+        match stmt {
+            StmtNode::ValueSepc(spec) => {
+                for ident in spec.names.iter() {
+                    let r = self.alloc();
+                    self.scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert(ident.name.as_str(), (Var::Local(r), spec.typ));
+                    //寄存器里可能是上次调用留下的值，清零
+                    self.emit(Op::Int { dst: r, n: 0 });
+                }
+            }
+            StmtNode::AssignStmt(assign) => {
+                let (var, typ) = match &assign.x {
+                    ExprNode::IdentExpr(ident) => self.lookup(&ident.name)?,
+                    _ => return Err(CompileError::NotAssignable),
+                };
+                match var {
+                    Var::Local(r) => self.expr_to(&assign.y, r, typ)?,
+                    Var::Global(g) => {
+                        let src = self.expr_as(&assign.y, typ)?;
+                        self.emit(Op::SetGlobal { g: g as u32, src });
+                    }
+                }
+            }
+            StmtNode::ExprStmt(x) => {
+                self.expr(x, None)?;
+            }
+            StmtNode::ReturnStmt(ret) => {
+                let src = match &ret.x {
+                    Some(x) => self.expr_as(x, self.ret)?,
+                    None => {
+                        let r = self.alloc();
+                        self.emit(Op::Int { dst: r, n: 0 });
+                        r
+                    }
+                };
+                self.emit(Op::Ret { src });
+            }
+            StmtNode::BlockStmt(block) => self.compile_block(&block.list, &block.lines)?,
+            StmtNode::IfStmt(s) => {
+                let jz = self.cond(&s.cond)?;
+                self.compile_block(&s.body.list, &s.body.lines)?;
+                match &s.els {
+                    Some(els) => {
+                        let jmp = self.emit(Op::Jmp { to: 0 });
+                        self.patch(jz);
+                        self.compile_stmt(els)?;
+                        self.patch(jmp);
+                    }
+                    None => self.patch(jz),
+                }
+            }
+            StmtNode::WhileStmt(s) => {
+                let start = self.p.code.len();
+                let jz = self.cond(&s.cond)?;
+                self.compile_block(&s.body.list, &s.body.lines)?;
+                self.emit(Op::Jmp { to: start as u32 });
+                self.patch(jz);
+            }
+        }
+        Ok(())
+    }
+
+    //编译表达式，返回结果所在的寄存器和类型。给出 dst 时结果一定写进 dst
+    fn expr(&mut self, x: &'a ExprNode, dst: Option<Reg>) -> CompileResult<(Reg, KeyWord)> {
+        match x {
+            ExprNode::IdentExpr(ident) => {
+                let (var, typ) = self.lookup(&ident.name)?;
+                match (var, dst) {
+                    (Var::Local(r), None) => Ok((r, typ)),
+                    (Var::Local(r), Some(d)) => {
+                        if r != d {
+                            self.emit(Op::Move { dst: d, src: r });
+                        }
+                        Ok((d, typ))
+                    }
+                    (Var::Global(g), _) => {
+                        let d = self.target(dst);
+                        self.emit(Op::GetGlobal {
+                            dst: d,
+                            g: g as u32,
+                        });
+                        Ok((d, typ))
+                    }
+                }
+            }
+            ExprNode::BasicLit(lit) => {
+                let d = self.target(dst);
+                match &lit.value {
+                    Token::Number(n) => {
+                        self.emit(Op::Int {
+                            dst: d,
+                            n: *n as i64,
+                        });
+                        Ok((d, KeyWord::Int))
+                    }
+                    Token::Float(n) => {
+                        let k = self.constant(n.to_bits()) as u32;
+                        self.emit(Op::Const { dst: d, k });
+                        Ok((d, KeyWord::Float))
+                    }
+                    t => Err(CompileError::Unsupported(format!("literal {:?}", t))),
+                }
+            }
+            ExprNode::ParenExpr(p) => self.expr(&p.x, dst),
+            ExprNode::UnaryExpr(u) => {
+                let typ = self.type_of(&u.x)?;
+                //和 compiler 一样写成 init op x
+                let (init, op) = match (&u.op, typ) {
+                    (Token::Oper(Operator::Add), _) => return self.expr(&u.x, dst),
+                    (Token::Oper(Operator::Sub), KeyWord::Float) => (0, Instruction::FSub),
+                    (Token::Oper(Operator::Sub), _) => (0, Instruction::Sub),
+                    (Token::Oper(Operator::LogicNot), KeyWord::Float) => (0, Instruction::FEq),
+                    (Token::Oper(Operator::LogicNot), _) => (0, Instruction::Eq),
+                    (Token::Oper(Operator::BitNot), KeyWord::Int) => (-1, Instruction::Xor),
+                    (op, _) => {
+                        return Err(CompileError::Unsupported(format!(
+                            "operator {:?} on {}",
+                            op,
+                            type_name(typ)
+                        )))
+                    }
+                };
+                let (v, _) = self.expr(&u.x, None)?;
+                let a = self.alloc();
+                self.emit(Op::Int { dst: a, n: init });
+                let d = self.target(dst);
+                self.emit(three(op, d, a, v));
+                Ok((d, result_type(op, typ)))
+            }
+            ExprNode::BinaryExpr(b) => {
+                let typ = join(self.type_of(&b.x)?, self.type_of(&b.y)?);
+                let op = binary_op(&b.op, typ)?;
+                let x = self.expr_as(&b.x, typ)?;
+                let y = self.expr_as(&b.y, typ)?;
+                let d = self.target(dst);
+                self.emit(three(op, d, x, y));
+                Ok((d, result_type(op, typ)))
+            }
+            ExprNode::CallExpr(call) if self.builtin(call).is_some() => {
+                let b = self.builtin(call).unwrap();
+                if b.op != Instruction::Trap {
+                    return Err(CompileError::Unsupported(format!(
+                        "builtin `{}` on the register VM",
+                        b.name
+                    )));
+                }
+                if call.args.len() != 1 {
+                    return Err(CompileError::Arity("trap".to_owned(), 1, call.args.len()));
+                }
+                let src = self.expr_as(&call.args[0], KeyWord::Int)?;
+                self.emit(Op::Trap { src });
+                Ok((src, KeyWord::Int))
+            }
+            ExprNode::CallExpr(call) => {
+                let f = self.callee(call)?;
+                let params = self.p.funcs[f].params.clone();
+                //实参依次放进从 base 开始的连续寄存器
+                let base = self.top;
+                for (i, (arg, typ)) in call.args.iter().zip(params.iter()).enumerate() {
+                    self.top = base + i as Reg;
+                    let r = self.alloc();
+                    self.expr_to(arg, r, *typ)?;
+                }
+                self.top = base;
+                let d = self.target(dst);
+                self.emit(Op::Call {
+                    dst: d,
+                    f: f as u32,
+                    args: base,
+                });
+                Ok((d, self.p.funcs[f].ret))
+            }
+        }
+    }
+
+    //结果转成 typ，返回所在的寄存器
+    fn expr_as(&mut self, x: &'a ExprNode, typ: KeyWord) -> CompileResult<Reg> {
+        let (r, from) = self.expr(x, None)?;
+        if from == typ {
+            return Ok(r);
+        }
+        let d = self.alloc();
+        self.convert(d, r, typ);
+        Ok(d)
+    }
+
+    //结果转成 typ 写进 dst
+    fn expr_to(&mut self, x: &'a ExprNode, dst: Reg, typ: KeyWord) -> CompileResult<()> {
+        if self.type_of(x)? == typ {
+            self.expr(x, Some(dst))?;
+        } else {
+            let (r, _) = self.expr(x, None)?;
+            self.convert(dst, r, typ);
+        }
+        Ok(())
+    }
+
+    fn convert(&mut self, dst: Reg, src: Reg, typ: KeyWord) {
+        match typ {
+            KeyWord::Float => self.emit(Op::Itof { dst, src }),
+            _ => self.emit(Op::Ftoi { dst, src }),
+        };
+    }
+
+    //条件为假时跳转，返回 Jz 的位置用来回填。-0.0 也是假
+    fn cond(&mut self, x: &'a ExprNode) -> CompileResult<usize> {
+        let (mut r, typ) = self.expr(x, None)?;
+        if typ == KeyWord::Float {
+            let zero = self.alloc();
+            self.emit(Op::Int { dst: zero, n: 0 });
+            let d = self.alloc();
+            self.emit(Op::FNe {
+                dst: d,
+                a: r,
+                b: zero,
+            });
+            r = d;
+        }
+        Ok(self.emit(Op::Jz { cond: r, to: 0 }))
+    }
+
+    fn type_of(&self, x: &ExprNode) -> CompileResult<KeyWord> {
+        match x {
+            ExprNode::IdentExpr(ident) => Ok(self.lookup(&ident.name)?.1),
+            ExprNode::BasicLit(lit) => match &lit.value {
+                Token::Float(_) => Ok(KeyWord::Float),
+                _ => Ok(KeyWord::Int),
+            },
+            ExprNode::ParenExpr(p) => self.type_of(&p.x),
+            ExprNode::UnaryExpr(u) => match &u.op {
+                Token::Oper(Operator::LogicNot) => Ok(KeyWord::Int),
+                _ => self.type_of(&u.x),
+            },
+            ExprNode::BinaryExpr(b) => {
+                let typ = join(self.type_of(&b.x)?, self.type_of(&b.y)?);
+                Ok(result_type(binary_op(&b.op, typ)?, typ))
+            }
+            ExprNode::CallExpr(call) => match self.builtin(call) {
+                Some(b) => Ok(b.ret),
+                None => Ok(self.p.funcs[self.callee(call)?].ret),
+            },
+        }
+    }
+
+    //寄存器虚拟机没有堆，内置函数只支持 trap
+    fn builtin(&self, call: &CallExpr) -> Option<&'static Builtin> {
+        match &*call.fun {
+            ExprNode::IdentExpr(ident) => builtin(&ident.name)
+                .filter(|_| matches!(self.lookup(&ident.name), Err(CompileError::Undefined(_)))),
+            _ => None,
+        }
+    }
+
+    fn callee(&self, call: &CallExpr) -> CompileResult<usize> {
+        let name = match &*call.fun {
+            ExprNode::IdentExpr(ident) => ident.name.as_str(),
+            _ => {
+                return Err(CompileError::Unsupported(
+                    "calling an expression".to_owned(),
+                ))
+            }
+        };
+        if self.scopes.iter().any(|s| s.contains_key(name)) || self.globals.contains_key(name) {
+            return Err(CompileError::NotFunction(name.to_owned()));
+        }
+        let f = match self.funcs.get(name) {
+            Some(f) => *f,
+            None if native(name).is_some() => {
+                return Err(CompileError::Unsupported(format!(
+                    "builtin `{}` on the register VM",
+                    name
+                )))
+            }
+            None => return Err(CompileError::Undefined(name.to_owned())),
+        };
+        let params = self.p.funcs[f].params.len();
+        if params != call.args.len() {
+            return Err(CompileError::Arity(
+                name.to_owned(),
+                params,
+                call.args.len(),
+            ));
+        }
+        Ok(f)
+    }
+
+    fn lookup(&self, name: &str) -> CompileResult<(Var, KeyWord)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(v) = scope.get(name) {
+                return Ok(*v);
+            }
+        }
+        if let Some(g) = self.globals.get(name) {
+            return Ok((Var::Global(*g), self.p.globals[*g].typ));
+        }
+        if self.funcs.contains_key(name) {
+            return Err(CompileError::NotVariable(name.to_owned()));
+        }
+        Err(CompileError::Undefined(name.to_owned()))
+    }
+
+    //dst 没有给出时分配一个临时寄存器
+    fn target(&mut self, dst: Option<Reg>) -> Reg {
+        match dst {
+            Some(d) => d,
+            None => self.alloc(),
+        }
+    }
+
+    fn alloc(&mut self) -> Reg {
+        let r = self.top;
+        self.top += 1;
+        self.max = self.max.max(self.top);
+        r
+    }
+
+    fn line(&mut self, line: u32) {
+        let at = self.p.code.len();
+        match self.p.lines.last_mut() {
+            Some(last) if last.0 == at => last.1 = line,
+            _ => self.p.lines.push((at, line)),
+        }
+    }
+
+    fn constant(&mut self, v: u64) -> usize {
+        match self.p.consts.iter().position(|c| *c == v) {
+            Some(i) => i,
+            None => {
+                self.p.consts.push(v);
+                self.p.consts.len() - 1
+            }
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.p.code.push(op);
+        self.p.code.len() - 1
+    }
+
+    //让 at 处的跳转指向当前位置
+    fn patch(&mut self, at: usize) {
+        let here = self.p.code.len() as u32;
+        match &mut self.p.code[at] {
+            Op::Jmp { to } | Op::Jz { to, .. } | Op::Jnz { to, .. } => *to = here,
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+    use crate::regvm::VM;
+    use crate::vm::{self, ErrorKind, Value};
+    use Value::{Float, Int};
+
+    #[test]
+    fn test_regvm() {
+        let s = "
+        var int count;
+        fn int fib(int n) {
+            count = count + 1;
+            if n < 2 {
+                return n;
+            }
+            return fib(n - 1) + fib(n - 2);
+        }
+        fn int sum(int a, int b) {
+            var int i, s;
+            i = a;
+            while i <= b {
+                s = s + i;
+                i = i + 1;
+            }
+            return s;
+        }
+        fn float mix(int a, float b) {
+            var float x;
+            x = a / 2 + b;
+            { var int x; x = 7; b = b * x; }
+            return x - -b;
+        }
+        fn int nest(int x) {
+            return sum(1, fib(x)) + sum(x, x) % 4 - (~x ^ 3);
+        }
+        fn int fail(int x) {
+            if x > 0 { return 10 / (x - x); }
+            trap(x);
+        }
+        ";
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        let p = compile(&ast).unwrap();
+        let q = crate::compiler::compile(&ast).unwrap();
+        let mut vm = VM::new(&p);
+        let mut sm = vm::VM::new(&q);
+        //和栈虚拟机的结果一致
+        let cases: Vec<(&str, Vec<Value>)> = vec![
+            ("fib", vec![Int(15)]),
+            ("sum", vec![Int(-3), Int(100)]),
+            ("mix", vec![Int(5), Float(0.5)]),
+            ("nest", vec![Int(6)]),
+        ];
+        for (f, args) in cases.iter() {
+            let want = sm.call(q.func(f).unwrap(), args).unwrap();
+            assert_eq!(vm.call(p.func(f).unwrap(), args).unwrap(), want, "{}", f);
+        }
+        assert_eq!(
+            vm.call(p.func("mix").unwrap(), &[Int(5), Float(0.5)])
+                .unwrap(),
+            Float(6.0)
+        );
+        vm.set_global(p.global("count").unwrap(), 0);
+        vm.call(p.func("fib").unwrap(), &[Int(5)]).unwrap();
+        assert_eq!(vm.global(p.global("count").unwrap()), 15);
+
+        let err = vm.call(p.func("fail").unwrap(), &[Int(1)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivByZero);
+        assert_eq!(err.backtrace[0].line, Some(29));
+        let err = vm.call(p.func("fail").unwrap(), &[Int(-2)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Trap(-2));
+        vm.set_max_depth(5);
+        let err = vm.call(p.func("fib").unwrap(), &[Int(6)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::CallDepth(5));
+        assert_eq!(err.backtrace.len(), 5);
+        assert_eq!(vm.call(p.func("fib").unwrap(), &[Int(5)]), Ok(Int(5)));
+        vm.set_max_depth(crate::vm::MAX_DEPTH);
+        //局部变量直接用寄存器，s = s + i 只是一条 Add
+        assert!(p
+            .dump(p.func("sum").unwrap())
+            .contains("Add dst=3, a=3, b=2"));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::lexer::KeyWord;
+use crate::vm::{
+    line_at, ErrorKind, Frame, Global, Value, VmError, VmResult, MAX_BACKTRACE, MAX_DEPTH,
+};
+use std::fmt;
+
+//寄存器虚拟机。和 vm 的累加器加栈的设计不同，这里每条指令直接读写寄存器：
+//`a = b + c * 2` 不用再 Push/Pop，只要两三条指令。
+//每次调用有自己的寄存器窗口，r0 开始依次是参数、局部变量和临时值。
+//调用时实参放在调用者窗口中连续的寄存器里，被调用者的窗口就从那里开始，
+//所以参数不需要复制。值的表示和 vm 一样：int 是 i64，float 是 f64 的位模式。
+
+pub type Reg = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Int { dst: Reg, n: i64 },
+    Const { dst: Reg, k: u32 }, //常量池中的第 k 个
+    Move { dst: Reg, src: Reg },
+    GetGlobal { dst: Reg, g: u32 },
+    SetGlobal { g: u32, src: Reg },
+    Add { dst: Reg, a: Reg, b: Reg },
+    Sub { dst: Reg, a: Reg, b: Reg },
+    Mul { dst: Reg, a: Reg, b: Reg },
+    Div { dst: Reg, a: Reg, b: Reg },
+    Mod { dst: Reg, a: Reg, b: Reg },
+    And { dst: Reg, a: Reg, b: Reg },
+    Or { dst: Reg, a: Reg, b: Reg },
+    Xor { dst: Reg, a: Reg, b: Reg },
+    Shl { dst: Reg, a: Reg, b: Reg },
+    Shr { dst: Reg, a: Reg, b: Reg },
+    Eq { dst: Reg, a: Reg, b: Reg },
+    Ne { dst: Reg, a: Reg, b: Reg },
+    Lt { dst: Reg, a: Reg, b: Reg },
+    Gt { dst: Reg, a: Reg, b: Reg },
+    Le { dst: Reg, a: Reg, b: Reg },
+    Ge { dst: Reg, a: Reg, b: Reg },
+    FAdd { dst: Reg, a: Reg, b: Reg },
+    FSub { dst: Reg, a: Reg, b: Reg },
+    FMul { dst: Reg, a: Reg, b: Reg },
+    FDiv { dst: Reg, a: Reg, b: Reg },
+    FMod { dst: Reg, a: Reg, b: Reg },
+    FEq { dst: Reg, a: Reg, b: Reg },
+    FNe { dst: Reg, a: Reg, b: Reg },
+    FLt { dst: Reg, a: Reg, b: Reg },
+    FGt { dst: Reg, a: Reg, b: Reg },
+    FLe { dst: Reg, a: Reg, b: Reg },
+    FGe { dst: Reg, a: Reg, b: Reg },
+    Itof { dst: Reg, src: Reg },
+    Ftoi { dst: Reg, src: Reg },
+    Jmp { to: u32 },
+    Jz { cond: Reg, to: u32 },
+    Jnz { cond: Reg, to: u32 },
+    //调用第 f 个函数，实参在 args 开始的寄存器里，返回值写进 dst
+    Call { dst: Reg, f: u32, args: Reg },
+    Ret { src: Reg },
+    Trap { src: Reg },
+}
+
+impl fmt::Display for Op {
+    //`Add dst=0, a=1, b=2` 这样的写法
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = format!("{:?}", self);
+        write!(
+            f,
+            "{}",
+            s.replace(" {", "").replace(" }", "").replace(": ", "=")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    pub end: usize,
+    pub params: Vec<KeyWord>,
+    pub ret: KeyWord,
+    pub regs: usize, //窗口中用到的寄存器个数
+}
+
+//只能由 regcompiler 生成：VM::run 不做边界检查，依赖编译器保证寄存器编号小于
+//所在函数的 regs，常量、全局变量和函数下标都在范围内，每个函数都以 Ret 结束。
+//所以字段不对 crate 外公开，外部只能通过 func/global 查询
+#[derive(Debug, Default)]
+pub struct Program {
+    pub(crate) code: Vec<Op>,
+    pub(crate) consts: Vec<u64>,
+    pub(crate) funcs: Vec<Function>,
+    pub(crate) globals: Vec<Global>,
+    pub(crate) lines: Vec<(usize, u32)>, //从 code 的这个下标开始的指令属于源码的哪一行
+}
+
+impl Program {
+    pub fn func(&self, name: &str) -> Option<usize> {
+        self.funcs.iter().position(|f| f.name == name)
+    }
+
+    pub fn global(&self, name: &str) -> Option<usize> {
+        self.globals.iter().position(|g| g.name == name)
+    }
+
+    //函数 f 的指令清单
+    pub fn dump(&self, f: usize) -> String {
+        let func = &self.funcs[f];
+        let mut out = format!("fn {} ({} registers):\n", func.name, func.regs);
+        for i in func.entry..func.end {
+            out.push_str(&format!("{:5}  {}\n", i, self.code[i]));
+        }
+        out
+    }
+}
+
+//寄存器总数的上限，超过时报告栈溢出
+const MAX_REGS: usize = 1024 * 1024;
+
+//调用者的状态，返回时恢复
+struct CallFrame {
+    func: usize,
+    pc: usize,   //返回后继续执行的位置
+    base: usize, //调用者窗口的起点
+    dst: Reg,    //返回值写到调用者的这个寄存器
+}
+
+pub struct VM {
+    code: Vec<Op>,
+    consts: Vec<u64>,
+    funcs: Vec<Function>,
+    lines: Vec<(usize, u32)>,
+    globals: Vec<u64>,
+    regs: Vec<u64>,
+    frames: Vec<CallFrame>,
+    max_depth: usize,
+}
+
+impl VM {
+    pub fn new(p: &Program) -> VM {
+        VM {
+            code: p.code.clone(),
+            consts: p.consts.clone(),
+            funcs: p.funcs.clone(),
+            lines: p.lines.clone(),
+            globals: vec![0; p.globals.len()],
+            regs: Vec::new(),
+            frames: Vec::new(),
+            max_depth: MAX_DEPTH,
+        }
+    }
+
+    pub fn set_max_depth(&mut self, n: usize) {
+        self.max_depth = n;
+    }
+
+    pub fn global(&self, i: usize) -> u64 {
+        self.globals[i]
+    }
+
+    pub fn set_global(&mut self, i: usize, v: u64) {
+        self.globals[i] = v;
+    }
+
+    //调用第 f 个函数，参数转成声明的类型，返回它的返回值
+    pub fn call(&mut self, f: usize, args: &[Value]) -> VmResult<Value> {
+        self.frames.clear();
+        let func = self.funcs[f].clone();
+        if args.len() != func.params.len() {
+            return Err(VmError {
+                kind: ErrorKind::Arity(func.params.len(), args.len()),
+                backtrace: Vec::new(),
+            });
+        }
+        self.regs = vec![0; func.regs];
+        for (i, (a, t)) in args.iter().zip(func.params.iter()).enumerate() {
+            self.regs[i] = a.to_bits(*t);
+        }
+        let mut pc = func.entry;
+        let mut func_index = f;
+        match self.run(&mut pc, &mut func_index) {
+            Ok(bits) => Ok(Value::from_bits(bits, func.ret)),
+            //pc 已经指向出错指令的下一条
+            Err(kind) => Err(VmError {
+                kind,
+                backtrace: self.backtrace(pc - 1, func_index),
+            }),
+        }
+    }
+
+    //pc 和 func 放在调用者那里，出错时用来生成调用栈
+    fn run(&mut self, pc: &mut usize, func: &mut usize) -> Result<u64, ErrorKind> {
+        let mut base = 0;
+        loop {
+            let op = self.code[*pc];
+            *pc += 1;
+            let r = &mut self.regs;
+            macro_rules! int {
+                ($dst:expr, $a:expr, $b:expr, |$x:ident, $y:ident| $e:expr) => {{
+                    let $x = r[base + $a as usize] as i64;
+                    let $y = r[base + $b as usize] as i64;
+                    r[base + $dst as usize] = ($e) as u64;
+                }};
+            }
+            macro_rules! float {
+                ($dst:expr, $a:expr, $b:expr, |$x:ident, $y:ident| $e:expr) => {{
+                    let $x = f64::from_bits(r[base + $a as usize]);
+                    let $y = f64::from_bits(r[base + $b as usize]);
+                    r[base + $dst as usize] = $e;
+                }};
+            }
+            match op {
+                Op::Int { dst, n } => r[base + dst as usize] = n as u64,
+                Op::Const { dst, k } => r[base + dst as usize] = self.consts[k as usize],
+                Op::Move { dst, src } => r[base + dst as usize] = r[base + src as usize],
+                Op::GetGlobal { dst, g } => r[base + dst as usize] = self.globals[g as usize],
+                Op::SetGlobal { g, src } => self.globals[g as usize] = r[base + src as usize],
+                Op::Add { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_add(y)),
+                Op::Sub { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_sub(y)),
+                Op::Mul { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_mul(y)),
+                Op::Div { b, .. } | Op::Mod { b, .. } if r[base + b as usize] == 0 => {
+                    return Err(ErrorKind::DivByZero)
+                }
+                Op::Div { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_div(y)),
+                Op::Mod { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_rem(y)),
+                Op::And { dst, a, b } => int!(dst, a, b, |x, y| x & y),
+                Op::Or { dst, a, b } => int!(dst, a, b, |x, y| x | y),
+                Op::Xor { dst, a, b } => int!(dst, a, b, |x, y| x ^ y),
+                Op::Shl { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_shl(y as u32)),
+                Op::Shr { dst, a, b } => int!(dst, a, b, |x, y| x.wrapping_shr(y as u32)),
+                Op::Eq { dst, a, b } => int!(dst, a, b, |x, y| x == y),
+                Op::Ne { dst, a, b } => int!(dst, a, b, |x, y| x != y),
+                Op::Lt { dst, a, b } => int!(dst, a, b, |x, y| x < y),
+                Op::Gt { dst, a, b } => int!(dst, a, b, |x, y| x > y),
+                Op::Le { dst, a, b } => int!(dst, a, b, |x, y| x <= y),
+                Op::Ge { dst, a, b } => int!(dst, a, b, |x, y| x >= y),
+                Op::FAdd { dst, a, b } => float!(dst, a, b, |x, y| (x + y).to_bits()),
+                Op::FSub { dst, a, b } => float!(dst, a, b, |x, y| (x - y).to_bits()),
+                Op::FMul { dst, a, b } => float!(dst, a, b, |x, y| (x * y).to_bits()),
+                Op::FDiv { dst, a, b } => float!(dst, a, b, |x, y| (x / y).to_bits()),
+                Op::FMod { dst, a, b } => float!(dst, a, b, |x, y| (x % y).to_bits()),
+                Op::FEq { dst, a, b } => float!(dst, a, b, |x, y| (x == y) as u64),
+                Op::FNe { dst, a, b } => float!(dst, a, b, |x, y| (x != y) as u64),
+                Op::FLt { dst, a, b } => float!(dst, a, b, |x, y| (x < y) as u64),
+                Op::FGt { dst, a, b } => float!(dst, a, b, |x, y| (x > y) as u64),
+                Op::FLe { dst, a, b } => float!(dst, a, b, |x, y| (x <= y) as u64),
+                Op::FGe { dst, a, b } => float!(dst, a, b, |x, y| (x >= y) as u64),
+                Op::Itof { dst, src } => {
+                    r[base + dst as usize] = (r[base + src as usize] as i64 as f64).to_bits()
+                }
+                Op::Ftoi { dst, src } => {
+                    r[base + dst as usize] = f64::from_bits(r[base + src as usize]) as i64 as u64
+                }
+                Op::Jmp { to } => *pc = to as usize,
+                Op::Jz { cond, to } => {
+                    if r[base + cond as usize] == 0 {
+                        *pc = to as usize;
+                    }
+                }
+                Op::Jnz { cond, to } => {
+                    if r[base + cond as usize] != 0 {
+                        *pc = to as usize;
+                    }
+                }
+                Op::Call { dst, f, args } => {
+                    let callee = &self.funcs[f as usize];
+                    let new_base = base + args as usize;
+                    let top = new_base + callee.regs;
+                    if top > MAX_REGS {
+                        return Err(ErrorKind::StackOverflow);
+                    }
+                    //frames 里是调用者，加上当前函数和被调用的函数
+                    if self.frames.len() + 2 > self.max_depth {
+                        return Err(ErrorKind::CallDepth(self.max_depth));
+                    }
+                    //寄存器只增不减，局部变量在声明时由编译器清零
+                    if r.len() < top {
+                        r.resize(top, 0);
+                    }
+                    self.frames.push(CallFrame {
+                        func: *func,
+                        pc: *pc,
+                        base,
+                        dst,
+                    });
+                    *func = f as usize;
+                    *pc = callee.entry;
+                    base = new_base;
+                }
+                Op::Ret { src } => {
+                    let v = r[base + src as usize];
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            base = frame.base;
+                            *pc = frame.pc;
+                            *func = frame.func;
+                            r[base + frame.dst as usize] = v;
+                        }
+                        None => return Ok(v),
+                    }
+                }
+                Op::Trap { src } => return Err(ErrorKind::Trap(r[base + src as usize] as i64)),
+            }
+        }
+    }
+
+    //出错的位置加上每一层调用者的 Call 指令
+    fn backtrace(&self, pc: usize, func: usize) -> Vec<Frame> {
+        let mut frames = vec![self.frame(pc, func)];
+        for f in self.frames.iter().rev().take(MAX_BACKTRACE - 1) {
+            frames.push(self.frame(f.pc - 1, f.func));
+        }
+        frames
+    }
+
+    fn frame(&self, pc: usize, func: usize) -> Frame {
+        Frame {
+            func: self.funcs[func].name.clone(),
+            line: line_at(&self.lines, pc),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use crate::ast::{ExprNode, StmtNode, AST};
+use crate::compiler;
+use crate::lexer::{lexer, DefaultLexer, KeyWord, LexerError, Operator, Token};
+use crate::parser::Parser;
+use crate::semantic;
+use crate::vm::{Value, VmError, VM};
+use std::collections::HashMap;
+
+//交互式解释器。输入一行：`var` / `fn` 开头的是全局定义，保留到之后的输入里，
+//同名的定义覆盖之前的；其他输入当作表达式求值，打印值和类型；`:` 开头的是命令。
+//大括号或小括号没有闭合时继续读下一行。
+
+const HELP: &str = ":type <expr>    show the type of an expression
+:ast <expr>     show the syntax tree of an expression
+:bytecode <fn>  show the VM code of a function
+:defs           list the definitions so far
+:reset          forget all definitions
+:quit           leave the REPL";
+
+//表达式放进一个临时函数里解析
+const EXPR_FN: &str = "__repl";
+
+pub struct Repl {
+    defs: Vec<Def>,
+    values: HashMap<String, u64>, //全局变量的值
+    pending: String,              //还没输完的多行输入
+}
+
+//一条全局定义的原文和它定义的名字
+struct Def {
+    names: Vec<String>,
+    text: String,
+}
+
+impl Default for Repl {
+    fn default() -> Repl {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            defs: Vec::new(),
+            values: HashMap::new(),
+            pending: String::new(),
+        }
+    }
+
+    //正在等多行输入的后续行
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    //丢掉还没输完的输入
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        if !self.is_pending() {
+            ">> "
+        } else {
+            ".. "
+        }
+    }
+
+    //输入一行。输入还不完整时返回 None；否则返回要打印的内容或错误
+    pub fn feed(&mut self, line: &str) -> Option<Result<String, String>> {
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        if !complete(&self.pending) {
+            return None;
+        }
+        let input = std::mem::take(&mut self.pending);
+        let input = input.trim();
+        if input.is_empty() {
+            return Some(Ok(String::new()));
+        }
+        Some(self.eval(input))
+    }
+
+    fn eval(&mut self, input: &str) -> Result<String, String> {
+        if input.starts_with(':') {
+            let (cmd, arg) = match input.find(char::is_whitespace) {
+                Some(i) => (&input[..i], input[i..].trim()),
+                None => (input, ""),
+            };
+            return match cmd {
+                ":type" | ":t" => {
+                    let ast = self.parse_expr(arg)?;
+                    Ok(type_name(ast.funcs.last().unwrap().typ).to_owned())
+                }
+                ":ast" => {
+                    let ast = self.parse_expr(arg)?;
+                    Ok(format!("{:#?}", returned(&ast).unwrap()))
+                }
+                ":bytecode" | ":b" => self.bytecode(arg),
+                ":defs" => Ok(self
+                    .defs
+                    .iter()
+                    .map(|d| d.text.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n")),
+                ":reset" => {
+                    self.defs.clear();
+                    self.values.clear();
+                    Ok(String::new())
+                }
+                ":help" | ":h" => Ok(HELP.to_owned()),
+                _ => Err(format!("unknown command `{}`, try :help", cmd)),
+            };
+        }
+        match DefaultLexer::new(input.as_bytes()).lex() {
+            Ok(Token::KeyWord(KeyWord::Var)) | Ok(Token::KeyWord(KeyWord::Fn)) => {
+                self.define(input)
+            }
+            _ if is_stmt(input) => {
+                let ast = self.parse_stmt(input)?;
+                self.evaluate(&ast)?;
+                Ok(String::new())
+            }
+            _ => {
+                let ast = self.parse_expr(input.trim_end_matches(';'))?;
+                let typ = ast.funcs.last().unwrap().typ;
+                let value = self.evaluate(&ast)?;
+                Ok(format!("{} : {}", value, type_name(typ)))
+            }
+        }
+    }
+
+    //全局定义。先单独解析出定义的名字，去掉之前的同名定义后整体检查一遍
+    fn define(&mut self, input: &str) -> Result<String, String> {
+        let ast = Parser::new(DefaultLexer::new(input.as_bytes()))
+            .parse()
+            .map_err(|e| e.to_string())?;
+        let mut names: Vec<String> = Vec::new();
+        for spec in ast.global.list.iter() {
+            names.extend(spec.names.iter().map(|i| i.name.clone()));
+        }
+        names.extend(ast.funcs.iter().map(|f| f.fn_name.name.clone()));
+        let kept: Vec<&Def> = self
+            .defs
+            .iter()
+            .filter(|d| !d.names.iter().any(|n| names.contains(n)))
+            .collect();
+        let mut src: String = kept.iter().map(|d| format!("{}\n", d.text)).collect();
+        let start = src.len();
+        src.push_str(input);
+        check(&src, start)?;
+        self.defs
+            .retain(|d| !d.names.iter().any(|n| names.contains(n)));
+        for n in names.iter() {
+            self.values.remove(n);
+        }
+        self.defs.push(Def {
+            names,
+            text: input.to_owned(),
+        });
+        Ok(String::new())
+    }
+
+    //把表达式包进临时函数返回，和已有定义一起解析、检查。
+    //临时函数的返回类型就是表达式的类型
+    fn parse_expr(&self, expr: &str) -> Result<AST, String> {
+        if expr.is_empty() {
+            return Err("expected an expression".to_owned());
+        }
+        let ast = self.parse_wrapped("int", "return ", expr, "\n;")?;
+        let typ = match returned(&ast) {
+            Some(x) => expr_type(&ast, x)?,
+            None => return Err("expected an expression".to_owned()),
+        };
+        if typ == KeyWord::Float {
+            return self.parse_wrapped("float", "return ", expr, "\n;");
+        }
+        Ok(ast)
+    }
+
+    //赋值、if、while 等语句，放进临时函数执行
+    fn parse_stmt(&self, stmt: &str) -> Result<AST, String> {
+        self.parse_wrapped("int", "", stmt, "")
+    }
+
+    fn parse_wrapped(
+        &self,
+        typ: &str,
+        before: &str,
+        input: &str,
+        after: &str,
+    ) -> Result<AST, String> {
+        let mut src = self.source();
+        src.push_str(&format!("fn {} {}() {{\n{}", typ, EXPR_FN, before));
+        let start = src.len();
+        src.push_str(input);
+        src.push_str(after);
+        src.push_str("\n}\n");
+        check(&src, start)?;
+        Parser::new(DefaultLexer::new(src.as_bytes()))
+            .parse()
+            .map_err(|e| e.to_string())
+    }
+
+    fn source(&self) -> String {
+        self.defs.iter().map(|d| format!("{}\n", d.text)).collect()
+    }
+
+    //编译后在 vm 上执行临时函数。全局变量的值在两次输入之间保存在 values 里
+    fn evaluate(&mut self, ast: &AST) -> Result<Value, String> {
+        let p = compiler::compile(ast).map_err(|e| e.to_string())?;
+        let mut vm = VM::new(&p);
+        for (i, g) in p.globals.iter().enumerate() {
+            if let Some(v) = self.values.get(&g.name) {
+                vm.set_global(i, *v);
+            }
+        }
+        let value = vm
+            .call(p.func(EXPR_FN).unwrap(), &[])
+            .map_err(runtime_error)?;
+        for (i, g) in p.globals.iter().enumerate() {
+            self.values.insert(g.name.clone(), vm.global(i));
+        }
+        Ok(value)
+    }
+
+    fn bytecode(&self, name: &str) -> Result<String, String> {
+        let src = self.source();
+        let ast = Parser::new(DefaultLexer::new(src.as_bytes()))
+            .parse()
+            .map_err(|e| e.to_string())?;
+        let p = compiler::compile(&ast).map_err(|e| e.to_string())?;
+        match p.func(name) {
+            Some(f) => Ok(p.dump(f).trim_end().to_owned()),
+            None => Err(format!("`{}` is not a function", name)),
+        }
+    }
+}
+
+//行号是拼接后的源码里的，对用户没有意义，只列出函数名
+fn runtime_error(e: VmError) -> String {
+    let mut s = e.kind.to_string();
+    for frame in e.backtrace.iter().filter(|f| f.func != EXPR_FN) {
+        s.push_str(&format!("\n    at {}", frame.func));
+    }
+    s
+}
+
+//临时函数里 return 的表达式
+pub fn returned(ast: &AST) -> Option<&ExprNode> {
+    match ast.funcs.last()?.body.list.first() {
+        Some(StmtNode::ReturnStmt(r)) => r.x.as_ref(),
+        _ => None,
+    }
+}
+
+//以 if、while、{ 开头或者含有赋值的输入是语句
+fn is_stmt(input: &str) -> bool {
+    let mut lex = DefaultLexer::new(input.as_bytes());
+    let mut first = true;
+    while let Ok(tok) = lex.lex() {
+        match tok {
+            Token::KeyWord(KeyWord::If)
+            | Token::KeyWord(KeyWord::While)
+            | Token::Oper(Operator::LeftBrace)
+                if first =>
+            {
+                return true
+            }
+            Token::Oper(Operator::Assign) => return true,
+            _ => first = false,
+        }
+    }
+    false
+}
+
+//括号都闭合了，也没有没结束的注释或字符串
+fn complete(input: &str) -> bool {
+    let mut lex = DefaultLexer::new(input.as_bytes());
+    let mut depth = 0;
+    loop {
+        match lex.lex_with_trivia() {
+            Ok(l) => match l.tok {
+                Token::Eof => return depth <= 0,
+                Token::Oper(Operator::LeftBrace) | Token::Oper(Operator::LeftParen) => depth += 1,
+                Token::Oper(Operator::RightBrace) | Token::Oper(Operator::RightParen) => depth -= 1,
+                _ => (),
+            },
+            Err(LexerError::Unterminated(_)) => return false,
+            Err(_) => {
+                //其他词法错误留给解析时报告
+                if lex.recover().is_none() {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+//src 里 start 之后的部分是这次输入，只报告这部分的错误
+fn check(src: &str, start: usize) -> Result<(), String> {
+    let (tree, errors) = Parser::new(DefaultLexer::new(src.as_bytes())).parse_tree();
+    let mut msgs: Vec<String> = errors
+        .iter()
+        .filter(|e| e.offset >= start)
+        .map(|e| e.err.to_string())
+        .collect();
+    if msgs.is_empty() && errors.is_empty() {
+        msgs = semantic::analyze(&tree)
+            .diagnostics
+            .iter()
+            .filter(|d| d.range.start >= start)
+            .map(|d| d.message.clone())
+            .collect();
+    } else if msgs.is_empty() {
+        msgs.push(errors[0].err.to_string());
+    }
+    if msgs.is_empty() {
+        Ok(())
+    } else {
+        Err(msgs.join("\n"))
+    }
+}
+
+fn type_name(typ: KeyWord) -> &'static str {
+    match typ {
+        KeyWord::Float => "float",
+        _ => "int",
+    }
+}
+
+//全局变量的类型按声明，函数调用是返回类型，比较的结果是 int，
+//其他运算中有 float 时结果是 float
+pub fn expr_type(ast: &AST, x: &ExprNode) -> Result<KeyWord, String> {
+    let mut names: HashMap<&str, KeyWord> = HashMap::new();
+    for spec in ast.global.list.iter() {
+        for i in spec.names.iter() {
+            names.insert(i.name.as_str(), spec.typ);
+        }
+    }
+    for f in ast.funcs.iter() {
+        names.insert(f.fn_name.name.as_str(), f.typ);
+    }
+    infer(&names, x)
+}
+
+fn infer(names: &HashMap<&str, KeyWord>, x: &ExprNode) -> Result<KeyWord, String> {
+    match x {
+        ExprNode::IdentExpr(i) => match names.get(i.name.as_str()) {
+            Some(t) => Ok(*t),
+            None => Err(format!("undefined name `{}`", i.name)),
+        },
+        ExprNode::BasicLit(lit) => match lit.value {
+            Token::Float(_) => Ok(KeyWord::Float),
+            _ => Ok(KeyWord::Int),
+        },
+        ExprNode::CallExpr(c) => infer(names, &c.fun),
+        ExprNode::UnaryExpr(u) => match u.op {
+            Token::Oper(Operator::LogicNot) => Ok(KeyWord::Int),
+            _ => infer(names, &u.x),
+        },
+        ExprNode::ParenExpr(p) => infer(names, &p.x),
+        ExprNode::BinaryExpr(b) => {
+            let (x, y) = (infer(names, &b.x)?, infer(names, &b.y)?);
+            if b.op.level() == 1 {
+                Ok(KeyWord::Int)
+            } else if x == KeyWord::Float || y == KeyWord::Float {
+                Ok(KeyWord::Float)
+            } else {
+                Ok(KeyWord::Int)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(repl: &mut Repl, lines: &[&str]) -> Option<Result<String, String>> {
+        let mut out = None;
+        for l in lines.iter() {
+            out = repl.feed(l);
+        }
+        out
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.feed("var int a;"), Some(Ok(String::new())));
+        assert_eq!(repl.feed("var float b;"), Some(Ok(String::new())));
+        assert_eq!(repl.feed(":type a + b"), Some(Ok("float".to_owned())));
+        assert_eq!(repl.feed(":t (a)"), Some(Ok("int".to_owned())));
+        // 多行输入
+        assert_eq!(repl.feed("fn int f(int x) {"), None);
+        assert_eq!(repl.prompt(), ".. ");
+        let out = feed_all(&mut repl, &["  a = x;", "}"]);
+        assert_eq!(out, Some(Ok(String::new())));
+        assert_eq!(repl.prompt(), ">> ");
+        // 重新定义 a 覆盖之前的定义
+        repl.feed("var float a;").unwrap().unwrap();
+        assert_eq!(repl.feed(":type a"), Some(Ok("float".to_owned())));
+        assert_eq!(repl.defs.len(), 3);
+        assert_eq!(
+            repl.feed(":type c"),
+            Some(Err("undefined name `c`".to_owned()))
+        );
+        assert!(repl.feed("fn int g() { c = a; }").unwrap().is_err());
+        assert!(repl.feed(":ast -a").unwrap().unwrap().contains("UnaryExpr"));
+        assert!(repl.feed(":nope").unwrap().is_err());
+        // 在 vm 上求值，全局变量的值保留到之后的输入
+        repl.feed(":reset").unwrap().unwrap();
+        repl.feed("var int n;").unwrap().unwrap();
+        repl.feed("fn int sq(int x) { return x * x; }")
+            .unwrap()
+            .unwrap();
+        assert_eq!(repl.feed("n = 2 + 3;"), Some(Ok(String::new())));
+        assert_eq!(repl.feed("sq(n) - 1"), Some(Ok("24 : int".to_owned())));
+        assert_eq!(
+            repl.feed("while n > 0 { n = n - 1; }"),
+            Some(Ok(String::new()))
+        );
+        assert_eq!(repl.feed("n == 0"), Some(Ok("1 : int".to_owned())));
+        assert!(repl.feed(":bytecode sq").unwrap().unwrap().contains("Mul"));
+        repl.feed("var float h;").unwrap().unwrap();
+        repl.feed("h = 1 / 2.0;").unwrap().unwrap();
+        assert_eq!(repl.feed("h + 1"), Some(Ok("1.5 : float".to_owned())));
+        assert_eq!(repl.feed("(6 ^ 3) << 1"), Some(Ok("10 : int".to_owned())));
+        repl.feed(":reset").unwrap().unwrap();
+        assert_eq!(repl.feed(":defs"), Some(Ok(String::new())));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::compiler::is_builtin;
+use crate::cst::AstNode;
+use crate::cst::SyntaxElement;
+use crate::cst::SyntaxKind;
+use crate::cst::SyntaxNode;
+use crate::cst::{FnDecl, Param, VarDecl};
+use std::ops::Range;
+
+//名字解析。在 CST 上收集全局变量、函数、参数和局部变量的定义，
+//把表达式中的每个标识符解析到定义上，找不到或重复定义的报告诊断。
+//全部位置都是源码中的字节偏移。
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Global,
+    Func,
+    Param,
+    Local,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub typ: String,    //变量类型，函数为返回类型
+    pub detail: String, //声明的写法，例如 `fn int add(int a, int b)`
+    pub doc: Option<String>,
+    pub def: Range<usize>,     //定义处的标识符
+    pub scope: Range<usize>,   //可见范围
+    pub parent: Option<usize>, //参数和局部变量所属的函数
+}
+
+impl Symbol {
+    //全局变量和函数在文件顶层
+    pub fn is_top(&self) -> bool {
+        self.kind == SymbolKind::Global || self.kind == SymbolKind::Func
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub range: Range<usize>,
+    pub symbol: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub range: Range<usize>,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub symbols: Vec<Symbol>,
+    pub refs: Vec<Reference>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    //offset 处的标识符（定义或引用）对应的符号
+    pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+        let inside = |r: &Range<usize>| r.start <= offset && offset <= r.end;
+        if let Some(r) = self.refs.iter().find(|r| inside(&r.range)) {
+            return Some(r.symbol);
+        }
+        self.symbols.iter().position(|s| inside(&s.def))
+    }
+
+    pub fn references(&self, symbol: usize) -> Vec<Range<usize>> {
+        self.refs
+            .iter()
+            .filter(|r| r.symbol == symbol)
+            .map(|r| r.range.clone())
+            .collect()
+    }
+
+    //offset 处可以使用的符号，内层的同名符号遮住外层的
+    pub fn visible_at(&self, offset: usize) -> Vec<&Symbol> {
+        let mut list: Vec<&Symbol> = Vec::new();
+        for s in self.symbols.iter().rev() {
+            if s.scope.start <= offset
+                && offset <= s.scope.end
+                && !list.iter().any(|x| x.name == s.name)
+            {
+                list.push(s);
+            }
+        }
+        list.reverse();
+        list
+    }
+}
+
+pub fn analyze(root: &SyntaxNode) -> Analysis {
+    let mut a = Analysis::default();
+    let file = 0..root.len;
+    let items = root.children_with_offset(0);
+    let mut funcs = Vec::new();
+    //先收集顶层定义，函数体里可以引用后面才定义的全局名字
+    for (start, c) in items.iter() {
+        let node = match c {
+            SyntaxElement::Node(n) => n,
+            _ => continue,
+        };
+        if let Some(var) = VarDecl::cast(node) {
+            declare_vars(&mut a, var, *start, SymbolKind::Global, file.clone(), None);
+        } else if let Some(f) = FnDecl::cast(node) {
+            if let Some(def) = ident_range(node, *start) {
+                let name = f.name().unwrap_or("").to_owned();
+                define(
+                    &mut a,
+                    Symbol {
+                        detail: fn_detail(f),
+                        typ: f.ret_type().unwrap_or("?").to_owned(),
+                        doc: f.doc(),
+                        kind: SymbolKind::Func,
+                        name,
+                        def,
+                        scope: file.clone(),
+                        parent: None,
+                    },
+                );
+                funcs.push((*start, node, a.symbols.len() - 1));
+            }
+        }
+    }
+    for (start, node, sym) in funcs.into_iter() {
+        analyze_fn(&mut a, node, start, sym);
+    }
+    a
+}
+
+fn analyze_fn(a: &mut Analysis, node: &SyntaxNode, start: usize, sym: usize) {
+    let scope = start..start + node.len;
+    for (offset, c) in node.children_with_offset(start) {
+        let n = match c {
+            SyntaxElement::Node(n) => n,
+            _ => continue,
+        };
+        match n.kind {
+            SyntaxKind::ParamList => {
+                for (offset, p) in n.children_with_offset(offset) {
+                    if let SyntaxElement::Node(p) = p {
+                        if let (Some(param), Some(def)) = (Param::cast(p), ident_range(p, offset)) {
+                            let typ = param.type_name().unwrap_or("?");
+                            let name = param.name().unwrap_or("");
+                            define(
+                                a,
+                                Symbol {
+                                    name: name.to_owned(),
+                                    kind: SymbolKind::Param,
+                                    typ: typ.to_owned(),
+                                    detail: format!("{} {}", typ, name),
+                                    doc: None,
+                                    def,
+                                    scope: scope.clone(),
+                                    parent: Some(sym),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            SyntaxKind::Block => analyze_block(a, n, offset, offset + n.len, sym),
+            _ => (),
+        }
+    }
+}
+
+//按顺序遍历语句，局部变量从定义处到块结束可见
+fn analyze_block(a: &mut Analysis, node: &SyntaxNode, start: usize, end: usize, sym: usize) {
+    for (offset, c) in node.children_with_offset(start) {
+        let n = match c {
+            SyntaxElement::Node(n) => n,
+            _ => continue,
+        };
+        match n.kind {
+            SyntaxKind::VarDecl => {
+                let var = VarDecl::cast(n).unwrap();
+                declare_vars(a, var, offset, SymbolKind::Local, offset..end, Some(sym))
+            }
+            SyntaxKind::IdentExpr => {
+                if let Some(range) = ident_range(n, offset) {
+                    let name = n.child_token(SyntaxKind::Ident).unwrap().text.as_str();
+                    resolve(a, name, range);
+                }
+            }
+            SyntaxKind::Block => analyze_block(a, n, offset, offset + n.len, sym),
+            _ => analyze_block(a, n, offset, end, sym),
+        }
+    }
+}
+
+fn declare_vars(
+    a: &mut Analysis,
+    var: VarDecl,
+    start: usize,
+    kind: SymbolKind,
+    scope: Range<usize>,
+    parent: Option<usize>,
+) {
+    let typ = var.type_name().unwrap_or("?");
+    for (offset, c) in var.syntax().children_with_offset(start) {
+        if let SyntaxElement::Token(t) = c {
+            if t.kind == SyntaxKind::Ident {
+                define(
+                    a,
+                    Symbol {
+                        name: t.text.clone(),
+                        kind,
+                        typ: typ.to_owned(),
+                        detail: format!("var {} {}", typ, t.text),
+                        doc: var.doc(),
+                        def: offset..offset + t.text.len(),
+                        scope: scope.clone(),
+                        parent,
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn define(a: &mut Analysis, s: Symbol) {
+    //同一层作用域（可见范围在同一处结束）里不能重名
+    if a.symbols
+        .iter()
+        .any(|x| x.name == s.name && x.scope.end == s.scope.end && x.is_top() == s.is_top())
+    {
+        a.diagnostics.push(Diagnostic {
+            range: s.def.clone(),
+            message: format!("`{}` is defined more than once", s.name),
+        });
+    }
+    a.symbols.push(s);
+}
+
+fn resolve(a: &mut Analysis, name: &str, range: Range<usize>) {
+    let found = a
+        .symbols
+        .iter()
+        .rposition(|s| s.name == name && s.scope.start <= range.start && range.end <= s.scope.end);
+    match found {
+        Some(i) => a.refs.push(Reference { range, symbol: i }),
+        //内置函数没有定义
+        None if is_builtin(name) => (),
+        None => a.diagnostics.push(Diagnostic {
+            range,
+            message: format!("undefined name `{}`", name),
+        }),
+    }
+}
+
+fn ident_range(node: &SyntaxNode, start: usize) -> Option<Range<usize>> {
+    for (offset, c) in node.children_with_offset(start) {
+        if let SyntaxElement::Token(t) = c {
+            if t.kind == SyntaxKind::Ident {
+                return Some(offset..offset + t.text.len());
+            }
+        }
+    }
+    None
+}
+
+fn fn_detail(f: FnDecl) -> String {
+    let params: Vec<String> = f
+        .params()
+        .iter()
+        .map(|p| {
+            format!(
+                "{} {}",
+                p.type_name().unwrap_or("?"),
+                p.name().unwrap_or("")
+            )
+        })
+        .collect();
+    format!(
+        "fn {} {}({})",
+        f.ret_type().unwrap_or("?"),
+        f.name().unwrap_or(""),
+        params.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_analyze() {
+        let s = "var int a;\n/// 求和\nfn int f(int x, float y) {\n  var int a;\n  a = x + b;\n}\nfn int g() {\n  a = a;\n}\n";
+        let (tree, _) = Parser::new(DefaultLexer::new(s.as_bytes())).parse_tree();
+        let a = analyze(&tree);
+        let f = &a.symbols[a.symbols.iter().position(|s| s.name == "f").unwrap()];
+        assert_eq!(f.detail, "fn int f(int x, float y)");
+        assert_eq!(f.doc.as_deref(), Some("求和"));
+        // f 里面的 a 是局部变量，g 里面的 a 是全局变量
+        let in_f = s.find("a = x").unwrap();
+        let local = a.symbol_at(in_f).unwrap();
+        assert_eq!(a.symbols[local].kind, SymbolKind::Local);
+        let in_g = s.rfind("a = a").unwrap();
+        let global = a.symbol_at(in_g).unwrap();
+        assert_eq!(a.symbols[global].kind, SymbolKind::Global);
+        assert_eq!(a.references(global).len(), 2);
+        assert_eq!(a.diagnostics.len(), 1);
+        assert_eq!(a.diagnostics[0].message, "undefined name `b`");
+        let names: Vec<&str> = a.visible_at(in_f).iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["f", "g", "x", "y", "a"]);
+    }
+}
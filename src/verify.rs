@@ -0,0 +1,482 @@
+use crate::natives::NATIVES;
+use crate::vm::{Instruction, Program, STACK_SIZE};
+use std::collections::HashMap;
+use std::fmt;
+
+//执行前检查字节码，从文件读入或者手写的程序不能让虚拟机执行到意料之外的状态。
+//每个函数单独检查：
+//  指令都能解码，操作数没有超出函数的结尾；
+//  函数、全局变量、常量、字符串、本地函数和宿主函数的编号都在范围内；
+//  跳转目标是同一个函数里某条指令的开头；
+//  Ent 只出现在函数入口，它和 Adj 的字数不超过栈的大小，
+//  用到 bp 的 Lea 和 Lev 要求函数以 Ent 开头，
+//  Lea 只能访问参数和 Ent 分配的局部变量；
+//  沿着控制流计算每条指令执行前栈上压了几个字（不算局部变量），
+//  弹出的不能比压入的多，控制流汇合时深度要相同，执行不能越过函数的结尾；
+//  Call 和 Host 执行时栈上要有被调用函数的全部参数，
+//  Native 前面要有给出参数个数的 Imm，个数符合本地函数的声明，
+//  跳转不能直接落在 Native 上，否则会越过那条 Imm。
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    BadRange(usize, usize),        //函数的 entry 和 end 不在 text 里
+    BadOpcode(u64),                //不是合法的指令
+    MissingOperand,                //操作数超出了函数的结尾
+    BadOperand(&'static str, u64), //编号越界：编号的种类，编号
+    BadJump(u64),                  //跳转目标不是本函数中指令的开头
+    BadFrame(i64),                 //Lea 访问的不是参数或局部变量
+    Misplaced(Instruction),        //Ent 不在入口，或者没有 Ent 时用了 Lea、Lev
+    StackUnderflow(usize, usize),  //需要的字数，栈上的字数
+    StackMismatch(usize, usize),   //汇合处两条路径的栈深度
+    Arity(String, usize, usize),   //被调用的函数，需要的参数个数，栈上或 Imm 给出的个数
+    NoArgCount,                    //Native 前面不是 Imm
+    JumpToNative(u64),             //跳转目标是 Native，越过了给出参数个数的 Imm
+    FallsOff,                      //执行越过了函数的结尾
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyErrorKind::BadRange(entry, end) => {
+                write!(f, "code range {}..{} is outside the text", entry, end)
+            }
+            VerifyErrorKind::BadOpcode(n) => write!(f, "bad opcode {}", n),
+            VerifyErrorKind::MissingOperand => {
+                write!(f, "operand runs past the end of the function")
+            }
+            VerifyErrorKind::BadOperand(what, n) => write!(f, "{} {} out of range", what, n),
+            VerifyErrorKind::BadJump(n) => {
+                write!(
+                    f,
+                    "jump target {} is not an instruction of this function",
+                    n
+                )
+            }
+            VerifyErrorKind::BadFrame(n) => {
+                write!(f, "Lea {} is not a parameter or a local variable", n)
+            }
+            VerifyErrorKind::Misplaced(Instruction::Ent) => {
+                write!(f, "Ent is only allowed at the function entry")
+            }
+            VerifyErrorKind::Misplaced(op) => {
+                write!(
+                    f,
+                    "{:?} needs a frame but the function does not start with Ent",
+                    op
+                )
+            }
+            VerifyErrorKind::StackUnderflow(need, have) => write!(
+                f,
+                "stack underflow: needs {} word(s) but {} are pushed",
+                need, have
+            ),
+            VerifyErrorKind::StackMismatch(a, b) => write!(
+                f,
+                "stack depth differs where control flow merges: {} and {}",
+                a, b
+            ),
+            VerifyErrorKind::Arity(name, want, got) => write!(
+                f,
+                "`{}` takes {} argument(s) but {} are passed",
+                name, want, got
+            ),
+            VerifyErrorKind::NoArgCount => {
+                write!(f, "Native must follow an Imm with the argument count")
+            }
+            VerifyErrorKind::JumpToNative(n) => write!(
+                f,
+                "jump target {} is a Native and skips its argument count",
+                n
+            ),
+            VerifyErrorKind::FallsOff => write!(f, "execution runs past the end of the function"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub func: String,
+    pub pc: usize, //出错的指令在 text 中的下标
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in `{}` at {}: {}", self.func, self.pc, self.kind)
+    }
+}
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+pub fn verify(p: &Program) -> VerifyResult<()> {
+    for f in 0..p.funcs.len() {
+        Verifier::new(p, f).run()?;
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    p: &'a Program,
+    f: usize,
+    code: Vec<(usize, Instruction, u64)>, //指令的位置、指令、操作数
+    at: HashMap<usize, usize>,            //指令的位置在 code 中的下标
+}
+
+impl<'a> Verifier<'a> {
+    fn new(p: &'a Program, f: usize) -> Verifier<'a> {
+        Verifier {
+            p,
+            f,
+            code: Vec::new(),
+            at: HashMap::new(),
+        }
+    }
+
+    fn error(&self, pc: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            func: self.p.funcs[self.f].name.clone(),
+            pc,
+            kind,
+        }
+    }
+
+    fn run(&mut self) -> VerifyResult<()> {
+        self.decode()?;
+        self.check_operands()?;
+        self.check_stack()
+    }
+
+    //逐条解码，记下每条指令的开头
+    fn decode(&mut self) -> VerifyResult<()> {
+        let func = &self.p.funcs[self.f];
+        if func.entry > func.end || func.end > self.p.text.len() {
+            let kind = VerifyErrorKind::BadRange(func.entry, func.end);
+            return Err(self.error(func.entry, kind));
+        }
+        let mut pc = func.entry;
+        while pc < func.end {
+            let word = self.p.text[pc];
+            let op = match Instruction::decode(word) {
+                Some(op) => op,
+                None => return Err(self.error(pc, VerifyErrorKind::BadOpcode(word))),
+            };
+            let operand = match op.operands() {
+                0 => 0,
+                _ if pc + 1 < func.end => self.p.text[pc + 1],
+                _ => return Err(self.error(pc, VerifyErrorKind::MissingOperand)),
+            };
+            self.at.insert(pc, self.code.len());
+            self.code.push((pc, op, operand));
+            pc += 1 + op.operands();
+        }
+        Ok(())
+    }
+
+    fn check_operands(&self) -> VerifyResult<()> {
+        let p = self.p;
+        let func = &p.funcs[self.f];
+        let frame = match self.code.first() {
+            Some((_, Instruction::Ent, n)) => Some(*n as i64),
+            _ => None,
+        };
+        for (i, (pc, op, n)) in self.code.iter().enumerate() {
+            let range = |what: &'static str, len: usize| {
+                if *n >= len as u64 {
+                    Err(self.error(*pc, VerifyErrorKind::BadOperand(what, *n)))
+                } else {
+                    Ok(())
+                }
+            };
+            match op {
+                Instruction::Call => range("function", p.funcs.len())?,
+                Instruction::Glo => range("global", p.globals.len())?,
+                Instruction::Const => range("constant", p.consts.len())?,
+                Instruction::Str => range("string offset", p.data.len())?,
+                Instruction::Host => range("host function", p.hosts.len())?,
+                Instruction::Native => {
+                    range("native function", NATIVES.len())?;
+                    let native = &NATIVES[*n as usize];
+                    let argc = match i.checked_sub(1).map(|k| self.code[k]) {
+                        Some((_, Instruction::Imm, argc)) => argc as usize,
+                        _ => return Err(self.error(*pc, VerifyErrorKind::NoArgCount)),
+                    };
+                    let want = native.params.len();
+                    if argc < want || (argc > want && !native.variadic) {
+                        let kind = VerifyErrorKind::Arity(native.name.to_owned(), want, argc);
+                        return Err(self.error(*pc, kind));
+                    }
+                }
+                Instruction::Jmp | Instruction::Jz | Instruction::Jnz
+                    if !self.at.contains_key(&(*n as usize)) =>
+                {
+                    return Err(self.error(*pc, VerifyErrorKind::BadJump(*n)));
+                }
+                Instruction::Jmp | Instruction::Jz | Instruction::Jnz
+                    if self.code[self.at[&(*n as usize)]].1 == Instruction::Native =>
+                {
+                    return Err(self.error(*pc, VerifyErrorKind::JumpToNative(*n)));
+                }
+                Instruction::Ent if i > 0 => {
+                    return Err(self.error(*pc, VerifyErrorKind::Misplaced(*op)))
+                }
+                //先限制字数，后面按 i64 计算栈深度和帧的范围时不会溢出
+                Instruction::Ent | Instruction::Adj => range("word count", STACK_SIZE + 1)?,
+                Instruction::Lea | Instruction::Lev if frame.is_none() => {
+                    return Err(self.error(*pc, VerifyErrorKind::Misplaced(*op)))
+                }
+                Instruction::Lea => {
+                    //参数在 bp + 2 到 bp + 1 + 参数个数，局部变量在 bp - 1 到 bp - Ent 的操作数
+                    let k = *n as i64;
+                    let params = func.params.len() as i64;
+                    let param = k >= 2 && k <= 1 + params;
+                    let local = k <= -1 && k >= -frame.unwrap();
+                    if !param && !local {
+                        return Err(self.error(*pc, VerifyErrorKind::BadFrame(k)));
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    //指令执行前栈上至少要有的字数，和执行后栈深度的变化
+    fn effect(&self, op: Instruction, n: u64, i: usize) -> (usize, i64, Option<String>) {
+        match op {
+            Instruction::Push => (0, 1, None),
+            //n 不超过 STACK_SIZE，check_operands 已经检查过
+            Instruction::Adj => (n as usize, -(n as i64), None),
+            Instruction::HSet => (2, -2, None),
+            Instruction::Si
+            | Instruction::Sc
+            | Instruction::Or
+            | Instruction::Xor
+            | Instruction::And
+            | Instruction::Eq
+            | Instruction::Ne
+            | Instruction::Lt
+            | Instruction::Gt
+            | Instruction::Le
+            | Instruction::Ge
+            | Instruction::Shl
+            | Instruction::Shr
+            | Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::FAdd
+            | Instruction::FSub
+            | Instruction::FMul
+            | Instruction::FDiv
+            | Instruction::FMod
+            | Instruction::FEq
+            | Instruction::FNe
+            | Instruction::FLt
+            | Instruction::FGt
+            | Instruction::FLe
+            | Instruction::FGe
+            | Instruction::HGet => (1, -1, None),
+            //参数留在栈上，由后面的 Adj 弹出
+            Instruction::Call => {
+                let f = &self.p.funcs[n as usize];
+                (f.params.len(), 0, Some(f.name.clone()))
+            }
+            Instruction::Host => {
+                let h = &self.p.hosts[n as usize];
+                (h.params.len(), 0, Some(h.name.clone()))
+            }
+            Instruction::Native => {
+                let argc = self.code[i - 1].2 as usize;
+                (argc, 0, Some(NATIVES[n as usize].name.to_owned()))
+            }
+            _ => (0, 0, None),
+        }
+    }
+
+    //沿控制流传播栈深度
+    fn check_stack(&self) -> VerifyResult<()> {
+        let end = self.p.funcs[self.f].end;
+        let mut depth: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut work = Vec::new();
+        if !self.code.is_empty() {
+            depth[0] = Some(0);
+            work.push(0);
+        }
+        while let Some(i) = work.pop() {
+            let (pc, op, n) = self.code[i];
+            let d = depth[i].unwrap();
+            let (need, delta, callee) = self.effect(op, n, i);
+            if d < need {
+                let kind = match callee {
+                    Some(name) => VerifyErrorKind::Arity(name, need, d),
+                    None => VerifyErrorKind::StackUnderflow(need, d),
+                };
+                return Err(self.error(pc, kind));
+            }
+            let after = (d as i64 + delta) as usize;
+            let next = pc + 1 + op.operands();
+            let succs: Vec<usize> = match op {
+                Instruction::Lev | Instruction::Exit => vec![],
+                Instruction::Jmp => vec![n as usize],
+                Instruction::Jz | Instruction::Jnz => vec![next, n as usize],
+                _ => vec![next],
+            };
+            for s in succs {
+                if s == end {
+                    return Err(self.error(pc, VerifyErrorKind::FallsOff));
+                }
+                let k = self.at[&s];
+                match depth[k] {
+                    Some(d) if d != after => {
+                        let kind = VerifyErrorKind::StackMismatch(d, after);
+                        return Err(self.error(s, kind));
+                    }
+                    Some(_) => (),
+                    None => {
+                        depth[k] = Some(after);
+                        work.push(k);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::compiler::compile;
+    use crate::lexer::DefaultLexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_verify_compiled() {
+        let s = r#"var int count;
+var float scale;
+
+fn int fib(int n) {
+    count = count + 1;
+    if n < 2 { return n; }
+    return fib(n - 1) + fib(n - 2);
+}
+
+fn float area(int w, float h) {
+    var float a;
+    a = w * h * scale;
+    if w > 0 { var int i; i = 2; a = a * i; }
+    return a;
+}
+
+fn int main() {
+    var int i, a;
+    a = array(10);
+    while i < len(a) {
+        if !(i > 20) { set(a, i, get(a, i) + fib(i) | i); }
+        i = i + 1;
+    }
+    printf("%d %s %f\n", i, "x", area(2, 3.5));
+    if i { trap(1); }
+}"#;
+        let ast = Parser::new(DefaultLexer::new(s.as_bytes()))
+            .parse()
+            .unwrap();
+        assert_eq!(verify(&compile(&ast).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_verify() {
+        let err = |s: &str| {
+            let src = format!(
+                ".fn int f(int)\n{}\n.end\n.fn int g(int, int)\nEnt 0\nLev\n.end",
+                s
+            );
+            verify(&assemble(&src).unwrap()).unwrap_err()
+        };
+        let kind = |s: &str| err(s).kind;
+        assert_eq!(
+            err("Ent 0\nImm 1\n.word 99\nLev"),
+            VerifyError {
+                func: "f".to_owned(),
+                pc: 4,
+                kind: VerifyErrorKind::BadOpcode(99),
+            }
+        );
+        assert_eq!(kind("Ent 0\n.word 2"), VerifyErrorKind::MissingOperand);
+        assert_eq!(
+            kind("Ent 0\nGlo #0\nLev"),
+            VerifyErrorKind::BadOperand("global", 0)
+        );
+        assert_eq!(
+            kind("Ent 0\nCall #7\nLev"),
+            VerifyErrorKind::BadOperand("function", 7)
+        );
+        //跳到 Imm 的操作数上
+        assert_eq!(
+            kind("Ent 0\nJmp #3\nImm 5\nLev"),
+            VerifyErrorKind::BadJump(3)
+        );
+        assert_eq!(kind("Ent 1\nLea 3\nLev"), VerifyErrorKind::BadFrame(3));
+        assert_eq!(kind("Ent 1\nLea -2\nLev"), VerifyErrorKind::BadFrame(-2));
+        assert_eq!(kind("Ent 1\nLea 1\nLev"), VerifyErrorKind::BadFrame(1));
+        assert_eq!(
+            kind("Ent -9223372036854775808\nLev"),
+            VerifyErrorKind::BadOperand("word count", 1 << 63)
+        );
+        assert_eq!(
+            kind("Ent 0\nAdj -9223372036854775808\nLev"),
+            VerifyErrorKind::BadOperand("word count", 1 << 63)
+        );
+        assert_eq!(
+            kind("Imm 1\nLev"),
+            VerifyErrorKind::Misplaced(Instruction::Lev)
+        );
+        assert_eq!(
+            kind("Ent 0\nEnt 0\nLev"),
+            VerifyErrorKind::Misplaced(Instruction::Ent)
+        );
+        assert_eq!(
+            kind("Ent 0\nImm 1\nAdd\nLev"),
+            VerifyErrorKind::StackUnderflow(1, 0)
+        );
+        assert_eq!(
+            kind("Ent 0\nPush\nAdj 2\nLev"),
+            VerifyErrorKind::StackUnderflow(2, 1)
+        );
+        //一条路径多压了一个字
+        assert_eq!(
+            err("Ent 0\nLea 2\nLi\nJz L\nPush\nL:\nLev"),
+            VerifyError {
+                func: "f".to_owned(),
+                pc: 8,
+                kind: VerifyErrorKind::StackMismatch(0, 1),
+            }
+        );
+        assert_eq!(
+            kind("Ent 0\nImm 1\nPush\nCall g\nAdj 1\nLev"),
+            VerifyErrorKind::Arity("g".to_owned(), 2, 1)
+        );
+        assert_eq!(
+            kind("Ent 0\nImm 1\nPush\nImm 1\nNative memset\nAdj 1\nLev"),
+            VerifyErrorKind::Arity("memset".to_owned(), 3, 1)
+        );
+        assert_eq!(
+            kind("Ent 0\nPush\nNative free\nLev"),
+            VerifyErrorKind::NoArgCount
+        );
+        //跳过了 Imm 1，执行时 Native 会用 ax 里的 0 作参数个数
+        assert_eq!(
+            kind("Ent 0\nPush\nImm 0\nJmp L\nImm 1\nL:\nNative printf\nAdj 1\nLev"),
+            VerifyErrorKind::JumpToNative(9)
+        );
+        assert_eq!(kind("Ent 0\nImm 1"), VerifyErrorKind::FallsOff);
+        //循环里深度不变是可以的，Exit 之后不需要再有指令
+        let ok = "Ent 1\nL:\nLea -1\nPush\nLea -1\nLi\nPush\nImm 1\nAdd\nSi\nLea -1\nLi\nJnz L\nImm 1\nPush\nCall f\nAdj 1\nExit";
+        assert_eq!(
+            verify(&assemble(&format!(".fn int f(int)\n{}\n.end", ok)).unwrap()),
+            Ok(())
+        );
+    }
+}
@@ -1,108 +1,1524 @@
-use std::alloc::{self, Layout};
-use std::mem;
-use std::slice;
+use crate::asm;
+use crate::gc::{self, Heap, Stats};
+use crate::lexer::KeyWord;
+use crate::natives::{HostFn, Io, Signature, NATIVES};
+use crate::profile::Profile;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 #[repr(u64)]
-#[derive(Debug)]
-enum Instruction {
-    Lea = 1,
-    Imm,
-    // JMP,
-    // CALL,
-    // JZ,
-    // JNZ,
-    // ENT,
-    // ADJ,
-    // LEV,
-    // LI,
-    // LC,
-    // SI,
-    // SC,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Lea = 1, //ax = bp + n，参数和局部变量的地址
+    Imm,     //ax = n
+    Jmp,     //pc = text + n
+    Call,    //调用函数表中的第 n 个函数，返回地址压栈
+    Jz,      //ax 为 0 时跳转
+    Jnz,     //ax 不为 0 时跳转
+    Ent,     //进入函数：保存 bp，分配 n 个局部变量
+    Adj,     //调用返回后弹出 n 个参数
+    Lev,     //离开函数：恢复 bp 和 pc
+    Li,      //ax = *ax
+    Lc,      //ax = *(char *)ax
+    Si,      //*pop() = ax
+    Sc,      //*(char *)pop() = ax
     Push,
-    // OR,
-    // XOR,
-    // AND,
-    // EQ,
-    // NE,
-    // LT,
-    // GT,
-    // LE,
-    // GE,
-    // SHL,
-    // SHR,
+    Or,
+    Xor,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Shl,
+    Shr, //算术右移
     Add,
-    // SUB,
-    // MUL,
-    // DIV,
-    // MOD,
-    // OPEN,
-    // READ,
-    // CLOS,
-    // PRTF,
-    // MALC,
-    // MSET,
-    // MCMP,
+    Sub,
+    Mul,
+    Div,
+    Mod,
     Exit,
+    Const, //ax = consts[n]，浮点常量。整数常量用 Imm
+    Glo,   //ax = data + n，全局变量的地址
+    //浮点运算，操作数和结果都是 f64 的位模式，比较的结果是整数 0 或 1
+    FAdd,
+    FSub,
+    FMul,
+    FDiv,
+    FMod,
+    FEq,
+    FNe,
+    FLt,
+    FGt,
+    FLe,
+    FGe,
+    Itof, //ax = ax as f64
+    Ftoi, //ax = ax as i64，向零取整
+    Trap, //以错误码 ax 中止执行
+    //堆上的数组，下标和元素都是整数
+    New,    //ax = 新分配的 ax 个字的数组，可能先触发回收
+    HGet,   //ax = pop()[ax]
+    HSet,   //i = pop(); pop()[i] = ax
+    HLen,   //ax = ax 的长度
+    Str,    //ax = 字符串常量的地址，n 是在 Program::data 中的偏移
+    Native, //调用 natives::NATIVES 中的第 n 个函数，ax 是压栈的参数个数
+    Host,   //调用 Program::hosts 中的第 n 个宿主函数，参数个数由签名决定
 }
-//虚拟机 模拟计算机
-struct VM {
-    pc: *mut u64, //程序计数器，它存放的是一个内存地址，该地址中存放着 下一条 要执行的计算机指令
-    sp: *mut u64, //指针寄存器，永远指向当前的栈顶。注意的是由于栈是位于高地址并向低地址增长的，所以入栈时 SP 的值减小
-    bp: *mut u64, //基址指针。也是用于指向栈的某些位置，在调用函数时会使用到它
-    ax: u64,      //通用寄存器，我们的虚拟机中，它用于存放一条指令执行后的结果
-    text: *mut u64, //代码段
-    old_text: *mut u64, //
-    stack: *mut u64, //用于处理函数调用相关的数据，如调用帧（calling frame）或是函数的局部变量等
-    data: *mut u64, //数据段 用于存放初始化了的数据，如int i = 10;，就需要存放到数据段中
+
+//按编码排列的全部指令，Lea 是 1
+pub const INSTRUCTIONS: &[Instruction] = &[
+    Instruction::Lea,
+    Instruction::Imm,
+    Instruction::Jmp,
+    Instruction::Call,
+    Instruction::Jz,
+    Instruction::Jnz,
+    Instruction::Ent,
+    Instruction::Adj,
+    Instruction::Lev,
+    Instruction::Li,
+    Instruction::Lc,
+    Instruction::Si,
+    Instruction::Sc,
+    Instruction::Push,
+    Instruction::Or,
+    Instruction::Xor,
+    Instruction::And,
+    Instruction::Eq,
+    Instruction::Ne,
+    Instruction::Lt,
+    Instruction::Gt,
+    Instruction::Le,
+    Instruction::Ge,
+    Instruction::Shl,
+    Instruction::Shr,
+    Instruction::Add,
+    Instruction::Sub,
+    Instruction::Mul,
+    Instruction::Div,
+    Instruction::Mod,
+    Instruction::Exit,
+    Instruction::Const,
+    Instruction::Glo,
+    Instruction::FAdd,
+    Instruction::FSub,
+    Instruction::FMul,
+    Instruction::FDiv,
+    Instruction::FMod,
+    Instruction::FEq,
+    Instruction::FNe,
+    Instruction::FLt,
+    Instruction::FGt,
+    Instruction::FLe,
+    Instruction::FGe,
+    Instruction::Itof,
+    Instruction::Ftoi,
+    Instruction::Trap,
+    Instruction::New,
+    Instruction::HGet,
+    Instruction::HSet,
+    Instruction::HLen,
+    Instruction::Str,
+    Instruction::Native,
+    Instruction::Host,
+];
+
+impl Instruction {
+    //从指令流中的一个字解码，不是合法的指令时返回 None
+    pub fn decode(n: u64) -> Option<Instruction> {
+        if n == 0 {
+            return None;
+        }
+        INSTRUCTIONS.get(n as usize - 1).copied()
+    }
+
+    //按名字找指令，名字和 {:?} 显示的一样
+    pub fn from_name(name: &str) -> Option<Instruction> {
+        INSTRUCTIONS
+            .iter()
+            .find(|op| format!("{:?}", op) == name)
+            .copied()
+    }
+
+    //指令后面跟着的操作数个数
+    pub fn operands(&self) -> usize {
+        match self {
+            Instruction::Lea
+            | Instruction::Imm
+            | Instruction::Jmp
+            | Instruction::Call
+            | Instruction::Jz
+            | Instruction::Jnz
+            | Instruction::Ent
+            | Instruction::Adj
+            | Instruction::Const
+            | Instruction::Glo
+            | Instruction::Str
+            | Instruction::Native
+            | Instruction::Host => 1,
+            _ => 0,
+        }
+    }
+}
+
+//编译好的程序。text 是指令流，每条指令占一个字，操作数紧跟在后面，
+//跳转目标和函数入口都是 text 中的下标。text[0] 固定是 Exit，
+//从外部调用的函数返回到这里结束执行。
+#[derive(Debug, Default)]
+pub struct Program {
+    pub text: Vec<u64>,
+    pub consts: Vec<u64>, //常量池
+    pub funcs: Vec<Function>,
+    pub globals: Vec<Global>,
+    pub lines: Vec<(usize, u32)>, //调试信息：从 text 的这个下标开始的指令属于源码的哪一行
+    pub data: Vec<u8>,            //以 0 结尾的字符串常量，运行时放在全局变量后面
+    pub hosts: Vec<Signature>,    //用到的宿主函数，运行前用 VM::bind 提供实现
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    pub end: usize,
+    pub params: Vec<KeyWord>, //参数类型
+    pub ret: KeyWord,
+    pub locals: Vec<Local>, //调试信息：参数和局部变量
+}
+
+//参数或局部变量的名字和位置，调试器按名字读取它的值
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub typ: KeyWord,
+    pub slot: i64,    //相对 bp 的偏移，按字计
+    pub start: usize, //作用域是 text[start..end]
+    pub end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub name: String,
+    pub typ: KeyWord,
+}
+
+impl Program {
+    pub fn func(&self, name: &str) -> Option<usize> {
+        self.funcs.iter().position(|f| f.name == name)
+    }
+
+    pub fn global(&self, name: &str) -> Option<usize> {
+        self.globals.iter().position(|g| g.name == name)
+    }
+
+    //text[pc] 处的指令对应的源码行
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        line_at(&self.lines, pc)
+    }
+
+    //函数 f 的指令清单，格式见 asm
+    pub fn dump(&self, f: usize) -> String {
+        asm::disassemble_fn(self, f)
+    }
+}
+
+pub const STACK_SIZE: usize = 64 * 1024;
+
+//虚拟机里的值。内存和寄存器中只存 64 位的位模式，类型由指令决定：
+//整数指令把它当作 i64，F 开头的指令当作 f64 的位模式，Li/Si 把它当作字节地址。
+//调用返回的值按函数声明的返回类型解释。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool), //比较的结果，参与运算时是 0 或 1
+    Ptr(u64),   //mem 中的字节地址
+    Ref(u32),   //堆上对象的编号
+}
+
+//值的类型标记，debug 构建下用来检查每条指令的操作数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tag {
+    Int,
+    Float,
+    Bool,
+    Ptr,
+    Ref,
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Tag::Int => "int",
+            Tag::Float => "float",
+            Tag::Bool => "bool",
+            Tag::Ptr => "pointer",
+            Tag::Ref => "reference",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Value {
+    pub fn from_bits(bits: u64, typ: KeyWord) -> Value {
+        match typ {
+            KeyWord::Float => Value::Float(f64::from_bits(bits)),
+            _ => Value::Int(bits as i64),
+        }
+    }
+
+    //按类型标记解释位模式
+    pub fn from_tag(bits: u64, tag: Tag) -> Value {
+        match tag {
+            Tag::Int => Value::Int(bits as i64),
+            Tag::Float => Value::Float(f64::from_bits(bits)),
+            Tag::Bool => Value::Bool(bits != 0),
+            Tag::Ptr => Value::Ptr(bits),
+            Tag::Ref => Value::Ref(bits as u32),
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        match self {
+            Value::Int(_) => Tag::Int,
+            Value::Float(_) => Tag::Float,
+            Value::Bool(_) => Tag::Bool,
+            Value::Ptr(_) => Tag::Ptr,
+            Value::Ref(_) => Tag::Ref,
+        }
+    }
+
+    //原样的位模式
+    pub fn bits(self) -> u64 {
+        match self {
+            Value::Int(n) => n as u64,
+            Value::Float(n) => n.to_bits(),
+            Value::Bool(b) => b as u64,
+            Value::Ptr(a) => a,
+            Value::Ref(r) => gc::to_ref(r),
+        }
+    }
+
+    //转成 typ 类型后的位模式
+    pub fn to_bits(self, typ: KeyWord) -> u64 {
+        match (self, typ) {
+            (Value::Float(n), KeyWord::Float) => n.to_bits(),
+            (Value::Float(n), _) => n as i64 as u64,
+            (v, KeyWord::Float) => (v.bits() as i64 as f64).to_bits(),
+            (v, _) => v.bits(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Ptr(a) => write!(f, "{:#x}", a),
+            Value::Ref(r) => write!(f, "ref {}", r),
+        }
+    }
+}
+
+//数组里没有记录类型，看起来像引用的就是引用
+fn element_tag(v: u64) -> Tag {
+    match gc::from_ref(v) {
+        Some(_) => Tag::Ref,
+        None => Tag::Int,
+    }
+}
+
+//tars 类型对应的标记
+pub fn tag_of(typ: KeyWord) -> Tag {
+    match typ {
+        KeyWord::Float => Tag::Float,
+        _ => Tag::Int,
+    }
+}
+
+//整数指令接受的操作数，引用也可以比较和判断
+const INTEGERS: &[Tag] = &[Tag::Int, Tag::Bool, Tag::Ptr, Tag::Ref];
+const REFS: &[Tag] = &[Tag::Ref];
+const FLOATS: &[Tag] = &[Tag::Float];
+const POINTERS: &[Tag] = &[Tag::Ptr];
+
+//debug 构建下给每个值带上类型标记并检查，release 构建不做任何额外工作
+const CHECK: bool = cfg!(debug_assertions);
+
+//运行时错误的种类。原来会越界读写内存或者执行垃圾数据的情况都变成错误返回
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    DivByZero,
+    StackOverflow,
+    StackUnderflow,
+    InvalidMemory(u64), //越界、没有对齐或者空的地址
+    BadOpcode(u64),
+    BadOperand(u64),         //跳转目标、函数、常量或者全局变量的编号越界
+    Trap(i64),               //程序调用 trap(code) 主动中止
+    Arity(usize, usize),     //从外部调用或调用本地函数时参数个数不对：需要的个数，传入的个数
+    Type(Tag, Tag),          //debug 构建下操作数类型不对：需要的类型，实际的类型
+    CallDepth(usize),        //调用层数超过上限，通常是无限递归
+    BadRef(u64),             //不是堆上存活对象的引用
+    OutOfBounds(i64, usize), //下标，数组长度
+    NegativeSize(i64),
+    Native(String, String), //本地函数或宿主函数报告的错误：函数名，原因
+    Exit(i64),              //程序调用了 exit，call 把它当作正常的返回值
+    Stopped,                //调试器的钩子要求中止
+    //超过了 Limits 的限制。前两种可以用 resume 从停下的地方继续
+    OutOfFuel,
+    Deadline,
+    MemoryLimit(usize), //允许的字节数
+    Forbidden(String),  //不在允许列表里的本地函数
+    NotSuspended,       //resume 时没有停下的调用
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::DivByZero => write!(f, "division by zero"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::InvalidMemory(a) => write!(f, "invalid memory access at {:#x}", a),
+            ErrorKind::BadOpcode(n) => write!(f, "bad opcode {}", n),
+            ErrorKind::BadOperand(n) => write!(f, "bad operand {}", n),
+            ErrorKind::Trap(n) => write!(f, "trap {}", n),
+            ErrorKind::Arity(want, got) => {
+                write!(f, "expected {} argument(s) but {} were given", want, got)
+            }
+            ErrorKind::CallDepth(n) => write!(f, "maximum call depth {} exceeded", n),
+            ErrorKind::BadRef(r) => write!(f, "invalid heap reference {:#x}", r),
+            ErrorKind::OutOfBounds(i, len) => {
+                write!(f, "index {} out of bounds for length {}", i, len)
+            }
+            ErrorKind::NegativeSize(n) => write!(f, "negative size {}", n),
+            ErrorKind::Native(name, msg) => write!(f, "{}: {}", name, msg),
+            ErrorKind::Exit(code) => write!(f, "exit {}", code),
+            ErrorKind::Stopped => write!(f, "stopped by the debugger"),
+            ErrorKind::OutOfFuel => write!(f, "out of fuel"),
+            ErrorKind::Deadline => write!(f, "deadline exceeded"),
+            ErrorKind::MemoryLimit(n) => write!(f, "memory limit of {} bytes exceeded", n),
+            ErrorKind::Forbidden(name) => write!(f, "native function `{}` is not allowed", name),
+            ErrorKind::NotSuspended => write!(f, "no suspended call to resume"),
+            ErrorKind::Type(want, got) => {
+                write!(f, "type error: expected {} but found {}", want, got)
+            }
+        }
+    }
 }
 
+//调用栈中的一层，出错的函数在最前面
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub func: String,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} (line {})", self.func, line),
+            None => write!(f, "{}", self.func),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub kind: ErrorKind,
+    pub backtrace: Vec<Frame>,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for frame in self.backtrace.iter() {
+            write!(f, "\n    at {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl VmError {
+    //燃料用完或者超时，补充之后可以 resume
+    pub fn is_resumable(&self) -> bool {
+        self.kind == ErrorKind::OutOfFuel || self.kind == ErrorKind::Deadline
+    }
+}
+
+pub type VmResult<T> = Result<T, VmError>;
+
+//执行中的错误先只记种类，回到 call 时再补上调用栈
+type Step<T> = Result<T, ErrorKind>;
+
+//调用栈最多列出的层数，无限递归时不至于太长
+pub const MAX_BACKTRACE: usize = 32;
+
+//默认的最大调用层数，可以用 set_max_depth 修改
+pub const MAX_DEPTH: usize = 10000;
+
+//运行不受信任的脚本时的限制，None 表示不限制。
+//fuel 和 deadline 是每次 call 或 resume 能用的指令数和时间，
+//memory 是 malloc 的内存和堆上的数组一共能占用的字节数，不含固定大小的栈，
+//natives 是允许调用的本地函数的名字。宿主函数由嵌入的程序提供，不受限制
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub deadline: Option<Duration>,
+    pub memory: Option<usize>,
+    pub natives: Option<Vec<String>>,
+}
+
+//每执行这么多条指令看一次时间
+const DEADLINE_CHECK: u32 = 1024;
+
+//一次 malloc 或数组分配最多的字节数，没有设置 memory 限制时也生效，
+//免得脚本要求的大小让宿主分配失败而中止
+pub const MAX_ALLOC: usize = 1 << 30;
+
+//调试器的钩子，执行到每条语句的开头时调用，返回 false 时中止执行。
+//函数入口的 Ent 不算语句，这时调用帧还没建好
+pub trait Hook {
+    fn statement(&mut self, vm: &VM) -> bool;
+}
+
+//虚拟机 模拟计算机。数据段和栈放在同一块按字存放的内存 mem 里：
+//mem[0] 空着当作空指针，之后是全局变量、字符串常量和栈，
+//栈之后是 malloc 分配的内存，需要时往后扩展 mem。
+//程序里的地址按字节计，Li/Si 要求按 8 字节对齐，Lc/Sc 读写其中一个字节。
+pub struct VM {
+    pc: usize,        //程序计数器，下一条要执行的指令在 text 中的下标
+    sp: usize,        //栈顶在 mem 中的下标。栈位于高地址并向低地址增长，入栈时 sp 减小
+    bp: usize,        //基址指针，当前函数调用帧的位置，参数和局部变量按它的偏移访问
+    ax: u64,          //通用寄存器，存放一条指令执行后的结果
+    tag: Option<Tag>, //ax 的类型，None 表示不知道，比如没有初始化的内存
+    at: usize,        //正在执行的指令的位置
+    text: Vec<u64>,   //代码段
+    mem: Vec<u64>,
+    tags: Vec<Option<Tag>>, //mem 中每个字的类型，只在 debug 构建下使用
+    stack: usize,           //栈底，也就是栈能用到的最低的下标
+    top: usize,             //栈顶的边界，空栈时 sp 等于它
+    data: u64,              //字符串常量的起始地址
+    consts: Vec<u64>,
+    funcs: Vec<Function>, //函数表
+    lines: Vec<(usize, u32)>,
+    globals: usize,
+    depth: usize, //正在执行的函数的层数，Ent 加一，Lev 减一
+    max_depth: usize,
+    heap: Heap,
+    allocs: HashMap<usize, usize>, //malloc 分配的块：起始下标，字数
+    free: Vec<(usize, usize)>,     //free 之后可以重新分配的块
+    io: Io,
+    hosts: Vec<Signature>,
+    host_fns: Vec<Option<HostFn>>, //和 hosts 一一对应，没有绑定的是 None
+    hook: Option<Box<dyn Hook>>,
+    stmts: Vec<bool>, //text 中每个位置是不是语句的开头，设置了钩子才有
+    profile: Option<Profile>,
+    limits: Limits,
+    fuel: Option<u64>,          //这次执行还能用的指令数
+    deadline: Option<Instant>,  //这次执行的截止时间
+    ticks: u32,                 //到下一次看时间还要执行的指令数
+    suspended: Option<KeyWord>, //停下的调用的返回类型，resume 时用
+}
+
+const DATA: usize = 1;
+
 impl VM {
-    unsafe fn eval(&mut self) {
+    pub fn new(p: &Program) -> VM {
+        let data = DATA + p.globals.len();
+        let stack = data + p.data.len().div_ceil(8);
+        let top = stack + STACK_SIZE;
+        let mut text = p.text.clone();
+        if text.is_empty() {
+            text.push(Instruction::Exit as u64);
+        }
+        let mut mem = vec![0; top];
+        for (i, b) in p.data.iter().enumerate() {
+            mem[data + i / 8] |= (*b as u64) << (i % 8 * 8);
+        }
+        VM {
+            pc: 0,
+            sp: top,
+            bp: top,
+            ax: 0,
+            tag: None,
+            at: 0,
+            text,
+            mem,
+            tags: if CHECK { vec![None; top] } else { Vec::new() },
+            stack,
+            top,
+            data: data as u64 * 8,
+            consts: p.consts.clone(),
+            funcs: p.funcs.clone(),
+            lines: p.lines.clone(),
+            globals: p.globals.len(),
+            depth: 0,
+            max_depth: MAX_DEPTH,
+            heap: Heap::new(),
+            allocs: HashMap::new(),
+            free: Vec::new(),
+            io: Io::new(),
+            hosts: p.hosts.clone(),
+            host_fns: vec![None; p.hosts.len()],
+            hook: None,
+            stmts: Vec::new(),
+            profile: None,
+            limits: Limits::default(),
+            fuel: None,
+            deadline: None,
+            ticks: DEADLINE_CHECK,
+            suspended: None,
+        }
+    }
+
+    //本地函数的输入输出
+    pub fn io(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    //给程序用到的宿主函数 name 提供实现，程序没有用到这个名字时返回 false
+    pub fn bind(&mut self, name: &str, f: HostFn) -> bool {
+        match self.hosts.iter().position(|h| h.name == name) {
+            Some(i) => {
+                self.host_fns[i] = Some(f);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_hook(&mut self, hook: Box<dyn Hook>) {
+        self.stmts = vec![false; self.text.len()];
+        for (pc, _) in self.lines.iter() {
+            if *pc < self.text.len() && self.text[*pc] != Instruction::Ent as u64 {
+                self.stmts[*pc] = true;
+            }
+        }
+        self.hook = Some(hook);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    //这次执行剩下的燃料
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    //malloc 用掉的内存和堆上的数组占用的字节数。
+    //malloc 的部分按 mem 在栈顶之上扩展了多少算，free 了但还没还给 mem 的块也算在内
+    pub fn memory(&self) -> usize {
+        (self.mem.len() - self.top + self.heap.stats().words) * 8
+    }
+
+    //再分配 words 个字会不会超过限制
+    fn reserve(&self, words: usize) -> Step<()> {
+        if words > MAX_ALLOC / 8 {
+            return Err(ErrorKind::MemoryLimit(MAX_ALLOC));
+        }
+        match self.limits.memory {
+            Some(max) if self.memory() + words * 8 > max => Err(ErrorKind::MemoryLimit(max)),
+            _ => Ok(()),
+        }
+    }
+
+    //打开执行剖析，每 interval 条指令采样一次调用栈。之前的计数清零
+    pub fn set_profile(&mut self, interval: u64) {
+        self.profile = Some(Profile::new(&self.funcs, self.text.len(), interval));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    //正在执行的指令的位置，钩子里是当前语句的开头。
+    //字段 pc 已经指向下一个要取的字，所以返回的是 at
+    #[allow(clippy::misnamed_getters)]
+    pub fn pc(&self) -> usize {
+        self.at
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    //当前调用帧里相对 bp 偏移 slot 个字的值，也就是参数或者局部变量
+    pub fn local(&self, slot: i64) -> Option<u64> {
+        let i = self.bp as i64 + slot;
+        if i < self.stack as i64 || i >= self.top as i64 {
+            return None;
+        }
+        Some(self.mem[i as usize])
+    }
+
+    pub fn set_max_depth(&mut self, n: usize) {
+        self.max_depth = n;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_stats(&self) -> &Stats {
+        self.heap.stats()
+    }
+
+    //每次分配都回收，测试用
+    pub fn set_gc_stress(&mut self, on: bool) {
+        self.heap.set_stress(on);
+    }
+
+    //回收堆上不再使用的对象，根是全局变量和栈，调用之间栈是空的
+    pub fn collect(&mut self) {
+        let roots = self.mem[DATA..DATA + self.globals]
+            .iter()
+            .chain(self.mem[self.sp..].iter());
+        //self.mem[self.sp..] 包括了栈和 malloc 分配的内存
+        self.heap.collect(roots.copied());
+    }
+
+    pub fn global(&self, i: usize) -> u64 {
+        assert!(i < self.globals);
+        self.mem[DATA + i]
+    }
+
+    pub fn set_global(&mut self, i: usize, v: u64) {
+        assert!(i < self.globals);
+        self.mem[DATA + i] = v;
+        if CHECK {
+            self.tags[DATA + i] = None;
+        }
+    }
+
+    //调用第 f 个函数，参数转成声明的类型，返回它的返回值
+    pub fn call(&mut self, f: usize, args: &[Value]) -> VmResult<Value> {
+        self.sp = self.top;
+        self.bp = self.sp;
+        self.at = 0;
+        self.depth = 0;
+        let func = match self.funcs.get(f) {
+            Some(func) => func.clone(),
+            None => return Err(self.error(ErrorKind::BadOperand(f as u64))),
+        };
+        if args.len() != func.params.len() {
+            let kind = ErrorKind::Arity(func.params.len(), args.len());
+            return Err(self.error(kind));
+        }
+        for (a, t) in args.iter().zip(func.params.iter()) {
+            self.push(a.to_bits(*t), Some(tag_of(*t)))
+                .map_err(|e| self.error(e))?;
+        }
+        //返回到 text[0] 的 Exit
+        self.push(0, None).map_err(|e| self.error(e))?;
+        self.pc = func.entry;
+        self.suspended = None;
+        self.eval(func.ret)
+    }
+
+    //从燃料用完或者超时的地方继续执行停下的调用，按 Limits 重新计算燃料和截止时间
+    pub fn resume(&mut self) -> VmResult<Value> {
+        match self.suspended.take() {
+            Some(ret) => self.eval(ret),
+            None => Err(self.error(ErrorKind::NotSuspended)),
+        }
+    }
+
+    //执行到 Exit，返回值按 ret 解释
+    fn eval(&mut self, ret: KeyWord) -> VmResult<Value> {
+        let want = match ret {
+            KeyWord::Float => FLOATS,
+            _ => INTEGERS,
+        };
+        self.fuel = self.limits.fuel;
+        self.deadline = self.limits.deadline.map(|d| Instant::now() + d);
+        let result = self
+            .run()
+            .and_then(|bits| self.check(self.tag, want).map(|_| bits));
+        let _ = self.io.stdout.flush();
+        match result {
+            Ok(bits) => Ok(Value::from_bits(bits, ret)),
+            Err(ErrorKind::Exit(code)) => Ok(Value::Int(code)),
+            Err(kind) => {
+                let e = self.error(kind);
+                if e.is_resumable() {
+                    self.suspended = Some(ret);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn error(&self, kind: ErrorKind) -> VmError {
+        VmError {
+            kind,
+            backtrace: self.backtrace(),
+        }
+    }
+
+    //从出错的位置开始，沿着栈上保存的 bp 和返回地址逐层找到调用者
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.callers(MAX_BACKTRACE)
+            .iter()
+            .map(|pc| self.frame(*pc))
+            .collect()
+    }
+
+    //调用栈上每一层正在执行的位置，最多 max 层，当前的在最前面
+    fn callers(&self, max: usize) -> Vec<usize> {
+        let mut pcs = vec![self.at];
+        //Ent 还没有建好调用帧，返回地址在栈顶，bp 还是调用者的
+        if self.text.get(self.at) == Some(&(Instruction::Ent as u64)) && self.sp < self.top {
+            let ret = self.mem[self.sp] as usize;
+            if ret >= 2 && ret <= self.text.len() {
+                pcs.push(ret - 2);
+            }
+        }
+        let mut bp = self.bp;
+        while pcs.len() < max && bp >= self.stack && bp + 1 < self.top {
+            //返回地址指向 Call 的下一条指令，Call 连同操作数占两个字
+            let ret = self.mem[bp + 1] as usize;
+            if ret < 2 || ret > self.text.len() {
+                break;
+            }
+            pcs.push(ret - 2);
+            //保存的 bp 可能被程序改写，调用者的 bp 一定在更高的地址，否则就不再往上走
+            let next = self.mem[bp] as usize;
+            if next <= bp {
+                break;
+            }
+            bp = next;
+        }
+        pcs
+    }
+
+    fn frame(&self, pc: usize) -> Frame {
+        let func = self.funcs.iter().find(|f| f.entry <= pc && pc < f.end);
+        Frame {
+            func: func.map_or("?".to_owned(), |f| f.name.clone()),
+            line: line_at(&self.lines, pc),
+        }
+    }
+
+    fn run(&mut self) -> Step<u64> {
         loop {
-            self.pc = self.pc.add(1);
-            let op = std::mem::transmute(self.pc.read());
+            self.at = self.pc;
+            //在取指令之前检查，停下时 pc 还指向这条指令，resume 从这里继续
+            if let Some(fuel) = self.fuel.as_mut() {
+                if *fuel == 0 {
+                    return Err(ErrorKind::OutOfFuel);
+                }
+                *fuel -= 1;
+            }
+            if let Some(deadline) = self.deadline {
+                self.ticks -= 1;
+                if self.ticks == 0 {
+                    self.ticks = DEADLINE_CHECK;
+                    if Instant::now() >= deadline {
+                        return Err(ErrorKind::Deadline);
+                    }
+                }
+            }
+            if self.stmts.get(self.pc) == Some(&true) {
+                if let Some(mut hook) = self.hook.take() {
+                    let go = hook.statement(self);
+                    self.hook = Some(hook);
+                    if !go {
+                        return Err(ErrorKind::Stopped);
+                    }
+                }
+            }
+            let op = self.fetch()?;
+            let op = Instruction::decode(op).ok_or(ErrorKind::BadOpcode(op))?;
+            if let Some(profile) = &mut self.profile {
+                if profile.count(self.at, op) {
+                    //当前指令、Ent 时的返回地址，加上最多 max_depth 层调用
+                    let pcs = self.callers(self.max_depth.saturating_add(2));
+                    self.profile.as_mut().unwrap().sample(&pcs);
+                }
+            }
             match op {
+                Instruction::Lea => {
+                    let n = self.fetch()? as i64;
+                    let addr = ((self.bp as i64).wrapping_add(n) as u64).wrapping_mul(8);
+                    self.set(addr, Tag::Ptr);
+                }
                 Instruction::Imm => {
-                    self.pc = self.pc.add(1);
-                    self.ax = self.pc.read();
+                    let n = self.fetch()?;
+                    self.set(n, Tag::Int);
                 }
-                Instruction::Lea => {
-                    println!("op : {:?}", op);
+                Instruction::Const => {
+                    let n = self.fetch()?;
+                    let v = *self
+                        .consts
+                        .get(n as usize)
+                        .ok_or(ErrorKind::BadOperand(n))?;
+                    self.set(v, Tag::Float);
+                }
+                Instruction::Glo => {
+                    let n = self.fetch()?;
+                    if n as usize >= self.globals {
+                        return Err(ErrorKind::BadOperand(n));
+                    }
+                    self.set((DATA as u64 + n) * 8, Tag::Ptr);
+                }
+                Instruction::Jmp => {
+                    self.pc = self.fetch()? as usize;
+                }
+                Instruction::Jz => {
+                    let n = self.fetch()?;
+                    self.check(self.tag, INTEGERS)?;
+                    if self.ax == 0 {
+                        self.pc = n as usize;
+                    }
+                }
+                Instruction::Jnz => {
+                    let n = self.fetch()?;
+                    self.check(self.tag, INTEGERS)?;
+                    if self.ax != 0 {
+                        self.pc = n as usize;
+                    }
+                }
+                Instruction::Call => {
+                    let f = self.fetch()?;
+                    self.push(self.pc as u64, None)?;
+                    self.pc = self.func(f)?;
+                }
+                Instruction::Ent => {
+                    let n = self.fetch()? as usize;
+                    if self.depth >= self.max_depth {
+                        return Err(ErrorKind::CallDepth(self.max_depth));
+                    }
+                    self.depth += 1;
+                    self.push(self.bp as u64, None)?;
+                    self.bp = self.sp;
+                    if n > self.sp - self.stack {
+                        return Err(ErrorKind::StackOverflow);
+                    }
+                    self.sp -= n;
+                    if CHECK {
+                        //局部变量在赋值之前类型未知
+                        for t in self.tags[self.sp..self.bp].iter_mut() {
+                            *t = None;
+                        }
+                    }
+                }
+                Instruction::Adj => {
+                    let n = self.fetch()? as usize;
+                    if n > self.top - self.sp {
+                        return Err(ErrorKind::StackUnderflow);
+                    }
+                    self.sp += n;
+                }
+                Instruction::Lev => {
+                    if self.bp > self.top || self.bp < self.stack {
+                        return Err(ErrorKind::StackUnderflow);
+                    }
+                    self.sp = self.bp;
+                    self.depth = self.depth.saturating_sub(1);
+                    self.bp = self.pop()?.0 as usize;
+                    self.pc = self.pop()?.0 as usize;
+                }
+                Instruction::Li => {
+                    self.check(self.tag, POINTERS)?;
+                    let (v, t) = self.load(self.ax)?;
+                    self.ax = v;
+                    self.tag = t;
+                }
+                Instruction::Lc => {
+                    self.check(self.tag, POINTERS)?;
+                    let (w, _) = self.load(self.ax & !7)?;
+                    self.set((w >> ((self.ax & 7) * 8)) & 0xff, Tag::Int);
+                }
+                Instruction::Si => {
+                    let (addr, t) = self.pop()?;
+                    self.check(t, POINTERS)?;
+                    self.store(addr, self.ax, self.tag)?;
+                }
+                Instruction::Sc => {
+                    let (addr, t) = self.pop()?;
+                    self.check(t, POINTERS)?;
+                    self.check(self.tag, INTEGERS)?;
+                    let shift = (addr & 7) * 8;
+                    let (w, _) = self.load(addr & !7)?;
+                    let w = (w & !(0xff << shift)) | ((self.ax & 0xff) << shift);
+                    self.store(addr & !7, w, Some(Tag::Int))?;
                 }
                 Instruction::Push => {
-                    self.sp = self.sp.sub(1);
-                    self.sp.write(self.ax);
+                    self.push(self.ax, self.tag)?;
+                }
+                Instruction::Or => self.binary(|x, y| x | y, Tag::Int)?,
+                Instruction::Xor => self.binary(|x, y| x ^ y, Tag::Int)?,
+                Instruction::And => self.binary(|x, y| x & y, Tag::Int)?,
+                Instruction::Shl => self.binary(|x, y| x.wrapping_shl(y as u32), Tag::Int)?,
+                Instruction::Shr => self.binary(|x, y| x.wrapping_shr(y as u32), Tag::Int)?,
+                Instruction::Eq => self.binary(|x, y| (x == y) as i64, Tag::Bool)?,
+                Instruction::Ne => self.binary(|x, y| (x != y) as i64, Tag::Bool)?,
+                Instruction::Lt => self.binary(|x, y| (x < y) as i64, Tag::Bool)?,
+                Instruction::Gt => self.binary(|x, y| (x > y) as i64, Tag::Bool)?,
+                Instruction::Le => self.binary(|x, y| (x <= y) as i64, Tag::Bool)?,
+                Instruction::Ge => self.binary(|x, y| (x >= y) as i64, Tag::Bool)?,
+                Instruction::Add => self.binary(|x, y| x.wrapping_add(y), Tag::Int)?,
+                Instruction::Sub => self.binary(|x, y| x.wrapping_sub(y), Tag::Int)?,
+                Instruction::Mul => self.binary(|x, y| x.wrapping_mul(y), Tag::Int)?,
+                Instruction::Div | Instruction::Mod if self.ax == 0 => {
+                    return Err(ErrorKind::DivByZero)
+                }
+                Instruction::Div => self.binary(|x, y| x.wrapping_div(y), Tag::Int)?,
+                Instruction::Mod => self.binary(|x, y| x.wrapping_rem(y), Tag::Int)?,
+                Instruction::FAdd => self.fbinary(|x, y| (x + y).to_bits(), Tag::Float)?,
+                Instruction::FSub => self.fbinary(|x, y| (x - y).to_bits(), Tag::Float)?,
+                Instruction::FMul => self.fbinary(|x, y| (x * y).to_bits(), Tag::Float)?,
+                Instruction::FDiv => self.fbinary(|x, y| (x / y).to_bits(), Tag::Float)?,
+                Instruction::FMod => self.fbinary(|x, y| (x % y).to_bits(), Tag::Float)?,
+                Instruction::FEq => self.fbinary(|x, y| (x == y) as u64, Tag::Bool)?,
+                Instruction::FNe => self.fbinary(|x, y| (x != y) as u64, Tag::Bool)?,
+                Instruction::FLt => self.fbinary(|x, y| (x < y) as u64, Tag::Bool)?,
+                Instruction::FGt => self.fbinary(|x, y| (x > y) as u64, Tag::Bool)?,
+                Instruction::FLe => self.fbinary(|x, y| (x <= y) as u64, Tag::Bool)?,
+                Instruction::FGe => self.fbinary(|x, y| (x >= y) as u64, Tag::Bool)?,
+                Instruction::Itof => {
+                    self.check(self.tag, INTEGERS)?;
+                    self.set((self.ax as i64 as f64).to_bits(), Tag::Float);
+                }
+                Instruction::Ftoi => {
+                    self.check(self.tag, FLOATS)?;
+                    self.set(f64::from_bits(self.ax) as i64 as u64, Tag::Int);
                 }
-                Instruction::Add => {
-                    self.ax = self.sp.read() + self.ax;
-                    self.sp = self.sp.add(1);
+                Instruction::Trap => {
+                    self.check(self.tag, INTEGERS)?;
+                    return Err(ErrorKind::Trap(self.ax as i64));
+                }
+                Instruction::New => {
+                    self.check(self.tag, INTEGERS)?;
+                    let n = self.ax as i64;
+                    if n < 0 {
+                        return Err(ErrorKind::NegativeSize(n));
+                    }
+                    //ax 是长度，不是根
+                    if self.heap.should_collect(n as usize) || self.reserve(n as usize).is_err() {
+                        self.collect();
+                    }
+                    self.reserve(n as usize)?;
+                    let r = self.heap.alloc(n as usize);
+                    self.set(r, Tag::Ref);
+                }
+                Instruction::HGet => {
+                    self.check(self.tag, INTEGERS)?;
+                    let (r, t) = self.pop()?;
+                    self.check(t, REFS)?;
+                    let v = self.heap.get(r, self.ax as i64)?;
+                    self.set(v, element_tag(v));
+                }
+                Instruction::HSet => {
+                    let (i, t) = self.pop()?;
+                    self.check(t, INTEGERS)?;
+                    let (r, t) = self.pop()?;
+                    self.check(t, REFS)?;
+                    self.heap.set(r, i as i64, self.ax)?;
+                }
+                Instruction::HLen => {
+                    self.check(self.tag, REFS)?;
+                    let n = self.heap.len(self.ax)?;
+                    self.set(n as u64, Tag::Int);
+                }
+                Instruction::Str => {
+                    let n = self.fetch()?;
+                    self.set(self.data + n, Tag::Ptr);
+                }
+                Instruction::Native => {
+                    let n = self.fetch()?;
+                    let native = NATIVES.get(n as usize).ok_or(ErrorKind::BadOperand(n))?;
+                    if let Some(allowed) = &self.limits.natives {
+                        if !allowed.iter().any(|name| name == native.name) {
+                            return Err(ErrorKind::Forbidden(native.name.to_owned()));
+                        }
+                    }
+                    self.check(self.tag, INTEGERS)?;
+                    let argc = self.ax as usize;
+                    //本地函数直接按下标取参数，个数不对的字节码不能交给它
+                    let want = native.params.len();
+                    if argc < want || (argc > want && !native.variadic) {
+                        return Err(ErrorKind::Arity(want, argc));
+                    }
+                    if argc > self.top - self.sp {
+                        return Err(ErrorKind::StackUnderflow);
+                    }
+                    //第一个参数最先压栈，在最高的地址
+                    let args: Vec<u64> = self.mem[self.sp..self.sp + argc]
+                        .iter()
+                        .rev()
+                        .copied()
+                        .collect();
+                    let v = (native.func)(self, &args)?;
+                    self.set(v, tag_of(native.ret));
+                }
+                Instruction::Host => {
+                    let n = self.fetch()?;
+                    let host = self.hosts.get(n as usize).ok_or(ErrorKind::BadOperand(n))?;
+                    let argc = host.params.len();
+                    if argc > self.top - self.sp {
+                        return Err(ErrorKind::StackUnderflow);
+                    }
+                    let args: Vec<Value> = self.mem[self.sp..self.sp + argc]
+                        .iter()
+                        .rev()
+                        .zip(host.params.iter())
+                        .map(|(bits, typ)| Value::from_bits(*bits, *typ))
+                        .collect();
+                    let f = match &self.host_fns[n as usize] {
+                        Some(f) => f.clone(),
+                        None => {
+                            let msg = "no implementation was bound".to_owned();
+                            return Err(ErrorKind::Native(host.name.clone(), msg));
+                        }
+                    };
+                    let ret = host.ret;
+                    let v = f(&args).map_err(|msg| ErrorKind::Native(host.name.clone(), msg))?;
+                    self.set(v.to_bits(ret), tag_of(ret));
                 }
                 Instruction::Exit => {
-                    println!("exit({})", self.sp.read());
+                    return Ok(self.ax);
                 }
-                _ => {
-                    break;
+            }
+        }
+    }
+
+    //取 pc 处的字，pc 加一
+    fn fetch(&mut self) -> Step<u64> {
+        let n = *self
+            .text
+            .get(self.pc)
+            .ok_or(ErrorKind::BadOperand(self.pc as u64))?;
+        self.pc += 1;
+        Ok(n)
+    }
+
+    fn func(&self, f: u64) -> Step<usize> {
+        self.funcs
+            .get(f as usize)
+            .map(|f| f.entry)
+            .ok_or(ErrorKind::BadOperand(f))
+    }
+
+    //按字节地址找到 mem 中的下标
+    fn word(&self, addr: u64) -> Step<usize> {
+        let i = (addr / 8) as usize;
+        if !addr.is_multiple_of(8) || i < DATA || i >= self.mem.len() {
+            return Err(ErrorKind::InvalidMemory(addr));
+        }
+        Ok(i)
+    }
+
+    //读 addr 开始的 n 个字节
+    pub fn read_bytes(&self, addr: u64, n: usize) -> Result<Vec<u8>, ErrorKind> {
+        (0..n as u64)
+            .map(|i| self.byte(addr.wrapping_add(i)))
+            .collect()
+    }
+
+    //读 addr 处以 0 结尾的字符串，不包括结尾的 0
+    pub fn read_cstr(&self, addr: u64) -> Result<Vec<u8>, ErrorKind> {
+        let mut s = Vec::new();
+        loop {
+            match self.byte(addr.wrapping_add(s.len() as u64))? {
+                0 => return Ok(s),
+                b => s.push(b),
+            }
+        }
+    }
+
+    //检查 addr 开始的 n 个字节都在可读写的内存里。内存从 DATA 开始是连续的，
+    //只要首尾两个字节有效就行。natives 在按 n 分配宿主缓冲区之前先调用它
+    pub fn check_bytes(&self, addr: u64, n: usize) -> Result<(), ErrorKind> {
+        if n == 0 {
+            return Ok(());
+        }
+        let last = addr
+            .checked_add(n as u64 - 1)
+            .ok_or(ErrorKind::InvalidMemory(addr))?;
+        self.word(addr & !7)?;
+        self.word(last & !7)?;
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), ErrorKind> {
+        for (i, b) in bytes.iter().enumerate() {
+            let a = addr.wrapping_add(i as u64);
+            let w = self.word(a & !7)?;
+            let shift = (a & 7) * 8;
+            self.mem[w] = (self.mem[w] & !(0xff << shift)) | ((*b as u64) << shift);
+            if CHECK {
+                self.tags[w] = Some(Tag::Int);
+            }
+        }
+        Ok(())
+    }
+
+    fn byte(&self, addr: u64) -> Step<u8> {
+        let w = self.mem[self.word(addr & !7)?];
+        Ok((w >> ((addr & 7) * 8)) as u8)
+    }
+
+    //分配 n 个字节，按字对齐，内容清零，返回地址。先找 free 过的块，没有就扩展 mem
+    pub fn malloc(&mut self, n: usize) -> Result<u64, ErrorKind> {
+        let words = n.div_ceil(8).max(1);
+        let start = match self.free.iter().position(|b| b.1 >= words) {
+            Some(i) => {
+                let (start, size) = self.free[i];
+                if size == words {
+                    self.free.remove(i);
+                } else {
+                    self.free[i] = (start + words, size - words);
+                }
+                for w in self.mem[start..start + words].iter_mut() {
+                    *w = 0;
                 }
+                start
             }
+            None => {
+                //只有扩展 mem 才会多用内存，重用 free 过的块不用检查
+                self.reserve(words)?;
+                let start = self.mem.len();
+                self.mem.resize(start + words, 0);
+                if CHECK {
+                    self.tags.resize(start + words, None);
+                }
+                start
+            }
+        };
+        self.allocs.insert(start, words);
+        Ok(start as u64 * 8)
+    }
+
+    //释放 malloc 返回的地址，free(0) 什么也不做
+    pub fn free(&mut self, addr: u64) -> Result<(), ErrorKind> {
+        if addr == 0 {
+            return Ok(());
+        }
+        //不对齐的地址不可能是 malloc 返回的，先排除，免得删掉同一个字里别的块的记录
+        if !addr.is_multiple_of(8) {
+            return Err(ErrorKind::InvalidMemory(addr));
+        }
+        match self.allocs.remove(&((addr / 8) as usize)) {
+            Some(words) => {
+                self.release((addr / 8) as usize, words);
+                Ok(())
+            }
+            _ => Err(ErrorKind::InvalidMemory(addr)),
+        }
+    }
+
+    //把块还回去：和相邻的空闲块合并，在 mem 末尾的就直接截掉，这样反复分配释放 mem 不会一直变大
+    fn release(&mut self, mut start: usize, mut words: usize) {
+        if let Some(i) = self.free.iter().position(|b| b.0 + b.1 == start) {
+            let (s, n) = self.free.swap_remove(i);
+            start = s;
+            words += n;
+        }
+        if let Some(i) = self.free.iter().position(|b| b.0 == start + words) {
+            words += self.free.swap_remove(i).1;
+        }
+        if start + words == self.mem.len() {
+            self.mem.truncate(start);
+            self.tags.truncate(start);
+        } else {
+            self.free.push((start, words));
+        }
+    }
+
+    //读写内存时连同类型标记一起，release 构建下标记总是 None
+    fn load(&self, addr: u64) -> Step<(u64, Option<Tag>)> {
+        let i = self.word(addr)?;
+        Ok((self.mem[i], self.tags.get(i).copied().flatten()))
+    }
+
+    fn store(&mut self, addr: u64, v: u64, t: Option<Tag>) -> Step<()> {
+        let i = self.word(addr)?;
+        self.mem[i] = v;
+        if CHECK {
+            self.tags[i] = t;
         }
+        Ok(())
+    }
+
+    fn push(&mut self, v: u64, t: Option<Tag>) -> Step<()> {
+        if self.sp <= self.stack {
+            return Err(ErrorKind::StackOverflow);
+        }
+        self.sp -= 1;
+        self.mem[self.sp] = v;
+        if CHECK {
+            self.tags[self.sp] = t;
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Step<(u64, Option<Tag>)> {
+        if self.sp >= self.top {
+            return Err(ErrorKind::StackUnderflow);
+        }
+        let v = self.mem[self.sp];
+        let t = self.tags.get(self.sp).copied().flatten();
+        self.sp += 1;
+        Ok((v, t))
+    }
+
+    fn set(&mut self, v: u64, t: Tag) {
+        self.ax = v;
+        self.tag = Some(t);
+    }
+
+    //debug 构建下检查 t 是 want 中的一种，不知道类型的值不检查
+    fn check(&self, t: Option<Tag>, want: &[Tag]) -> Step<()> {
+        match t {
+            Some(t) if CHECK && !want.contains(&t) => Err(ErrorKind::Type(want[0], t)),
+            _ => Ok(()),
+        }
+    }
+
+    //ax = pop() op ax，按有符号整数计算，结果的类型是 tag。
+    //指针和整数运算的结果还是指针，两个指针相减得到整数
+    fn binary<F: Fn(i64, i64) -> i64>(&mut self, f: F, tag: Tag) -> Step<()> {
+        let (x, t) = self.pop()?;
+        self.check(t, INTEGERS)?;
+        self.check(self.tag, INTEGERS)?;
+        let tag = match (tag, t, self.tag) {
+            (Tag::Int, Some(Tag::Ptr), Some(Tag::Ptr)) => Tag::Int,
+            (Tag::Int, Some(Tag::Ptr), _) | (Tag::Int, _, Some(Tag::Ptr)) => Tag::Ptr,
+            _ => tag,
+        };
+        self.set(f(x as i64, self.ax as i64) as u64, tag);
+        Ok(())
+    }
+
+    //ax = pop() op ax，按 f64 计算，f 返回结果的位模式
+    fn fbinary<F: Fn(f64, f64) -> u64>(&mut self, f: F, tag: Tag) -> Step<()> {
+        let (x, t) = self.pop()?;
+        self.check(t, FLOATS)?;
+        self.check(self.tag, FLOATS)?;
+        self.set(f(f64::from_bits(x), f64::from_bits(self.ax)), tag);
+        Ok(())
     }
-    fn programs(&mut self) {}
 }
 
-struct Pointer<'a, T> {
-    data: *mut T,
-    slice: &'a mut [T],
+//在 (下标, 行号) 表中找 pc 所在的行
+pub fn line_at(lines: &[(usize, u32)], pc: usize) -> Option<u32> {
+    let i = lines.partition_point(|l| l.0 <= pc);
+    if i == 0 {
+        None
+    } else {
+        Some(lines[i - 1].1)
+    }
 }
 
-impl<'a, T> Pointer<'a, T> {
-    unsafe fn new() -> Pointer<'a, T> {
-        let data: *mut T = alloc::alloc(Layout::from_size_align_unchecked(
-            4096 * mem::size_of::<T>(),
-            mem::size_of::<T>(),
-        )) as *mut T;
-        Self {
-            data: data,
-            slice: slice::from_raw_parts_mut(data, 4096),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(text: Vec<u64>) -> Program {
+        Program {
+            text,
+            funcs: vec![Function {
+                name: "f".to_owned(),
+                entry: 1,
+                end: 0,
+                params: vec![],
+                ret: KeyWord::Int,
+                locals: vec![],
+            }],
+            ..Program::default()
+        }
+    }
+
+    #[test]
+    fn test_malloc() {
+        let p = program(vec![Instruction::Exit as u64]);
+        let mut vm = VM::new(&p);
+        vm.set_limits(Limits {
+            memory: Some(1500),
+            ..Limits::default()
+        });
+        //a 被 b 挡住没法还给 mem，仍然算在占用里
+        let a = vm.malloc(1000).unwrap();
+        let b = vm.malloc(8).unwrap();
+        vm.free(a).unwrap();
+        assert_eq!(vm.memory(), 1008);
+        assert_eq!(vm.malloc(1001), Err(ErrorKind::MemoryLimit(1500)));
+        assert_eq!(vm.malloc(1000), Ok(a));
+        //和前面的空闲块合并后在末尾，整个截掉
+        vm.free(a).unwrap();
+        vm.free(b).unwrap();
+        assert_eq!(vm.memory(), 0);
+        assert_eq!(vm.mem.len(), vm.top);
+    }
+
+    #[test]
+    fn test_profile_bad_frame() {
+        use Instruction::*;
+        //把 bp 自己存进 mem[bp]，返回地址改成 Ent 后面，采样时沿 bp 往上走也要能停下来
+        let text = vec![
+            Exit as u64,
+            Ent as u64,
+            0,
+            Lea as u64,
+            1,
+            Push as u64,
+            Imm as u64,
+            3,
+            Si as u64,
+            Lea as u64,
+            0,
+            Push as u64,
+            Lea as u64,
+            0,
+            Push as u64,
+            Imm as u64,
+            8,
+            Div as u64,
+            Si as u64,
+            Imm as u64,
+            7,
+            Exit as u64,
+        ];
+        let p = program(text);
+        let mut vm = VM::new(&p);
+        vm.set_profile(1);
+        assert_eq!(vm.call(0, &[]).map_err(|e| e.kind), Ok(Value::Int(7)));
+    }
+
+    #[test]
+    fn test_vm_errors() {
+        use Instruction::*;
+        for (i, op) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(Instruction::decode(*op as u64), Some(*op));
+            assert_eq!(*op as usize, i + 1);
+        }
+        let run = |text: Vec<u64>| VM::new(&program(text)).call(0, &[]).map_err(|e| e.kind);
+        let ok = vec![
+            Exit as u64,
+            Ent as u64,
+            0,
+            Imm as u64,
+            40,
+            Push as u64,
+            Imm as u64,
+            2,
+            Add as u64,
+            Lev as u64,
+        ];
+        assert_eq!(run(ok), Ok(Value::Int(42)));
+        // 读写一个字节
+        let bytes = vec![
+            Exit as u64,
+            Ent as u64,
+            1,
+            Lea as u64,
+            -1i64 as u64,
+            Push as u64,
+            Imm as u64,
+            0x1ff,
+            Sc as u64,
+            Lea as u64,
+            -1i64 as u64,
+            Lc as u64,
+            Lev as u64,
+        ];
+        assert_eq!(run(bytes), Ok(Value::Int(0xff)));
+        assert_eq!(run(vec![Exit as u64, 999]), Err(ErrorKind::BadOpcode(999)));
+        //没有 Ent 时 bp 在 mem 的末尾，Lea -1 是最后一个字
+        let last = (STACK_SIZE as u64) * 8;
+        assert_eq!(
+            run(vec![
+                Exit as u64,
+                Lea as u64,
+                -1i64 as u64,
+                Push as u64,
+                Imm as u64,
+                3,
+                Add as u64,
+                Li as u64
+            ]),
+            Err(ErrorKind::InvalidMemory(last + 3))
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Lea as u64, 0, Li as u64]),
+            Err(ErrorKind::InvalidMemory(last + 8))
+        );
+        assert_eq!(
+            run(vec![
+                Exit as u64,
+                Lea as u64,
+                (-(STACK_SIZE as i64) - 1) as u64,
+                Li as u64
+            ]),
+            Err(ErrorKind::InvalidMemory(0))
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Adj as u64, 2]),
+            Err(ErrorKind::StackUnderflow)
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Ent as u64, 1 << 20]),
+            Err(ErrorKind::StackOverflow)
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Call as u64, 0]),
+            Err(ErrorKind::StackOverflow)
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Jmp as u64, 100]),
+            Err(ErrorKind::BadOperand(100))
+        );
+        assert_eq!(
+            run(vec![Exit as u64, Glo as u64, 0]),
+            Err(ErrorKind::BadOperand(0))
+        );
+        //debug 构建下检查操作数的类型
+        if cfg!(debug_assertions) {
+            let float = |n: f64| n.to_bits();
+            assert_eq!(
+                run(vec![
+                    Exit as u64,
+                    Imm as u64,
+                    1,
+                    Push as u64,
+                    Imm as u64,
+                    2,
+                    FAdd as u64
+                ]),
+                Err(ErrorKind::Type(Tag::Float, Tag::Int))
+            );
+            assert_eq!(
+                run(vec![Exit as u64, Imm as u64, 8, Li as u64]),
+                Err(ErrorKind::Type(Tag::Ptr, Tag::Int))
+            );
+            let mut p = program(vec![
+                Exit as u64,
+                Ent as u64,
+                0,
+                Const as u64,
+                0,
+                Ftoi as u64,
+                Itof as u64,
+                Lev as u64,
+            ]);
+            p.consts = vec![float(2.5)];
+            assert_eq!(
+                VM::new(&p).call(0, &[]).map_err(|e| e.kind),
+                Err(ErrorKind::Type(Tag::Int, Tag::Float))
+            );
         }
+        assert_eq!(Value::from_tag(1, Tag::Bool), Value::Bool(true));
+        assert_eq!(Value::Ptr(16).to_bits(KeyWord::Float), 16f64.to_bits());
     }
 }